@@ -0,0 +1,21 @@
+// runs one of the library's markets as described by a server config (see src/config.rs)
+use clap::{App, Arg};
+
+use widget_market::config::{self, ServerConfig};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = App::new("configured-market")
+        .author("atpoverload")
+        .version("0.1.0")
+        .about("a market server set up from a config file")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .required(true)
+            .help("path to the server config as a json"))
+        .get_matches();
+
+    let config = ServerConfig::load(args.value_of("config").unwrap())?;
+    config::run_from_config(&config).await
+}
@@ -0,0 +1,66 @@
+// runs the library's order book and automated market maker side by side on one exchange
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::net::ToSocketAddrs;
+
+use clap::{App, Arg};
+use log::{debug, info};
+
+use widget_market::amm::AmmMarket;
+use widget_market::ledger::Ledgered;
+use widget_market::multi_market::{self, ExchangeServer};
+use widget_market::order_book::OrderBookMarket;
+use widget_market::transfer::Transferable;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = App::new("exchange")
+        .author("atpoverload")
+        .version("0.1.0")
+        .about("an exchange hosting an order book market and an amm market")
+        .arg(Arg::with_name("address")
+            .short("a")
+            .long("address")
+            .takes_value(true)
+            .required(true)
+            .help("address of the server"))
+        .arg(Arg::with_name("account")
+            .long("account")
+            .takes_value(true)
+            .help("path to the starting account of every market as a json"))
+        .arg(Arg::with_name("secret")
+            .long("secret")
+            .takes_value(true)
+            .required(true)
+            .help("secret the markets sign transferred accounts with"))
+        .get_matches();
+
+    env_logger::builder().filter(None, log::LevelFilter::Debug).init();
+
+    let account: HashMap<String, i32> = match args.value_of("account") {
+        Some(path) => serde_json::from_str(&read_to_string(path)?)?,
+        _ => {
+            debug!("no starting account provided; using 10 foo and 10 bar");
+            serde_json::from_str("{\"foo\": 10, \"bar\": 10}")?
+        }
+    };
+    // accounts can move between the markets by leaving with the other as the destination
+    let secret = args.value_of("secret").unwrap();
+    let book = Transferable::new(Ledgered::new(OrderBookMarket::new(account.clone())), "book", secret).trust("amm", secret);
+    let amm = AmmMarket::new(account).with_pool(("foo", 1000), ("bar", 1000), 30);
+    let amm = Transferable::new(Ledgered::new(amm), "amm", secret).trust("book", secret);
+    let exchange = ExchangeServer::new()
+        .with_market("book", book)
+        .with_market("amm", amm);
+
+    let addr = args
+        .value_of("address")
+        .unwrap()
+        .to_socket_addrs()?
+        .next()
+        .expect("could not parse address");
+    info!("starting exchange at {} with markets:", addr);
+    exchange.names().for_each(|name| {info!(" - {}", name);});
+
+    multi_market::run(addr, exchange).await
+}
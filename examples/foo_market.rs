@@ -8,6 +8,7 @@
 //  - the market's will not allow trades of new widgets
 //  - the market's will not allow trades of identical widgets
 //  - the market's will not allow trades of with insufficient widgets
+//  - trades are checked by the library's standard validator chain
 //  - all widgets are worth the same amount
 //  - trades are done immediately
 //  - accounts are removed and returned to the user when leaving
@@ -19,10 +20,12 @@ use std::fs::read_to_string;
 use clap::{App, Arg};
 use log::{debug, info};
 use rand::{distributions::Alphanumeric, Rng};
-use serde_json;
+use serde::{Deserialize, Serialize};
 
-use widget_market::market::{Market, ValidationError};
+use widget_market::config::{self, MarketKind, ServerConfig};
+use widget_market::market::{check_counts, ErrorCode, Market, Quote, ValidationError};
 use widget_market::single_market;
+use widget_market::validator::{ProposedTrade, Validator, ValidatorChain};
 
 fn new_id(size: usize) -> String {
     rand::thread_rng()
@@ -32,10 +35,14 @@ fn new_id(size: usize) -> String {
         .collect()
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct FooMarket {
     market: HashMap<String, i32>,
     accounts: HashMap<String, HashMap<String, i32>>,
+    // validators aren't saved; this market only uses the standard chain, which has no
+    // state, so rebuilding it on restore loses nothing
+    #[serde(skip, default = "ValidatorChain::standard")]
+    validator: ValidatorChain,
 }
 
 impl FooMarket {
@@ -43,6 +50,7 @@ impl FooMarket {
         FooMarket {
             market: widgets,
             accounts: HashMap::new(),
+            validator: ValidatorChain::standard(),
         }
     }
 
@@ -54,7 +62,7 @@ impl FooMarket {
         if self.accounts.contains_key(id) {
             Ok(())
         } else {
-            Err(ValidationError::AccountError(format!("account {} does not exist",id)))
+            Err(ValidationError::AccountError(ErrorCode::UnknownAccount, format!("account {} does not exist", id)))
         }
     }
 
@@ -66,28 +74,21 @@ impl FooMarket {
         (1, 1)
     }
 
-    fn make_trade(&mut self, id: &str, buy: &str, sell: &str, buy_cost: i32, sell_cost: i32) -> Result<(), ValidationError> {
-        if self.market.get(buy).unwrap() < &buy_cost {
-            Err(ValidationError::TradeError(format!("not enough {} in market", buy)))
-        } else if self.accounts.get(id).unwrap().get(sell).unwrap() < &sell_cost {
-            Err(ValidationError::TradeError(format!("not enough {} in account {}", sell, id)))
-        } else {
-            self.market
-                .entry(buy.to_string())
-                .and_modify(|widgets| *widgets -= buy_cost);
-            self.market
-                .entry(sell.to_string())
-                .and_modify(|widgets| *widgets += sell_cost);
-            self.accounts.entry(id.to_string()).and_modify(|account| {
-                account
-                    .entry(buy.to_string())
-                    .and_modify(|widgets| *widgets += buy_cost);
-                account
-                    .entry(sell.to_string())
-                    .and_modify(|widgets| *widgets -= sell_cost);
-            });
-            Ok(())
-        }
+    fn make_trade(&mut self, trade: &ProposedTrade) {
+        self.market
+            .entry(trade.buy.to_string())
+            .and_modify(|widgets| *widgets -= trade.buy_amount);
+        self.market
+            .entry(trade.sell.to_string())
+            .and_modify(|widgets| *widgets += trade.sell_amount);
+        self.accounts.entry(trade.id.to_string()).and_modify(|account| {
+            account
+                .entry(trade.buy.to_string())
+                .and_modify(|widgets| *widgets += trade.buy_amount);
+            account
+                .entry(trade.sell.to_string())
+                .and_modify(|widgets| *widgets -= trade.sell_amount);
+        });
     }
 }
 
@@ -103,14 +104,40 @@ impl Market for FooMarket {
         }
     }
 
+    fn get_accounts(&self) -> Result<Vec<String>, ValidationError> {
+        let mut ids: Vec<String> = self.accounts.keys().cloned().collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+        self.has_account(id)?;
+        if amount <= 0 {
+            Err(ValidationError::TradeError(ErrorCode::InvalidAmount, "quote amount must be positive".to_string()))
+        } else if !self.market.contains_key(buy) {
+            Err(ValidationError::TradeError(ErrorCode::UnknownWidget, format!("{} is not in market", buy)))
+        } else if !self.market.contains_key(sell) {
+            Err(ValidationError::TradeError(ErrorCode::UnknownWidget, format!("{} is not in market", sell)))
+        } else {
+            let (buy_cost, sell_cost) = self.get_costs(buy, sell);
+            Ok(Quote {
+                buy: buy.to_string(),
+                buy_amount: buy_cost * amount,
+                sell: sell.to_string(),
+                sell_amount: sell_cost * amount,
+            })
+        }
+    }
+
     fn create_account(&mut self) -> Result<String, ValidationError> {
         self.add_account(self.new_account())
     }
 
     fn add_account(&mut self, account: HashMap<String, i32>) -> Result<String, ValidationError> {
+        check_counts(&account)?;
         let id: String = new_id(10);
         if self.accounts.contains_key(&id) {
-            Err(ValidationError::MarketError(format!("account {} already exists", id)))
+            Err(ValidationError::MarketError(ErrorCode::DuplicateAccount, format!("account {} already exists", id)))
         } else {
             let mut account: HashMap<String, i32> = account.iter()
                 .filter(|(k, _)| self.market.contains_key(&k.to_string()))
@@ -129,22 +156,14 @@ impl Market for FooMarket {
         }
     }
 
-    fn submit_trade(&mut self, id: &str, buy: &str, sell: &str) -> Result<(), ValidationError> {
-        match self.has_account(id) {
-            Ok(_) => {
-                if buy == sell {
-                    Err(ValidationError::TradeError(format!("both widgets are {}", buy)))
-                } else if !self.market.contains_key(buy) {
-                    Err(ValidationError::TradeError(format!("{} is not in market", buy)))
-                } else if !self.market.contains_key(sell) {
-                    Err(ValidationError::TradeError(format!("{} is not in market", sell)))
-                } else {
-                    let (buy_cost, sell_cost) = self.get_costs(buy, sell);
-                    self.make_trade(id, buy, sell, buy_cost, sell_cost)
-                }
-            }
-            Err(error) => Err(error),
-        }
+    fn submit_trade(&mut self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ValidationError> {
+        self.has_account(id)?;
+        let (buy_cost, sell_cost) = self.get_costs(buy, sell);
+        let trade = ProposedTrade { id, buy, buy_amount: buy_cost * amount, sell, sell_amount: sell_cost * amount };
+        self.validator.validate(&trade, &self.market, &self.accounts[id])?;
+        self.make_trade(&trade);
+        self.validator.record(&trade);
+        Ok(())
     }
 }
 
@@ -181,6 +200,13 @@ mod tests {
             .collect()
     }
 
+    #[test]
+    fn test_conformance() {
+        widget_market::testing::assert_conformance(&FooMarket::from_map(new_market()));
+        // trades move widgets between the market and accounts without making or losing any
+        widget_market::testing::PropertyTest::new().assert_holds(&FooMarket::from_map(new_market()));
+    }
+
     // TODO: think about these test cases some more; i don't think we **really** exhausted this
     #[test]
     fn test_market_impl() {
@@ -198,21 +224,30 @@ mod tests {
         assert_eq!(market.get_account(&id).unwrap(), &new_account());
 
         // try to trade non-existent widget
-        market.submit_trade(&id, "foo", "bang").expect_err("shouldn't be able to trade baz");
+        market.submit_trade(&id, "foo", "bang", 1).expect_err("shouldn't be able to trade baz");
 
         // try to trade two foos
-        market.submit_trade(&id, "foo", "foo").expect_err("shouldn't be able to trade two foos");
+        market.submit_trade(&id, "foo", "foo", 1).expect_err("shouldn't be able to trade two foos");
+
+        // try to price a trade
+        assert_eq!(market.quote(&id, "foo", "bar", 2).unwrap().sell_amount, 2);
+        market.quote(&id, "foo", "bang", 1).expect_err("shouldn't be able to price bang");
+
+        // try to trade more than the account can pay for
+        market.submit_trade(&id, "foo", "bar", 2).expect_err("shouldn't be enough bar");
+        market.submit_trade(&id, "foo", "bar", 0).expect_err("shouldn't be able to trade nothing");
+        assert_eq!(market.get_account(&id).unwrap(), &new_account());
 
         // try to trade
-        assert_eq!(market.submit_trade(&id, "foo", "bar").unwrap(), ());
+        assert_eq!(market.submit_trade(&id, "foo", "bar", 1).unwrap(), ());
         assert_eq!(market.get_account(&id).unwrap(), &used_account());
 
         // try to trade without resources left
-        market.submit_trade(&id, "baz", "bar").expect_err("shouldn't be any bar left");
-        market.submit_trade(&id, "foo", "bar").expect_err("shouldn't be any bar left");
+        market.submit_trade(&id, "baz", "bar", 1).expect_err("shouldn't be any bar left");
+        market.submit_trade(&id, "foo", "bar", 1).expect_err("shouldn't be any bar left");
 
         // try to trade back
-        assert_eq!(market.submit_trade(&id, "bar", "foo").unwrap(), ());
+        assert_eq!(market.submit_trade(&id, "bar", "foo", 1).unwrap(), ());
         assert_eq!(market.get_account(&id).unwrap(), &new_account());
         assert_eq!(market.remove_account(&id).unwrap(), new_account());
 
@@ -232,14 +267,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .short("a")
             .long("address")
             .takes_value(true)
-            .required(true)
+            .required_unless("config")
             .help("address of the server"))
         .arg(Arg::with_name("market")
             .long("market")
             .takes_value(true)
             .help("path to an market as a json"))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .conflicts_with_all(&["address", "market"])
+            .help("path to a server config with a custom market; its inventory is the market"))
         .get_matches();
 
+    if let Some(path) = args.value_of("config") {
+        let config = ServerConfig::load(path)?;
+        let market = match &config.market {
+            MarketKind::Custom { inventory } => FooMarket::from_map(inventory.clone()),
+            _ => return Err("the foo market can only be run from a custom market config".into()),
+        };
+        return config::run_market_from_config(&config, market).await;
+    }
+
     env_logger::builder().filter(None, log::LevelFilter::Debug).init();
 
     let market = match args.value_of("market") {
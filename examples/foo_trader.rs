@@ -6,9 +6,26 @@ use std::vec::Vec;
 
 use clap::{App, Arg};
 use log::{error, info};
-use serde_json;
+use serde::Deserialize;
 
-use widget_market::client;
+use widget_market::client::{self, ClientError};
+
+// an order is [buy, sell] for a single widget or [buy, sell, amount]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Order {
+    Single(String, String),
+    Many(String, String, i32),
+}
+
+impl Order {
+    fn parts(&self) -> (&str, &str, i32) {
+        match self {
+            Order::Single(buy, sell) => (buy, sell, 1),
+            Order::Many(buy, sell, amount) => (buy, sell, *amount),
+        }
+    }
+}
 
 #[tokio::main(flavor = "current_thread")]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -52,33 +69,36 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap()
         .next()
         .expect("could not parse address");
-    let order: Vec<(String, String)> = serde_json::from_str(
+    let order: Vec<Order> = serde_json::from_str(
         &fs::read_to_string(args.value_of("orders").unwrap()).unwrap()).unwrap();
 
     env_logger::builder().filter(None, log::LevelFilter::Info).init();
-    tokio::task::LocalSet::new()
+    let result: Result<(), ClientError> = tokio::task::LocalSet::new()
         .run_until(async move {
             // create the rpc client
-            let service = client::WidgetMarketClient::new(&addr).await.unwrap();
-            let id = match account {
-                Some(account) => service.join_with_account(account).await,
-                None => service.join().await
+            let service = client::WidgetMarketClient::new(&addr).await?;
+            let account = match account {
+                Some(account) => service.join_handle_with_account(account).await?,
+                None => service.join_handle().await?
             };
-            info!("joined server at {} with {}", addr, id);
+            info!("joined server at {}", addr);
 
             info!("proposing {} trades", order.len());
             let mut trades = Vec::new();
-            for (i, (buy, sell)) in order.iter().enumerate() {
-                if let Ok(_) = service.trade(&id, &buy, &sell).await {
-                    trades.push((i, buy, sell));
+            for (i, (buy, sell, amount)) in order.iter().map(Order::parts).enumerate() {
+                match account.trade(buy, sell, amount).await {
+                    Ok(()) => trades.push((i, buy, sell, amount)),
+                    // rejected trades are expected; anything else means we lost the market
+                    Err(ClientError::Market(error)) => info!("trade {} rejected: {}", i, error),
+                    Err(error) => return Err(error),
                 }
             }
             info!("submitted {} trades", trades.len());
 
-            let account = service.leave(&id).await;
+            let account = account.leave().await?;
             let path = match args.value_of("output") {
                 Some(path) => path.to_string(),
-                _ => format!("{}_{}.json", id, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()),
+                _ => format!("foo_trader_{}.json", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()),
             };
             info!("writing account details to {}", path);
             if let Err(error) = fs::write(path, serde_json::to_string(&account).unwrap()) {
@@ -86,5 +106,11 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             Ok(())
         })
-        .await
+        .await;
+
+    if let Err(error) = result {
+        error!("{}", error);
+        std::process::exit(1);
+    }
+    Ok(())
 }
@@ -0,0 +1,88 @@
+// fuzzes a market server with random calls, or replays inputs that broke it before
+use std::collections::HashMap;
+use std::fs;
+use std::process;
+
+use clap::{value_t, App, Arg};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use widget_market::amm::AmmMarket;
+use widget_market::ledger::Ledgered;
+use widget_market::market::Market;
+use widget_market::order_book::OrderBookMarket;
+use widget_market::testing::fuzz_server;
+
+fn fuzz<M: 'static + Market + Clone>(market: &M, args: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(paths) = args.values_of("input") {
+        let mut failed = false;
+        for path in paths {
+            match fuzz_server(market, &fs::read(path)?) {
+                Ok(()) => println!("{}: ok", path),
+                Err(failure) => {
+                    println!("{}: {}", path, failure);
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let seed = value_t!(args, "seed", u64).unwrap_or_else(|e| e.exit());
+    let runs = value_t!(args, "runs", usize).unwrap_or_else(|e| e.exit());
+    let max_len = value_t!(args, "max-len", usize).unwrap_or_else(|e| e.exit());
+    let mut rng = StdRng::seed_from_u64(seed);
+    for run in 0..runs {
+        let data: Vec<u8> = (0..rng.gen_range(0..=max_len)).map(|_| rng.gen()).collect();
+        if let Err(failure) = fuzz_server(market, &data) {
+            let path = format!("fuzz-{}-{}.bin", seed, run);
+            fs::write(&path, &data)?;
+            println!("run {}: {}", run, failure);
+            println!("input written to {}", path);
+            process::exit(1);
+        }
+    }
+    println!("{} runs without a failure", runs);
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = App::new("fuzz_server")
+        .author("atpoverload")
+        .version("0.1.0")
+        .about("makes random calls on a market server, checking it never panics and keeps its widgets consistent")
+        .arg(Arg::with_name("market")
+            .long("market")
+            .takes_value(true)
+            .possible_values(&["order-book", "amm"])
+            .default_value("amm")
+            .help("kind of market to fuzz"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .takes_value(true)
+            .default_value("0")
+            .help("seed for the random inputs"))
+        .arg(Arg::with_name("runs")
+            .long("runs")
+            .takes_value(true)
+            .default_value("10000")
+            .help("number of random inputs to try"))
+        .arg(Arg::with_name("max-len")
+            .long("max-len")
+            .takes_value(true)
+            .default_value("512")
+            .help("longest random input in bytes"))
+        .arg(Arg::with_name("input")
+            .multiple(true)
+            .help("inputs to replay instead of fuzzing, like the ones written on a failure"))
+        .get_matches();
+
+    let account: HashMap<String, i32> = serde_json::from_str("{\"foo\": 100, \"bar\": 100}")?;
+    match args.value_of("market").unwrap() {
+        "order-book" => fuzz(&Ledgered::new(OrderBookMarket::new(account)), &args),
+        _ => fuzz(&Ledgered::new(AmmMarket::new(account).with_pool(("foo", 1000), ("bar", 1000), 30)), &args),
+    }
+}
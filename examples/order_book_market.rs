@@ -0,0 +1,93 @@
+// runs the library's order book market, where accounts trade with each other through limit orders
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+use clap::{value_t, App, Arg, ArgMatches};
+use log::{debug, info};
+
+use widget_market::ledger::Ledgered;
+use widget_market::market::Market;
+use widget_market::order_book::OrderBookMarket;
+use widget_market::persistence::PersistentMarket;
+use widget_market::recording::Recorder;
+use widget_market::single_market::{self, MarketServer};
+use widget_market::threaded_market;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = App::new("order-book-market")
+        .author("atpoverload")
+        .version("0.1.0")
+        .about("a market where accounts trade widgets with each other through limit orders")
+        .arg(Arg::with_name("address")
+            .short("a")
+            .long("address")
+            .takes_value(true)
+            .required(true)
+            .help("address of the server"))
+        .arg(Arg::with_name("account")
+            .long("account")
+            .takes_value(true)
+            .help("path to the starting account as a json"))
+        .arg(Arg::with_name("state")
+            .long("state")
+            .takes_value(true)
+            .help("directory to save the market in; a saved market is recovered from it"))
+        .arg(Arg::with_name("record")
+            .long("record")
+            .takes_value(true)
+            .help("path to record every call made of the market to"))
+        .arg(Arg::with_name("threads")
+            .long("threads")
+            .takes_value(true)
+            .conflicts_with("record")
+            .help("number of threads to handle connections on"))
+        .arg(Arg::with_name("settle")
+            .long("settle")
+            .takes_value(true)
+            .conflicts_with("threads")
+            .help("directory to settle every open account into when the server is stopped"))
+        .get_matches();
+
+    env_logger::builder().filter(None, log::LevelFilter::Debug).init();
+
+    let account: HashMap<String, i32> = match args.value_of("account") {
+        Some(path) => serde_json::from_str(&read_to_string(path)?)?,
+        _ => {
+            debug!("no starting account provided; using 10 foo and 10 bar");
+            serde_json::from_str("{\"foo\": 10, \"bar\": 10}")?
+        }
+    };
+    let addr = args
+        .value_of("address")
+        .unwrap()
+        .to_socket_addrs()?
+        .next()
+        .expect("could not parse address");
+    info!("starting order book market server at {} with starting account:", addr);
+    account.iter().for_each(|(k, v)| {info!(" - {}: {}", k, v);});
+
+    let market = Ledgered::new(OrderBookMarket::new(account));
+    match args.value_of("state") {
+        Some(dir) => serve(addr, PersistentMarket::open(dir, market)?, &args).await,
+        _ => serve(addr, market, &args).await,
+    }
+}
+
+async fn serve<M: 'static + Market + Clone + Send>(addr: SocketAddr, market: M, args: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    if args.is_present("threads") {
+        let threads = value_t!(args, "threads", usize).unwrap_or_else(|e| e.exit());
+        return threaded_market::run(addr, market, threads, None).await;
+    }
+    let mut server = MarketServer::new(market);
+    if let Some(path) = args.value_of("record") {
+        info!("recording calls to {}", path);
+        server = server.recording(Recorder::create(path)?);
+    }
+    match args.value_of("settle") {
+        Some(dir) => single_market::run_server_until(addr, server, single_market::shutdown_signal(), Path::new(dir)).await,
+        _ => single_market::run_server(addr, server).await,
+    }
+}
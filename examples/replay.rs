@@ -0,0 +1,85 @@
+// replays a recorded market session against a fresh market and reports where it diverged
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::process;
+
+use clap::{value_t, App, Arg};
+
+use widget_market::amm::AmmMarket;
+use widget_market::ledger::Ledgered;
+use widget_market::order_book::OrderBookMarket;
+use widget_market::recording::{self, Divergence};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = App::new("replay")
+        .author("atpoverload")
+        .version("0.1.0")
+        .about("replays a recorded market session and reports calls answered differently")
+        .arg(Arg::with_name("log")
+            .long("log")
+            .takes_value(true)
+            .required(true)
+            .help("path to a recording made with --record"))
+        .arg(Arg::with_name("market")
+            .long("market")
+            .takes_value(true)
+            .possible_values(&["order-book", "amm"])
+            .default_value("amm")
+            .help("kind of market to replay against"))
+        .arg(Arg::with_name("account")
+            .long("account")
+            .takes_value(true)
+            .help("path to the starting account as a json"))
+        .arg(Arg::with_name("pools")
+            .long("pools")
+            .takes_value(true)
+            .help("path to a json list of pools as [widget, reserve, widget, reserve]"))
+        .arg(Arg::with_name("fee")
+            .long("fee")
+            .takes_value(true)
+            .default_value("30")
+            .help("fee charged by every pool in basis points"))
+        .get_matches();
+
+    let records = recording::read_records(args.value_of("log").unwrap())?;
+    let account: Option<HashMap<String, i32>> = match args.value_of("account") {
+        Some(path) => Some(serde_json::from_str(&read_to_string(path)?)?),
+        _ => None,
+    };
+
+    // defaults match the servers', which keep a ledger, so a recording of a default server replays cleanly
+    let divergences = match args.value_of("market").unwrap() {
+        "order-book" => {
+            let account = match account {
+                Some(account) => account,
+                _ => serde_json::from_str("{\"foo\": 10, \"bar\": 10}")?,
+            };
+            recording::replay(&mut Ledgered::new(OrderBookMarket::new(account)), &records)
+        }
+        _ => {
+            let pools: Vec<(String, i32, String, i32)> = match args.value_of("pools") {
+                Some(path) => serde_json::from_str(&read_to_string(path)?)?,
+                _ => vec![("foo".to_string(), 1000, "bar".to_string(), 1000)],
+            };
+            let account = account.unwrap_or_else(|| {
+                pools.iter().flat_map(|(first, _, second, _)| vec![(first.clone(), 10), (second.clone(), 10)]).collect()
+            });
+            let fee = value_t!(args, "fee", u32).unwrap_or_else(|e| e.exit());
+            let market = pools.iter().fold(AmmMarket::new(account), |market, (first, first_reserve, second, second_reserve)| {
+                market.with_pool((first, *first_reserve), (second, *second_reserve), fee)
+            });
+            recording::replay(&mut Ledgered::new(market), &records)
+        }
+    };
+
+    println!("replayed {} calls with {} divergences", records.len(), divergences.len());
+    for Divergence { index, call, recorded, replayed } in &divergences {
+        println!("call {}: {:?}", index, call);
+        println!("  recorded: {:?}", recorded);
+        println!("  replayed: {:?}", replayed);
+    }
+    if !divergences.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+// runs a seeded simulation of trader agents against a market and prints how each did
+use std::collections::HashMap;
+
+use clap::{value_t, App, Arg};
+
+use widget_market::amm::AmmMarket;
+use widget_market::market::Market;
+use widget_market::order_book::OrderBookMarket;
+use widget_market::simulation::{RandomTrader, Rebalancer, Simulation};
+
+fn simulate<M: Market>(market: M, args: &clap::ArgMatches, orders: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut simulation = Simulation::new(market)
+        .with_seed(value_t!(args, "seed", u64).unwrap_or_else(|e| e.exit()))
+        .with_rounds(value_t!(args, "rounds", usize).unwrap_or_else(|e| e.exit()));
+    let max_amount = value_t!(args, "max-amount", i32).unwrap_or_else(|e| e.exit());
+    for i in 0..value_t!(args, "random", usize).unwrap_or_else(|e| e.exit()) {
+        let trader = if orders { RandomTrader::new(max_amount).placing_orders() } else { RandomTrader::new(max_amount) };
+        simulation = simulation.with_trader(&format!("random-{}", i + 1), trader);
+    }
+    for i in 0..value_t!(args, "rebalancers", usize).unwrap_or_else(|e| e.exit()) {
+        simulation = simulation.with_trader(&format!("rebalancer-{}", i + 1), Rebalancer::new(4));
+    }
+    println!("{}", simulation.run()?);
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = App::new("simulation")
+        .author("atpoverload")
+        .version("0.1.0")
+        .about("runs trader agents against a market in-process and reports every account")
+        .arg(Arg::with_name("market")
+            .long("market")
+            .takes_value(true)
+            .possible_values(&["order-book", "amm"])
+            .default_value("amm")
+            .help("kind of market to simulate"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .takes_value(true)
+            .default_value("0")
+            .help("seed for everything random in the simulation"))
+        .arg(Arg::with_name("rounds")
+            .long("rounds")
+            .takes_value(true)
+            .default_value("100")
+            .help("number of rounds every trader gets a turn in"))
+        .arg(Arg::with_name("random")
+            .long("random")
+            .takes_value(true)
+            .default_value("4")
+            .help("number of random traders"))
+        .arg(Arg::with_name("rebalancers")
+            .long("rebalancers")
+            .takes_value(true)
+            .default_value("1")
+            .help("number of traders evening out their holdings"))
+        .arg(Arg::with_name("max-amount")
+            .long("max-amount")
+            .takes_value(true)
+            .default_value("10")
+            .help("most a random trader trades at once"))
+        .get_matches();
+
+    let account: HashMap<String, i32> = serde_json::from_str("{\"foo\": 100, \"bar\": 100}")?;
+    match args.value_of("market").unwrap() {
+        "order-book" => simulate(OrderBookMarket::new(account), &args, true),
+        _ => simulate(AmmMarket::new(account).with_pool(("foo", 1000), ("bar", 1000), 30), &args, false),
+    }
+}
@@ -0,0 +1,343 @@
+// a market that prices trades with pooled liquidity
+//
+// the market has the following properties:
+//  - each pool holds two widgets and keeps the product of its reserves from decreasing
+//  - new accounts are given a copy of the starting account
+//  - added accounts start with their provided widgets, ignoring unknown widgets
+//  - the market reports the total of each widget across all pools
+//  - a trade buys widgets from the pool for the pair, paying whatever keeps the product constant plus a fee
+//  - fees stay in the pool
+//  - trades that would empty a side of a pool are rejected
+//  - quotes report the current cost of buying one of each widget from each pool
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::market::{check_counts, ErrorCode, Market, Quote, ValidationError};
+
+const BASIS_POINTS: i64 = 10_000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Pool {
+    reserves: HashMap<String, i32>,
+    // fee charged on the amount paid into the pool, in basis points
+    fee: u32,
+}
+
+impl Pool {
+    // the amount of sell needed to take amount of buy out of the pool
+    fn cost(&self, buy: &str, sell: &str, amount: i32) -> Result<i32, ValidationError> {
+        let buy_reserve = self.reserves[buy] as i64;
+        let sell_reserve = self.reserves[sell] as i64;
+        let amount = amount as i64;
+        if amount >= buy_reserve {
+            return Err(ValidationError::TradeError(
+                ErrorCode::InsufficientWidgets,
+                format!("not enough {} in the {}/{} pool", buy, buy, sell),
+            ));
+        }
+        // the part of the payment left after the fee has to keep the product constant.
+        // round against the trader so the product never shrinks. this is done in i128,
+        // since the numerator overflows i64 for big enough reserves
+        let denominator = ((buy_reserve - amount) * (BASIS_POINTS - self.fee as i64)) as i128;
+        let cost = (sell_reserve as i128 * amount as i128 * BASIS_POINTS as i128 + denominator - 1) / denominator;
+        i32::try_from(cost).map_err(|_| {
+            ValidationError::TradeError(ErrorCode::InvalidAmount, format!("{} {} would cost more {} than anyone can hold", amount, buy, sell))
+        })
+    }
+}
+
+// changes a count, rejecting the trade if it would overflow
+fn change(count: i32, by: i32, widget: &str) -> Result<i32, ValidationError> {
+    count.checked_add(by).ok_or_else(|| {
+        ValidationError::TradeError(ErrorCode::InvalidAmount, format!("trade would overflow the count of {}", widget))
+    })
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AmmMarket {
+    starting_account: HashMap<String, i32>,
+    market: HashMap<String, i32>,
+    accounts: HashMap<String, HashMap<String, i32>>,
+    // pools keyed by their widgets in sorted order
+    #[serde(serialize_with = "serialize_pools", deserialize_with = "deserialize_pools")]
+    pools: HashMap<(String, String), Pool>,
+    next_account: u64,
+}
+
+// json maps need text keys, so pools are stored as a list
+fn serialize_pools<S: Serializer>(pools: &HashMap<(String, String), Pool>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(pools.iter())
+}
+
+fn deserialize_pools<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<(String, String), Pool>, D::Error> {
+    Ok(Vec::<((String, String), Pool)>::deserialize(deserializer)?.into_iter().collect())
+}
+
+fn pool_key(first: &str, second: &str) -> (String, String) {
+    if first < second {
+        (first.to_string(), second.to_string())
+    } else {
+        (second.to_string(), first.to_string())
+    }
+}
+
+impl AmmMarket {
+    // creates a market without any pools where every new account starts with the given widgets
+    pub fn new(starting_account: HashMap<String, i32>) -> AmmMarket {
+        AmmMarket {
+            starting_account,
+            market: HashMap::new(),
+            accounts: HashMap::new(),
+            pools: HashMap::new(),
+            next_account: 0,
+        }
+    }
+
+    // adds a pool for a pair of widgets with some starting reserves and a fee in basis
+    // points, panicking if try_with_pool would reject it
+    pub fn with_pool(self, first: (&str, i32), second: (&str, i32), fee: u32) -> AmmMarket {
+        self.try_with_pool(first, second, fee).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    // adds a pool, unless its widgets are the same, a reserve isn't positive, the fee is
+    // 10000 basis points or more, the pair already has a pool, or the market's totals
+    // would be too big to count
+    pub fn try_with_pool(mut self, first: (&str, i32), second: (&str, i32), fee: u32) -> Result<AmmMarket, ValidationError> {
+        let invalid = |message: String| Err(ValidationError::MarketError(ErrorCode::InvalidAmount, message));
+        if first.0 == second.0 {
+            return Err(ValidationError::MarketError(ErrorCode::IdenticalWidgets, format!("the {} pool needs two different widgets", first.0)));
+        }
+        if first.1 <= 0 || second.1 <= 0 {
+            return invalid(format!("the {}/{} pool needs positive reserves", first.0, second.0));
+        }
+        if fee as i64 >= BASIS_POINTS {
+            return invalid(format!("fee of {} basis points is not less than {}", fee, BASIS_POINTS));
+        }
+        let key = pool_key(first.0, second.0);
+        if self.pools.contains_key(&key) {
+            return Err(ValidationError::MarketError(ErrorCode::Other, format!("there is already a {}/{} pool", first.0, second.0)));
+        }
+        let mut totals = Vec::new();
+        for (widget, reserve) in [first, second].iter() {
+            match self.market.get(*widget).copied().unwrap_or(0).checked_add(*reserve) {
+                Some(total) => totals.push((widget.to_string(), total)),
+                None => return invalid(format!("the pools hold more {} than a market can count", widget)),
+            }
+        }
+
+        self.market.extend(totals);
+        let reserves = [first, second].iter().map(|(widget, reserve)| (widget.to_string(), *reserve)).collect();
+        self.pools.insert(key, Pool { reserves, fee });
+        Ok(self)
+    }
+
+    fn has_account(&self, id: &str) -> Result<(), ValidationError> {
+        if self.accounts.contains_key(id) {
+            Ok(())
+        } else {
+            Err(ValidationError::AccountError(ErrorCode::UnknownAccount, format!("account {} does not exist", id)))
+        }
+    }
+
+    fn get_pool(&self, buy: &str, sell: &str) -> Result<&Pool, ValidationError> {
+        if buy == sell {
+            return Err(ValidationError::TradeError(ErrorCode::IdenticalWidgets, format!("both widgets are {}", buy)));
+        }
+        self.pools.get(&pool_key(buy, sell)).ok_or_else(|| {
+            ValidationError::TradeError(ErrorCode::UnknownWidget, format!("there is no {}/{} pool", buy, sell))
+        })
+    }
+}
+
+impl Market for AmmMarket {
+    fn get_market(&self) -> Result<&HashMap<String, i32>, ValidationError> {
+        Ok(&self.market)
+    }
+
+    fn get_account(&self, id: &str) -> Result<&HashMap<String, i32>, ValidationError> {
+        self.has_account(id)?;
+        Ok(&self.accounts[id])
+    }
+
+    fn get_accounts(&self) -> Result<Vec<String>, ValidationError> {
+        let mut ids: Vec<String> = self.accounts.keys().cloned().collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn get_quotes(&self) -> Result<Vec<Quote>, ValidationError> {
+        let mut quotes = Vec::new();
+        for pool in self.pools.values() {
+            for buy in pool.reserves.keys() {
+                let sell = pool.reserves.keys().find(|widget| *widget != buy).unwrap();
+                // an empty side just means there is nothing left to quote
+                if let Ok(cost) = pool.cost(buy, sell, 1) {
+                    quotes.push(Quote { buy: buy.clone(), buy_amount: 1, sell: sell.clone(), sell_amount: cost });
+                }
+            }
+        }
+        quotes.sort_by(|first, second| (&first.buy, &first.sell).cmp(&(&second.buy, &second.sell)));
+        Ok(quotes)
+    }
+
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+        self.has_account(id)?;
+        if amount <= 0 {
+            return Err(ValidationError::TradeError(ErrorCode::InvalidAmount, "quote amount must be positive".to_string()));
+        }
+        let cost = self.get_pool(buy, sell)?.cost(buy, sell, amount)?;
+        Ok(Quote { buy: buy.to_string(), buy_amount: amount, sell: sell.to_string(), sell_amount: cost })
+    }
+
+    fn create_account(&mut self) -> Result<String, ValidationError> {
+        self.add_account(self.starting_account.clone())
+    }
+
+    fn add_account(&mut self, account: HashMap<String, i32>) -> Result<String, ValidationError> {
+        check_counts(&account)?;
+        let id = format!("account-{}", self.next_account);
+        self.next_account += 1;
+        let mut account: HashMap<String, i32> = account
+            .into_iter()
+            .filter(|(widget, _)| self.market.contains_key(widget))
+            .collect();
+        self.market.keys().for_each(|widget| {
+            account.entry(widget.to_string()).or_insert(0);
+        });
+        self.accounts.insert(id.clone(), account);
+        Ok(id)
+    }
+
+    fn remove_account(&mut self, id: &str) -> Result<HashMap<String, i32>, ValidationError> {
+        self.has_account(id)?;
+        Ok(self.accounts.remove(id).unwrap())
+    }
+
+    fn submit_trade(&mut self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ValidationError> {
+        let cost = self.quote(id, buy, sell, amount)?.sell_amount;
+        if self.accounts[id][sell] < cost {
+            return Err(ValidationError::TradeError(
+                ErrorCode::InsufficientWidgets,
+                format!("not enough {} in account {}", sell, id),
+            ));
+        }
+
+        // every new count is worked out before any of them change, so an overflow leaves the market as it was
+        let pool = &self.pools[&pool_key(buy, sell)];
+        let reserves = (change(pool.reserves[buy], -amount, buy)?, change(pool.reserves[sell], cost, sell)?);
+        let market = (change(self.market[buy], -amount, buy)?, change(self.market[sell], cost, sell)?);
+        let account = (change(self.accounts[id][buy], amount, buy)?, change(self.accounts[id][sell], -cost, sell)?);
+
+        let pool = self.pools.get_mut(&pool_key(buy, sell)).unwrap();
+        pool.reserves.insert(buy.to_string(), reserves.0);
+        pool.reserves.insert(sell.to_string(), reserves.1);
+        self.market.insert(buy.to_string(), market.0);
+        self.market.insert(sell.to_string(), market.1);
+        let holdings = self.accounts.get_mut(id).unwrap();
+        holdings.insert(buy.to_string(), account.0);
+        holdings.insert(sell.to_string(), account.1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market::Leg;
+
+    fn starting_account() -> HashMap<String, i32> {
+        ["foo", "bar", "baz"].iter().map(|&widget| (widget.to_string(), 1000)).collect()
+    }
+
+    fn quote(market: &AmmMarket, buy: &str, sell: &str) -> i32 {
+        market
+            .get_quotes()
+            .unwrap()
+            .into_iter()
+            .find(|quote| quote.buy == buy && quote.sell == sell)
+            .map(|quote| quote.sell_amount)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_amm_market() {
+        let mut market = AmmMarket::new(starting_account())
+            .with_pool(("foo", 10), ("bar", 100), 0)
+            .with_pool(("bar", 100), ("baz", 5), 1000);
+        let id = market.create_account().unwrap();
+
+        // 1 foo out of 10 costs ceil(100 * 1 / 9) bar
+        assert_eq!(quote(&market, "foo", "bar"), 12);
+        market.submit_trade(&id, "foo", "bar", 1).unwrap();
+        assert_eq!(market.get_account(&id).unwrap()["foo"], 1001);
+        assert_eq!(market.get_account(&id).unwrap()["bar"], 988);
+        assert_eq!(market.get_market().unwrap()["foo"], 9);
+        assert_eq!(market.get_market().unwrap()["bar"], 212);
+
+        // the price moves after each trade
+        assert_eq!(quote(&market, "foo", "bar"), 14);
+
+        // without the fee, 1 baz would cost 25 bar
+        assert_eq!(quote(&market, "baz", "bar"), 28);
+
+        // larger quotes walk further along the curve
+        assert_eq!(market.quote(&id, "foo", "bar", 2).unwrap().sell_amount, 32);
+        market.quote(&id, "foo", "bar", 9).expect_err("the pool only has 9 foo");
+
+        // larger trades pay for the whole amount at once
+        market.submit_trade(&id, "foo", "bar", 2).unwrap();
+        assert_eq!(market.get_account(&id).unwrap()["foo"], 1003);
+        assert_eq!(market.get_account(&id).unwrap()["bar"], 956);
+        market.submit_trade(&id, "foo", "bar", 7).expect_err("the pool only has 7 foo");
+        market.submit_trade(&id, "foo", "bar", 0).expect_err("amounts must be positive");
+        assert_eq!(market.get_account(&id).unwrap()["foo"], 1003);
+
+        // bundles trade every leg or none of them
+        let leg = |buy: &str, sell: &str, amount| Leg { buy: buy.to_string(), sell: sell.to_string(), amount };
+        market.submit_bundle(&id, &[leg("bar", "foo", 1), leg("foo", "baz", 1)]).expect_err("there is no foo/baz pool");
+        assert_eq!(market.get_account(&id).unwrap()["foo"], 1003);
+        assert_eq!(market.get_market().unwrap()["foo"], 7);
+        market.submit_bundle(&id, &[]).expect_err("bundles need a leg");
+        market.submit_bundle(&id, &[leg("bar", "foo", 1), leg("foo", "bar", 1)]).unwrap();
+        assert_eq!(market.get_account(&id).unwrap()["foo"], 1003);
+        assert!(market.get_account(&id).unwrap()["bar"] < 956);
+
+        // pools can't be drained
+        for _ in 0..4 {
+            market.submit_trade(&id, "baz", "bar", 1).unwrap();
+        }
+        market.submit_trade(&id, "baz", "bar", 1).expect_err("the last baz can't leave the pool");
+
+        // trades need a pool and enough widgets to pay for it
+        market.submit_trade(&id, "foo", "baz", 1).expect_err("there is no foo/baz pool");
+        market.submit_trade(&id, "foo", "foo", 1).expect_err("shouldn't be able to trade two foos");
+        market.submit_trade("fake id", "foo", "bar", 1).expect_err("shouldn't have been an account!");
+        let poor = market.add_account(HashMap::new()).unwrap();
+        market.submit_trade(&poor, "foo", "bar", 1).expect_err("shouldn't have any bar");
+
+        assert_eq!(market.remove_account(&id).unwrap()["foo"], 1003);
+
+        // costs too big for an i32 are rejected instead of wrapping around to something cheap
+        let rich: HashMap<String, i32> = [("foo".to_string(), 0), ("bar".to_string(), i32::MAX)].iter().cloned().collect();
+        let mut lopsided = AmmMarket::new(rich).with_pool(("foo", 1000), ("bar", 1_000_000_000), 30);
+        let whale = lopsided.create_account().unwrap();
+        let error = lopsided.submit_trade(&whale, "foo", "bar", 999).expect_err("999 foo costs about 1e12 bar");
+        assert_eq!(error.code(), ErrorCode::InvalidAmount);
+        assert_eq!(lopsided.get_market().unwrap()["foo"], 1000);
+        // and so are trades that would overflow what the pool holds
+        lopsided.submit_trade(&whale, "foo", "bar", 600).expect_err("the pool can't hold another 1.5e9 bar");
+        assert_eq!(lopsided.get_account(&whale).unwrap()["bar"], i32::MAX);
+
+        // pools the market couldn't keep track of aren't added
+        let pooled = AmmMarket::new(HashMap::new()).with_pool(("foo", 1000), ("bar", i32::MAX), 30);
+        let code = |pool: ((&str, i32), (&str, i32), u32)| pooled.clone().try_with_pool(pool.0, pool.1, pool.2).map(drop).map_err(|error| error.code());
+        assert_eq!(code((("bar", 10), ("foo", 10), 30)), Err(ErrorCode::Other));
+        assert_eq!(code((("baz", 10), ("baz", 10), 30)), Err(ErrorCode::IdenticalWidgets));
+        assert_eq!(code((("baz", 0), ("foo", 10), 30)), Err(ErrorCode::InvalidAmount));
+        assert_eq!(code((("baz", 10), ("foo", 10), 10000)), Err(ErrorCode::InvalidAmount));
+        assert_eq!(code((("baz", 10), ("bar", 1), 30)), Err(ErrorCode::InvalidAmount));
+        assert_eq!(code((("baz", 10), ("foo", 10), 30)), Ok(()));
+    }
+}
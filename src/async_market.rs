@@ -0,0 +1,369 @@
+// a market whose operations return futures, for implementations that do i/o
+//
+// the server drives these futures on its event loop, so a market that has to wait on a
+// database or another service doesn't hold up everyone else. futures own everything
+// they need, so they may do their work when they are created or when they are polled.
+//
+// operations that a synchronous market gets for free, like checking a condition right
+// before a trade or rolling back a bundle, can't be done atomically from the outside.
+// the defaults here either make them in separate steps, as noted, or reject them; an
+// implementation that can do better should override them
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use futures::future::{self, LocalBoxFuture};
+
+use crate::market::{self, ErrorCode, Fill, Leg, Market, Order, Quote, Snapshot, ValidationError};
+use crate::predicate::Predicate;
+use crate::transfer::AccountBundle;
+
+pub type MarketFuture<T> = LocalBoxFuture<'static, Result<T, ValidationError>>;
+
+fn ready<T: 'static>(result: Result<T, ValidationError>) -> MarketFuture<T> {
+    Box::pin(future::ready(result))
+}
+
+pub trait AsyncMarket {
+    // market viewing
+    fn get_market(&self) -> MarketFuture<HashMap<String, i32>>;
+    fn get_account(&self, id: &str) -> MarketFuture<HashMap<String, i32>>;
+    fn get_accounts(&self) -> MarketFuture<Vec<String>> {
+        ready(Err(market::unsupported("account listing")))
+    }
+    fn get_quotes(&self) -> MarketFuture<Vec<Quote>> {
+        ready(Ok(Vec::new()))
+    }
+    // the market as seen by the account; by default the views are looked up one at a time
+    fn check(&self, id: &str) -> MarketFuture<Snapshot> {
+        let (market, account, quotes) = (self.get_market(), self.get_account(id), self.get_quotes());
+        Box::pin(async move { Ok(Snapshot { market: market.await?, account: account.await?, quotes: quotes.await? }) })
+    }
+    // account modification
+    fn create_account(&self) -> MarketFuture<String>;
+    fn add_account(&self, account: HashMap<String, i32>) -> MarketFuture<String>;
+    fn remove_account(&self, id: &str) -> MarketFuture<HashMap<String, i32>>;
+    // by default this scales the market's quote for the pair, like Market::quote
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> MarketFuture<Quote> {
+        let (account, quotes) = (self.get_account(id), self.get_quotes());
+        let (buy, sell) = (buy.to_string(), sell.to_string());
+        Box::pin(async move {
+            account.await?;
+            market::scale_quote(quotes.await?, &buy, &sell, amount)
+        })
+    }
+    // market modification; either the whole amount is traded or nothing changes
+    fn submit_trade(&self, id: &str, buy: &str, sell: &str, amount: i32) -> MarketFuture<()>;
+    // by default the condition is checked and then the trade is made, so the market may
+    // move in between
+    fn submit_trade_if(&self, id: &str, buy: &str, sell: &str, amount: i32, condition: &Predicate) -> MarketFuture<()>
+    where
+        Self: Sized + Clone + 'static,
+    {
+        let (view, account) = (self.get_market(), self.get_account(id));
+        let (market, condition) = (self.clone(), condition.clone());
+        let (id, buy, sell) = (id.to_string(), buy.to_string(), sell.to_string());
+        Box::pin(async move {
+            if !condition.evaluate_counts(&view.await?, &account.await?) {
+                return Err(ValidationError::TradeError(ErrorCode::PredicateFailed, "trade condition does not hold".to_string()));
+            }
+            market.submit_trade(&id, &buy, &sell, amount).await
+        })
+    }
+    // bundles can't be rolled back from the outside, so they are rejected by default
+    fn submit_bundle(&self, _id: &str, _legs: &[Leg]) -> MarketFuture<()> {
+        ready(Err(market::unsupported("bundles")))
+    }
+    // makes the trade, with the condition if there is one, and returns how far it moved
+    // the market's widget counts. by default the market is looked at before and after,
+    // so other trades made in between show up in the changes too
+    fn trade_changes(&self, id: &str, buy: &str, sell: &str, amount: i32, condition: Option<&Predicate>) -> MarketFuture<HashMap<String, i32>>
+    where
+        Self: Sized + Clone + 'static,
+    {
+        let (market, before) = (self.clone(), self.get_market());
+        let (id, buy, sell, condition) = (id.to_string(), buy.to_string(), sell.to_string(), condition.cloned());
+        Box::pin(async move {
+            let before = before.await?;
+            match condition {
+                Some(condition) => market.submit_trade_if(&id, &buy, &sell, amount, &condition).await?,
+                None => market.submit_trade(&id, &buy, &sell, amount).await?,
+            }
+            Ok(market::changes(&before, &market.get_market().await?))
+        })
+    }
+    // the same for a bundle
+    fn bundle_changes(&self, id: &str, legs: &[Leg]) -> MarketFuture<HashMap<String, i32>>
+    where
+        Self: Sized + Clone + 'static,
+    {
+        let (market, before) = (self.clone(), self.get_market());
+        let (id, legs) = (id.to_string(), legs.to_vec());
+        Box::pin(async move {
+            let before = before.await?;
+            market.submit_bundle(&id, &legs).await?;
+            Ok(market::changes(&before, &market.get_market().await?))
+        })
+    }
+    fn place_order(&self, _id: &str, _sell: &str, _sell_amount: i32, _buy: &str, _buy_amount: i32) -> MarketFuture<Order> {
+        ready(Err(market::unsupported("orders")))
+    }
+    fn cancel_order(&self, _id: &str, _order: u64) -> MarketFuture<Order> {
+        ready(Err(market::unsupported("orders")))
+    }
+    fn get_orders(&self) -> MarketFuture<Vec<Order>> {
+        ready(Err(market::unsupported("orders")))
+    }
+    fn export_account(&self, _id: &str, _destination: &str) -> MarketFuture<AccountBundle> {
+        ready(Err(market::unsupported("transfers")))
+    }
+    fn import_account(&self, _bundle: &AccountBundle) -> MarketFuture<String> {
+        ready(Err(market::unsupported("transfers")))
+    }
+    fn history(&self, _id: &str, _since: u64) -> MarketFuture<Vec<Fill>> {
+        ready(Err(market::unsupported("history")))
+    }
+}
+
+// runs a synchronous market as an async one. every operation is made as soon as it is
+// asked for, so they all stay as atomic as they were
+pub struct SyncMarket<M: Market> {
+    market: Rc<RefCell<M>>,
+}
+
+impl<M: Market> SyncMarket<M> {
+    pub fn new(market: M) -> SyncMarket<M> {
+        SyncMarket { market: Rc::new(RefCell::new(market)) }
+    }
+
+    // shares a market that is also used directly
+    pub fn shared(market: Rc<RefCell<M>>) -> SyncMarket<M> {
+        SyncMarket { market }
+    }
+}
+
+impl<M: Market> Clone for SyncMarket<M> {
+    fn clone(&self) -> SyncMarket<M> {
+        SyncMarket { market: self.market.clone() }
+    }
+}
+
+impl<M: Market + Clone> AsyncMarket for SyncMarket<M> {
+    fn get_market(&self) -> MarketFuture<HashMap<String, i32>> {
+        ready(self.market.borrow().get_market().cloned())
+    }
+
+    fn get_account(&self, id: &str) -> MarketFuture<HashMap<String, i32>> {
+        ready(self.market.borrow().get_account(id).cloned())
+    }
+
+    fn get_accounts(&self) -> MarketFuture<Vec<String>> {
+        ready(self.market.borrow().get_accounts())
+    }
+
+    fn get_quotes(&self) -> MarketFuture<Vec<Quote>> {
+        ready(self.market.borrow().get_quotes())
+    }
+
+    fn check(&self, id: &str) -> MarketFuture<Snapshot> {
+        let market = self.market.borrow();
+        ready(market.get_market().and_then(|view| {
+            Ok(Snapshot { account: market.get_account(id)?.clone(), market: view.clone(), quotes: market.get_quotes()? })
+        }))
+    }
+
+    fn create_account(&self) -> MarketFuture<String> {
+        ready(self.market.borrow_mut().create_account())
+    }
+
+    fn add_account(&self, account: HashMap<String, i32>) -> MarketFuture<String> {
+        ready(self.market.borrow_mut().add_account(account))
+    }
+
+    fn remove_account(&self, id: &str) -> MarketFuture<HashMap<String, i32>> {
+        ready(self.market.borrow_mut().remove_account(id))
+    }
+
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> MarketFuture<Quote> {
+        ready(self.market.borrow().quote(id, buy, sell, amount))
+    }
+
+    fn submit_trade(&self, id: &str, buy: &str, sell: &str, amount: i32) -> MarketFuture<()> {
+        ready(self.market.borrow_mut().submit_trade(id, buy, sell, amount))
+    }
+
+    fn submit_trade_if(&self, id: &str, buy: &str, sell: &str, amount: i32, condition: &Predicate) -> MarketFuture<()>
+    where
+        Self: Sized + Clone + 'static,
+    {
+        ready(self.market.borrow_mut().submit_trade_if(id, buy, sell, amount, condition))
+    }
+
+    fn submit_bundle(&self, id: &str, legs: &[Leg]) -> MarketFuture<()> {
+        ready(self.market.borrow_mut().submit_bundle(id, legs))
+    }
+
+    fn trade_changes(&self, id: &str, buy: &str, sell: &str, amount: i32, condition: Option<&Predicate>) -> MarketFuture<HashMap<String, i32>>
+    where
+        Self: Sized + Clone + 'static,
+    {
+        ready(market::traced(&mut *self.market.borrow_mut(), |market| match condition {
+            Some(condition) => market.submit_trade_if(id, buy, sell, amount, condition),
+            None => market.submit_trade(id, buy, sell, amount),
+        }))
+    }
+
+    fn bundle_changes(&self, id: &str, legs: &[Leg]) -> MarketFuture<HashMap<String, i32>>
+    where
+        Self: Sized + Clone + 'static,
+    {
+        ready(market::traced(&mut *self.market.borrow_mut(), |market| market.submit_bundle(id, legs)))
+    }
+
+    fn place_order(&self, id: &str, sell: &str, sell_amount: i32, buy: &str, buy_amount: i32) -> MarketFuture<Order> {
+        ready(self.market.borrow_mut().place_order(id, sell, sell_amount, buy, buy_amount))
+    }
+
+    fn cancel_order(&self, id: &str, order: u64) -> MarketFuture<Order> {
+        ready(self.market.borrow_mut().cancel_order(id, order))
+    }
+
+    fn get_orders(&self) -> MarketFuture<Vec<Order>> {
+        ready(self.market.borrow().get_orders())
+    }
+
+    fn export_account(&self, id: &str, destination: &str) -> MarketFuture<AccountBundle> {
+        ready(self.market.borrow_mut().export_account(id, destination))
+    }
+
+    fn import_account(&self, bundle: &AccountBundle) -> MarketFuture<String> {
+        ready(self.market.borrow_mut().import_account(bundle))
+    }
+
+    fn history(&self, id: &str, since: u64) -> MarketFuture<Vec<Fill>> {
+        ready(self.market.borrow().history(id, since))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use futures::FutureExt;
+
+    use crate::amm::AmmMarket;
+    use crate::predicate::Op;
+
+    fn starting_account() -> HashMap<String, i32> {
+        ["foo", "bar"].iter().map(|&widget| (widget.to_string(), 10)).collect()
+    }
+
+    // a market kept in a "database" that only answers once it is told to
+    #[derive(Clone, Default)]
+    struct StubMarket {
+        accounts: Rc<RefCell<HashMap<String, HashMap<String, i32>>>>,
+        pending: Rc<RefCell<Vec<oneshot::Sender<()>>>>,
+    }
+
+    impl StubMarket {
+        // waits for the database before running the operation
+        fn query<T: 'static>(&self, operation: impl FnOnce(&mut HashMap<String, HashMap<String, i32>>) -> Result<T, ValidationError> + 'static) -> MarketFuture<T> {
+            let (answer, answered) = oneshot::channel();
+            self.pending.borrow_mut().push(answer);
+            let accounts = self.accounts.clone();
+            Box::pin(async move {
+                answered.await.unwrap();
+                operation(&mut accounts.borrow_mut())
+            })
+        }
+
+        fn answer(&self) {
+            self.pending.borrow_mut().drain(..).for_each(|answer| answer.send(()).unwrap());
+        }
+
+        // answers the database until the future is done
+        fn run<T>(&self, mut future: MarketFuture<T>) -> Result<T, ValidationError> {
+            loop {
+                if let Some(result) = (&mut future).now_or_never() {
+                    return result;
+                }
+                self.answer();
+            }
+        }
+    }
+
+    fn unknown(id: &str) -> ValidationError {
+        ValidationError::AccountError(ErrorCode::UnknownAccount, format!("no account {}", id))
+    }
+
+    impl AsyncMarket for StubMarket {
+        fn get_market(&self) -> MarketFuture<HashMap<String, i32>> {
+            self.query(|_| Ok(HashMap::new()))
+        }
+
+        fn get_account(&self, id: &str) -> MarketFuture<HashMap<String, i32>> {
+            let id = id.to_string();
+            self.query(move |accounts| accounts.get(&id).cloned().ok_or_else(|| unknown(&id)))
+        }
+
+        fn create_account(&self) -> MarketFuture<String> {
+            self.add_account(starting_account())
+        }
+
+        fn add_account(&self, account: HashMap<String, i32>) -> MarketFuture<String> {
+            self.query(move |accounts| {
+                let id = format!("account-{}", accounts.len());
+                accounts.insert(id.clone(), account);
+                Ok(id)
+            })
+        }
+
+        fn remove_account(&self, id: &str) -> MarketFuture<HashMap<String, i32>> {
+            let id = id.to_string();
+            self.query(move |accounts| accounts.remove(&id).ok_or_else(|| unknown(&id)))
+        }
+
+        // swaps one for one
+        fn submit_trade(&self, id: &str, buy: &str, sell: &str, amount: i32) -> MarketFuture<()> {
+            let (id, buy, sell) = (id.to_string(), buy.to_string(), sell.to_string());
+            self.query(move |accounts| {
+                let account = accounts.get_mut(&id).ok_or_else(|| unknown(&id))?;
+                *account.entry(sell).or_default() -= amount;
+                *account.entry(buy).or_default() += amount;
+                Ok(())
+            })
+        }
+    }
+
+    #[test]
+    fn test_async_market() {
+        // nothing happens until the database answers
+        let stub = StubMarket::default();
+        let mut created = stub.create_account();
+        assert!((&mut created).now_or_never().is_none());
+        assert!(stub.accounts.borrow().is_empty());
+        let id = stub.run(created).unwrap();
+
+        // defaults are built from the required operations
+        stub.run(stub.submit_trade_if(&id, "foo", "bar", 2, &Predicate::account("bar", Op::GreaterOrEqual, 2))).unwrap();
+        assert_eq!(stub.run(stub.check(&id)).unwrap().account["foo"], 12);
+        let trade = stub.submit_trade_if(&id, "foo", "bar", 2, &Predicate::account("bar", Op::Greater, 100));
+        assert_eq!(stub.run(trade).unwrap_err().code(), ErrorCode::PredicateFailed);
+        assert_eq!(stub.run(stub.check(&id)).unwrap().account["foo"], 12);
+        assert_eq!(stub.run(stub.submit_bundle(&id, &[])).unwrap_err().code(), ErrorCode::Unsupported);
+
+        // sync markets work through the adapter
+        let sync = SyncMarket::new(AmmMarket::new(starting_account()).with_pool(("foo", 100), ("bar", 100), 0));
+        let id = block_on(sync.create_account()).unwrap();
+        block_on(sync.submit_trade(&id, "foo", "bar", 1)).unwrap();
+        let legs = vec![Leg { buy: "foo".to_string(), sell: "bar".to_string(), amount: 1 }];
+        block_on(sync.submit_bundle(&id, &legs)).unwrap();
+        assert_eq!(block_on(sync.get_account(&id)).unwrap()["foo"], 12);
+        let changes = block_on(sync.trade_changes(&id, "foo", "bar", 1, None)).unwrap();
+        assert_eq!((changes["foo"], changes.len()), (-1, 2));
+        let quote = block_on(sync.quote(&id, "foo", "bar", 1)).unwrap();
+        assert_eq!(quote.buy_amount, 1);
+    }
+}
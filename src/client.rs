@@ -1,126 +1,712 @@
 // a simple client that executes tasks immediately
-use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use capnp::capability::Promise;
+use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
+use futures::channel::mpsc;
+use futures::task::{Context, Poll};
 use futures::AsyncReadExt;
-use futures::FutureExt;
+use futures::{FutureExt, Stream, StreamExt};
 use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
+use std::pin::Pin;
 
-use crate::widget_capnp::market;
+use crate::market::{ErrorCode, Fill, Leg, Order, Quote, Snapshot, TradeEvent, ValidationError};
+use crate::predicate::{Holder, Op, Predicate};
+use crate::transfer::AccountBundle;
+use crate::widget_capnp::{account, exchange, market, market_listener, subscription};
+
+// everything that can go wrong when talking to a market
+#[derive(Debug)]
+pub enum ClientError {
+    // the request never made it to the market or the connection failed
+    Transport(capnp::Error),
+    // the market answered with something we couldn't read
+    Decode(capnp::Error),
+    // the market rejected the request
+    Market(ValidationError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Transport(error) => write!(f, "transport failure: {}", error),
+            ClientError::Decode(error) => write!(f, "unable to decode response: {}", error),
+            ClientError::Market(error) => write!(f, "rejected by market: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Transport(error) | ClientError::Decode(error) => Some(error),
+            ClientError::Market(error) => Some(error),
+        }
+    }
+}
+
+impl From<ValidationError> for ClientError {
+    fn from(error: ValidationError) -> Self {
+        ClientError::Market(error)
+    }
+}
+
+impl From<market::error::Code> for ErrorCode {
+    fn from(code: market::error::Code) -> Self {
+        match code {
+            market::error::Code::Other => ErrorCode::Other,
+            market::error::Code::UnknownAccount => ErrorCode::UnknownAccount,
+            market::error::Code::DuplicateAccount => ErrorCode::DuplicateAccount,
+            market::error::Code::UnknownWidget => ErrorCode::UnknownWidget,
+            market::error::Code::IdenticalWidgets => ErrorCode::IdenticalWidgets,
+            market::error::Code::InsufficientWidgets => ErrorCode::InsufficientWidgets,
+            market::error::Code::InvalidAmount => ErrorCode::InvalidAmount,
+            market::error::Code::UnknownOrder => ErrorCode::UnknownOrder,
+            market::error::Code::Unsupported => ErrorCode::Unsupported,
+            market::error::Code::PredicateFailed => ErrorCode::PredicateFailed,
+            market::error::Code::UnknownMarket => ErrorCode::UnknownMarket,
+            market::error::Code::InvalidBundle => ErrorCode::InvalidBundle,
+            market::error::Code::TradeLimitExceeded => ErrorCode::TradeLimitExceeded,
+            market::error::Code::PositionLimitExceeded => ErrorCode::PositionLimitExceeded,
+        }
+    }
+}
+
+// connects to a server and returns whatever capability it bootstraps
+async fn connect<C: capnp::capability::FromClientHook>(addr: &SocketAddr) -> Result<C, ClientError> {
+    let stream = tokio::net::TcpStream::connect(&addr)
+        .await
+        .map_err(|error| ClientError::Transport(error.into()))?;
+    stream.set_nodelay(true).map_err(|error| ClientError::Transport(error.into()))?;
+    Ok(connect_stream(stream))
+}
+
+// bootstraps a capability over a stream that is already connected to a server
+pub(crate) fn connect_stream<C, S>(stream: S) -> C
+where
+    C: capnp::capability::FromClientHook,
+    S: 'static + tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    // set up the rpc system
+    let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+    let rpc_network = Box::new(twoparty::VatNetwork::new(
+        reader,
+        writer,
+        rpc_twoparty_capnp::Side::Client,
+        Default::default(),
+    ));
+    let mut rpc_system = RpcSystem::new(rpc_network, None);
+    let service: C = rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+
+    // pin the rpc system to a task
+    tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
+    service
+}
+
+fn decode<T>(result: capnp::Result<T>) -> Result<T, ClientError> {
+    result.map_err(ClientError::Decode)
+}
+
+// a response the market can put an error in
+trait Answer<'a> {
+    fn error(self) -> Option<capnp::Result<market::error::Reader<'a>>>;
+}
+
+macro_rules! answers_with_error {
+    ($($results:ident)::+) => {
+        impl<'a> Answer<'a> for $($results)::+::Reader<'a> {
+            fn error(self) -> Option<capnp::Result<market::error::Reader<'a>>> {
+                if self.has_error() {
+                    Some(self.get_error())
+                } else {
+                    None
+                }
+            }
+        }
+    };
+}
+
+answers_with_error!(market::join_results);
+answers_with_error!(market::check_results);
+answers_with_error!(market::trade_results);
+answers_with_error!(market::leave_results);
+answers_with_error!(market::place_order_results);
+answers_with_error!(market::cancel_order_results);
+answers_with_error!(market::list_orders_results);
+answers_with_error!(market::quote_results);
+answers_with_error!(market::submit_bundle_results);
+answers_with_error!(market::subscribe_results);
+answers_with_error!(market::history_results);
+answers_with_error!(account::check_results);
+answers_with_error!(account::trade_results);
+answers_with_error!(account::leave_results);
+answers_with_error!(account::place_order_results);
+answers_with_error!(account::cancel_order_results);
+answers_with_error!(account::quote_results);
+answers_with_error!(account::submit_bundle_results);
+answers_with_error!(account::history_results);
+answers_with_error!(exchange::get_market_results);
+
+impl<'a> Answer<'a> for exchange::list_markets_results::Reader<'a> {
+    fn error(self) -> Option<capnp::Result<market::error::Reader<'a>>> {
+        None
+    }
+}
+
+// sends a request, turning a failed call or an error from the market into a client error
+async fn call<P, R>(request: capnp::capability::Request<P, R>) -> Result<capnp::capability::Response<R>, ClientError>
+where
+    R: capnp::traits::Pipelined + for<'a> capnp::traits::Owned<'a> + 'static + Unpin,
+    <R as capnp::traits::Pipelined>::Pipeline: capnp::capability::FromTypelessPipeline,
+    for<'a> <R as capnp::traits::Owned<'a>>::Reader: Answer<'a>,
+{
+    let response = request.send().promise.await.map_err(ClientError::Transport)?;
+    if let Some(error) = decode(response.get())?.error() {
+        return Err(read_error(decode(error)?)?.into());
+    }
+    Ok(response)
+}
+
+// ids are only handed out by servers built with legacy-ids
+fn read_id(response_has_id: bool, id: capnp::Result<capnp::text::Reader>) -> Result<String, ClientError> {
+    if !response_has_id {
+        return Err(ClientError::Decode(capnp::Error::failed("market did not return an id; use the join_handle methods".to_string())));
+    }
+    Ok(decode(id)?.to_string())
+}
+
+// reads the error field of a response back into a validation error
+fn read_error(error: market::error::Reader) -> Result<ValidationError, ClientError> {
+    let read_reason = |reason: capnp::Result<market::error::reason::Reader>| -> Result<(ErrorCode, String), ClientError> {
+        let reason = decode(reason)?;
+        let code = reason.get_code().map(ErrorCode::from).unwrap_or(ErrorCode::Other);
+        Ok((code, decode(reason.get_message())?.to_string()))
+    };
+    match decode(error.which().map_err(capnp::Error::from))? {
+        market::error::Account(reason) => {
+            let (code, message) = read_reason(reason)?;
+            Ok(ValidationError::AccountError(code, message))
+        }
+        market::error::Market(reason) => {
+            let (code, message) = read_reason(reason)?;
+            Ok(ValidationError::MarketError(code, message))
+        }
+        market::error::Trade(reason) => {
+            let (code, message) = read_reason(reason)?;
+            Ok(ValidationError::TradeError(code, message))
+        }
+    }
+}
+
+fn read_counts(
+    counts: capnp::Result<capnp::struct_list::Reader<market::widget_count::Owned>>,
+) -> Result<HashMap<String, i32>, ClientError> {
+    decode(counts)?
+        .iter()
+        .map(|w| Ok((decode(w.get_widget())?.to_string(), w.get_count())))
+        .collect()
+}
+
+fn read_quote(quote: capnp::Result<market::quote::Reader>) -> Result<Quote, ClientError> {
+    let quote = decode(quote)?;
+    Ok(Quote {
+        buy: decode(quote.get_buy())?.to_string(),
+        buy_amount: quote.get_buy_amount(),
+        sell: decode(quote.get_sell())?.to_string(),
+        sell_amount: quote.get_sell_amount(),
+    })
+}
+
+fn read_quotes(
+    quotes: capnp::Result<capnp::struct_list::Reader<market::quote::Owned>>,
+) -> Result<Vec<Quote>, ClientError> {
+    decode(quotes)?.iter().map(|quote| read_quote(Ok(quote))).collect()
+}
+
+fn read_order(order: capnp::Result<market::order::Reader>) -> Result<Order, ClientError> {
+    let order = decode(order)?;
+    Ok(Order {
+        id: order.get_id(),
+        sell: decode(order.get_sell())?.to_string(),
+        sell_amount: order.get_sell_amount(),
+        buy: decode(order.get_buy())?.to_string(),
+        buy_amount: order.get_buy_amount(),
+        remaining: order.get_remaining(),
+    })
+}
+
+fn read_fills(fills: capnp::Result<capnp::struct_list::Reader<market::fill::Owned>>) -> Result<Vec<Fill>, ClientError> {
+    decode(fills)?
+        .iter()
+        .map(|fill| {
+            Ok(Fill {
+                sequence: fill.get_sequence(),
+                timestamp: fill.get_timestamp(),
+                buy: decode(fill.get_buy())?.to_string(),
+                buy_amount: fill.get_buy_amount(),
+                sell: decode(fill.get_sell())?.to_string(),
+                sell_amount: fill.get_sell_amount(),
+            })
+        })
+        .collect()
+}
+
+fn read_bundle(response_has_bundle: bool, bundle: capnp::Result<market::account_bundle::Reader>) -> Result<AccountBundle, ClientError> {
+    if !response_has_bundle {
+        return Err(ClientError::Decode(capnp::Error::failed("market did not return a bundle".to_string())));
+    }
+    let bundle = decode(bundle)?;
+    Ok(AccountBundle {
+        origin: decode(bundle.get_origin())?.to_string(),
+        destination: decode(bundle.get_destination())?.to_string(),
+        account: read_counts(bundle.get_account())?,
+        nonce: bundle.get_nonce(),
+        signature: decode(bundle.get_signature())?.to_string(),
+    })
+}
+
+fn set_bundle(mut builder: market::account_bundle::Builder, bundle: &AccountBundle) {
+    builder.set_origin(&bundle.origin);
+    builder.set_destination(&bundle.destination);
+    let mut counts = builder.reborrow().init_account(bundle.account.len() as u32);
+    bundle.account.iter().enumerate().for_each(|(i, (w, c))| {
+        counts.reborrow().get(i as u32).set_widget(w);
+        counts.reborrow().get(i as u32).set_count(*c);
+    });
+    builder.set_nonce(bundle.nonce);
+    builder.set_signature(&bundle.signature);
+}
+
+fn set_legs(mut builder: capnp::struct_list::Builder<market::leg::Owned>, legs: &[Leg]) {
+    legs.iter().enumerate().for_each(|(i, leg)| {
+        let mut builder = builder.reborrow().get(i as u32);
+        builder.set_buy(&leg.buy);
+        builder.set_sell(&leg.sell);
+        builder.set_amount(leg.amount);
+    });
+}
+
+fn read_legs(legs: capnp::Result<capnp::struct_list::Reader<market::leg::Owned>>) -> Result<Vec<Leg>, ClientError> {
+    decode(legs)?
+        .iter()
+        .map(|leg| Ok(Leg { buy: decode(leg.get_buy())?.to_string(), sell: decode(leg.get_sell())?.to_string(), amount: leg.get_amount() }))
+        .collect()
+}
+
+fn set_predicate(builder: market::predicate::Builder, predicate: &Predicate) {
+    let set_all = |mut builder: capnp::struct_list::Builder<market::predicate::Owned>, predicates: &[Predicate]| {
+        predicates.iter().enumerate().for_each(|(i, predicate)| set_predicate(builder.reborrow().get(i as u32), predicate));
+    };
+    match predicate {
+        Predicate::Compare { holder, widget, op, value } => {
+            let mut comparison = builder.init_compare();
+            comparison.set_holder(match holder {
+                Holder::Market => market::predicate::Holder::Market,
+                Holder::Account => market::predicate::Holder::Account,
+            });
+            comparison.set_widget(widget);
+            comparison.set_op(match op {
+                Op::Less => market::predicate::Op::Less,
+                Op::LessOrEqual => market::predicate::Op::LessOrEqual,
+                Op::Equal => market::predicate::Op::Equal,
+                Op::GreaterOrEqual => market::predicate::Op::GreaterOrEqual,
+                Op::Greater => market::predicate::Op::Greater,
+            });
+            comparison.set_value(*value);
+        }
+        Predicate::All(predicates) => set_all(builder.init_all(predicates.len() as u32), predicates),
+        Predicate::Any(predicates) => set_all(builder.init_any(predicates.len() as u32), predicates),
+        Predicate::Not(predicate) => set_predicate(builder.init_not(), predicate),
+    }
+}
+
+// hands the events the market calls back with to a trade stream
+struct ListenerServer {
+    events: mpsc::UnboundedSender<TradeEvent>,
+}
+
+impl market_listener::Server for ListenerServer {
+    fn on_trade(&mut self, params: market_listener::OnTradeParams, _: market_listener::OnTradeResults) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let event = TradeEvent {
+            legs: pry!(read_legs(params.get_legs()).map_err(|error| capnp::Error::failed(error.to_string()))),
+            changes: pry!(read_counts(params.get_changes()).map_err(|error| capnp::Error::failed(error.to_string()))),
+        };
+        // failing tells the market to stop calling once nobody is reading
+        match self.events.unbounded_send(event) {
+            Ok(()) => Promise::ok(()),
+            Err(_) => Promise::err(capnp::Error::disconnected("trade stream was dropped".to_string())),
+        }
+    }
+}
+
+// the trades made in a market, in the order they were made. the stream ends if
+// the connection to the market is lost, and dropping it unsubscribes
+pub struct TradeStream {
+    _subscription: subscription::Client,
+    events: mpsc::UnboundedReceiver<TradeEvent>,
+}
+
+impl Stream for TradeStream {
+    type Item = TradeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<TradeEvent>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
 
 pub struct WidgetMarketClient {
     service: market::Client,
 }
 
 impl WidgetMarketClient {
-    pub async fn new(addr: &SocketAddr) -> Result<WidgetMarketClient, Box<dyn std::error::Error>> {
-        // set up the rpc system
-        let stream = tokio::net::TcpStream::connect(&addr).await?;
-        stream.set_nodelay(true)?;
-        let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
-        let rpc_network = Box::new(twoparty::VatNetwork::new(
-            reader,
-            writer,
-            rpc_twoparty_capnp::Side::Client,
-            Default::default(),
-        ));
-        let mut rpc_system = RpcSystem::new(rpc_network, None);
-        let service: market::Client = rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
-
-        // pin the rpc system to a task
-        tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
-
-        // return the service
-        Ok(WidgetMarketClient { service })
+    // connects to a server that runs a single market
+    pub async fn new(addr: &SocketAddr) -> Result<WidgetMarketClient, ClientError> {
+        Ok(WidgetMarketClient { service: connect(addr).await? })
+    }
+
+    // talks to a market over a stream that is already connected to its server
+    pub(crate) fn from_stream<S: 'static + tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(stream: S) -> WidgetMarketClient {
+        WidgetMarketClient { service: connect_stream(stream) }
     }
 
     // joins the market and returns the id for the account
-    pub async fn join(&self) -> String {
-        self.service
-            .join_request()
-            .send()
-            .promise
-            .await
-            .unwrap()
-            .get()
-            .unwrap()
-            .get_id()
-            .unwrap()
-            .to_string()
+    pub async fn join(&self) -> Result<String, ClientError> {
+        let response = call(self.service.join_request()).await?;
+        let response = decode(response.get())?;
+        read_id(response.has_id(), response.get_id())
     }
 
     // joins the market and returns the id for the account
-    pub async fn join_with_account(&self, account: HashMap<String, i32>) -> String {
+    pub async fn join_with_account(&self, account: HashMap<String, i32>) -> Result<String, ClientError> {
         let mut request = self.service.join_request();
         let mut builder = request.get().init_account(account.len() as u32);
         account.iter().enumerate().for_each(|(i, (w, c))| {
             builder.reborrow().get(i as u32).set_widget(w);
-            builder.reborrow().get(i as u32).set_count(*c as i32);
+            builder.reborrow().get(i as u32).set_count(*c);
         });
 
-        request
-            .send()
-            .promise
-            .await
-            .unwrap()
-            .get()
-            .unwrap()
-            .get_id()
-            .unwrap()
-            .to_string()
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_id(response.has_id(), response.get_id())
+    }
+
+    // joins the market and returns a handle to the new account
+    pub async fn join_handle(&self) -> Result<AccountHandle, ClientError> {
+        let response = call(self.service.join_request()).await?;
+        let response = decode(response.get())?;
+        Ok(AccountHandle { account: decode(response.get_account())? })
+    }
+
+    // joins the market with an account and returns a handle to it
+    pub async fn join_handle_with_account(&self, account: HashMap<String, i32>) -> Result<AccountHandle, ClientError> {
+        let mut request = self.service.join_request();
+        let mut builder = request.get().init_account(account.len() as u32);
+        account.iter().enumerate().for_each(|(i, (w, c))| {
+            builder.reborrow().get(i as u32).set_widget(w);
+            builder.reborrow().get(i as u32).set_count(*c);
+        });
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        Ok(AccountHandle { account: decode(response.get_account())? })
+    }
+
+    // joins the market with an account brought from another market
+    pub async fn join_with_bundle(&self, bundle: &AccountBundle) -> Result<String, ClientError> {
+        let mut request = self.service.join_request();
+        set_bundle(request.get().init_bundle(), bundle);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_id(response.has_id(), response.get_id())
+    }
+
+    // joins the market with an account brought from another market and returns a handle to it
+    pub async fn join_handle_with_bundle(&self, bundle: &AccountBundle) -> Result<AccountHandle, ClientError> {
+        let mut request = self.service.join_request();
+        set_bundle(request.get().init_bundle(), bundle);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        Ok(AccountHandle { account: decode(response.get_account())? })
     }
 
     // checks the current status of the market from the account's perspective
-    pub async fn check(&self, id: &str) -> (HashMap<String, i32>, HashMap<String, i32>) {
+    pub async fn check(&self, id: &str) -> Result<Snapshot, ClientError> {
         let mut request = self.service.check_request();
         request.get().set_id(id);
 
-        let result = request.send().promise.await.unwrap();
-        let market = result.get().unwrap();
-        (
-            market
-                .get_account()
-                .unwrap()
-                .iter()
-                .map(|w| (w.get_widget().unwrap().to_string(), w.get_count()))
-                .collect(),
-            market
-                .get_market()
-                .unwrap()
-                .iter()
-                .map(|w| (w.get_widget().unwrap().to_string(), w.get_count()))
-                .collect(),
-        )
-    }
-
-    // request a trade be made
-    pub async fn trade(&self, id: &str, first: &str, second: &str) -> Result<(), String> {
+        let response = call(request).await?;
+        let market = decode(response.get())?;
+        Ok(Snapshot {
+            account: read_counts(market.get_account())?,
+            market: read_counts(market.get_market())?,
+            quotes: read_quotes(market.get_quotes())?,
+        })
+    }
+
+    // asks what buying some amount of a widget would cost without trading
+    pub async fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ClientError> {
+        let mut request = self.service.quote_request();
+        request.get().set_id(id);
+        request.get().set_buy(buy);
+        request.get().set_sell(sell);
+        request.get().set_amount(amount);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_quote(response.get_quote())
+    }
+
+    // request that amount of buy be bought with sell; either all of it trades or none of it does
+    pub async fn trade(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ClientError> {
         let mut request = self.service.trade_request();
         request.get().set_id(id);
-        request.get().set_buy(first);
-        request.get().set_sell(second);
+        request.get().set_buy(buy);
+        request.get().set_sell(sell);
+        request.get().set_amount(amount);
 
-        match request.send().promise.await {
-            Ok(_) => Ok(()),
-            // let's recapture the validation error perhaps?
-            Err(error) => Err(error.to_string().split("\"").skip(1).take(1).collect()),
-        }
+        call(request).await?;
+        Ok(())
+    }
+
+    // like trade, but the market only makes the trade if the condition holds
+    pub async fn trade_if(&self, id: &str, buy: &str, sell: &str, amount: i32, condition: &Predicate) -> Result<(), ClientError> {
+        let mut request = self.service.trade_request();
+        request.get().set_id(id);
+        request.get().set_buy(buy);
+        request.get().set_sell(sell);
+        request.get().set_amount(amount);
+        set_predicate(request.get().init_condition(), condition);
+
+        call(request).await?;
+        Ok(())
     }
 
     // leaves the market and returns the number of points scored
-    pub async fn leave(&self, id: &str) -> HashMap<String, i32> {
+    pub async fn leave(&self, id: &str) -> Result<HashMap<String, i32>, ClientError> {
         let mut request = self.service.leave_request();
         request.get().set_id(id);
-        request
-            .send()
-            .promise
-            .await
-            .unwrap()
-            .get()
-            .unwrap()
-            .get_account()
-            .unwrap()
-            .iter()
-            .map(|w| (w.get_widget().unwrap().to_string(), w.get_count()))
-            .collect()
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_counts(response.get_account())
+    }
+
+    // leaves the market for another one, returning the signed bundle to join it with
+    pub async fn leave_for(&self, id: &str, destination: &str) -> Result<AccountBundle, ClientError> {
+        let mut request = self.service.leave_request();
+        request.get().set_id(id);
+        request.get().set_destination(destination);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_bundle(response.has_bundle(), response.get_bundle())
+    }
+
+    // places a limit order and returns what is left of it after matching
+    pub async fn place_order(&self, id: &str, sell: &str, sell_amount: i32, buy: &str, buy_amount: i32) -> Result<Order, ClientError> {
+        let mut request = self.service.place_order_request();
+        request.get().set_id(id);
+        request.get().set_sell(sell);
+        request.get().set_sell_amount(sell_amount);
+        request.get().set_buy(buy);
+        request.get().set_buy_amount(buy_amount);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_order(response.get_order())
+    }
+
+    // cancels a resting order and returns what was left of it
+    pub async fn cancel_order(&self, id: &str, order: u64) -> Result<Order, ClientError> {
+        let mut request = self.service.cancel_order_request();
+        request.get().set_id(id);
+        request.get().set_order(order);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_order(response.get_order())
+    }
+
+    // request that every leg be traded, or none of them
+    pub async fn submit_bundle(&self, id: &str, legs: &[Leg]) -> Result<(), ClientError> {
+        let mut request = self.service.submit_bundle_request();
+        request.get().set_id(id);
+        set_legs(request.get().init_legs(legs.len() as u32), legs);
+
+        call(request).await?;
+        Ok(())
+    }
+
+    // gets a page of the account's trades numbered after since, oldest first
+    pub async fn history(&self, id: &str, since: u64) -> Result<Vec<Fill>, ClientError> {
+        let mut request = self.service.history_request();
+        request.get().set_id(id);
+        request.get().set_since(since);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_fills(response.get_fills())
+    }
+
+    // subscribes to the trades made in the market from now on
+    pub async fn subscribe(&self) -> Result<TradeStream, ClientError> {
+        let (sender, events) = mpsc::unbounded();
+        let mut request = self.service.subscribe_request();
+        request.get().set_listener(capnp_rpc::new_client(ListenerServer { events: sender }));
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        Ok(TradeStream { _subscription: decode(response.get_subscription())?, events })
+    }
+
+    // lists the orders resting in the book
+    pub async fn list_orders(&self) -> Result<Vec<Order>, ClientError> {
+        let response = call(self.service.list_orders_request()).await?;
+        let response = decode(response.get())?;
+        decode(response.get_orders())?.iter().map(|order| read_order(Ok(order))).collect()
+    }
+}
+
+// an account held through the capability returned by join; it is only valid
+// for as long as the connection that joined stays open
+pub struct AccountHandle {
+    account: account::Client,
+}
+
+impl AccountHandle {
+    // checks the current status of the market from the account's perspective
+    pub async fn check(&self) -> Result<Snapshot, ClientError> {
+        let response = call(self.account.check_request()).await?;
+        let market = decode(response.get())?;
+        Ok(Snapshot {
+            account: read_counts(market.get_account())?,
+            market: read_counts(market.get_market())?,
+            quotes: read_quotes(market.get_quotes())?,
+        })
+    }
+
+    // asks what buying some amount of a widget would cost without trading
+    pub async fn quote(&self, buy: &str, sell: &str, amount: i32) -> Result<Quote, ClientError> {
+        let mut request = self.account.quote_request();
+        request.get().set_buy(buy);
+        request.get().set_sell(sell);
+        request.get().set_amount(amount);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_quote(response.get_quote())
+    }
+
+    // request that amount of buy be bought with sell; either all of it trades or none of it does
+    pub async fn trade(&self, buy: &str, sell: &str, amount: i32) -> Result<(), ClientError> {
+        let mut request = self.account.trade_request();
+        request.get().set_buy(buy);
+        request.get().set_sell(sell);
+        request.get().set_amount(amount);
+
+        call(request).await?;
+        Ok(())
+    }
+
+    // like trade, but the market only makes the trade if the condition holds
+    pub async fn trade_if(&self, buy: &str, sell: &str, amount: i32, condition: &Predicate) -> Result<(), ClientError> {
+        let mut request = self.account.trade_request();
+        request.get().set_buy(buy);
+        request.get().set_sell(sell);
+        request.get().set_amount(amount);
+        set_predicate(request.get().init_condition(), condition);
+
+        call(request).await?;
+        Ok(())
+    }
+
+    // leaves the market and returns the final account
+    pub async fn leave(self) -> Result<HashMap<String, i32>, ClientError> {
+        let response = call(self.account.leave_request()).await?;
+        let response = decode(response.get())?;
+        read_counts(response.get_account())
+    }
+
+    // leaves the market for another one, returning the signed bundle to join it with
+    pub async fn leave_for(self, destination: &str) -> Result<AccountBundle, ClientError> {
+        let mut request = self.account.leave_request();
+        request.get().set_destination(destination);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_bundle(response.has_bundle(), response.get_bundle())
+    }
+
+    // places a limit order and returns what is left of it after matching
+    pub async fn place_order(&self, sell: &str, sell_amount: i32, buy: &str, buy_amount: i32) -> Result<Order, ClientError> {
+        let mut request = self.account.place_order_request();
+        request.get().set_sell(sell);
+        request.get().set_sell_amount(sell_amount);
+        request.get().set_buy(buy);
+        request.get().set_buy_amount(buy_amount);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_order(response.get_order())
+    }
+
+    // cancels a resting order and returns what was left of it
+    pub async fn cancel_order(&self, order: u64) -> Result<Order, ClientError> {
+        let mut request = self.account.cancel_order_request();
+        request.get().set_order(order);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_order(response.get_order())
+    }
+
+    // request that every leg be traded, or none of them
+    pub async fn submit_bundle(&self, legs: &[Leg]) -> Result<(), ClientError> {
+        let mut request = self.account.submit_bundle_request();
+        set_legs(request.get().init_legs(legs.len() as u32), legs);
+
+        call(request).await?;
+        Ok(())
+    }
+
+    // gets a page of the account's trades numbered after since, oldest first
+    pub async fn history(&self, since: u64) -> Result<Vec<Fill>, ClientError> {
+        let mut request = self.account.history_request();
+        request.get().set_since(since);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        read_fills(response.get_fills())
+    }
+}
+
+// a client for a server hosting several named markets
+pub struct ExchangeClient {
+    service: exchange::Client,
+}
+
+impl ExchangeClient {
+    pub async fn new(addr: &SocketAddr) -> Result<ExchangeClient, ClientError> {
+        Ok(ExchangeClient { service: connect(addr).await? })
+    }
+
+    // lists the names of the hosted markets
+    pub async fn list_markets(&self) -> Result<Vec<String>, ClientError> {
+        let response = call(self.service.list_markets_request()).await?;
+        let response = decode(response.get())?;
+        decode(response.get_names())?.iter().map(|name| Ok(decode(name)?.to_string())).collect()
+    }
+
+    // gets a client for one of the hosted markets
+    pub async fn get_market(&self, name: &str) -> Result<WidgetMarketClient, ClientError> {
+        let mut request = self.service.get_market_request();
+        request.get().set_name(name);
+
+        let response = call(request).await?;
+        let response = decode(response.get())?;
+        Ok(WidgetMarketClient { service: decode(response.get_market())? })
     }
 }
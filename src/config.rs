@@ -0,0 +1,240 @@
+// configuration for market servers, read from a json file
+//
+// a config says where to listen, what market to serve and how to serve it:
+//
+//   {
+//     "address": "127.0.0.1:8080",
+//     "market": {"kind": "amm", "pools": [["foo", 1000, "bar", 1000]], "fee": 30},
+//     "account": {"foo": 10, "bar": 10},
+//     "log_level": "info",
+//     "state": "market-state",
+//     "settle": "settled",
+//     "max_connections": 64
+//   }
+//
+// only the address and market are required. the market kinds are "amm", "order-book"
+// and "custom"; the library builds the first two itself, while custom markets are built
+// by whoever runs them, with the "inventory" the config gives them. only amm markets
+// charge a fee; order books and custom markets have none. every market is served with
+// a ledger, and with journaling if there is a "state" directory
+use std::collections::HashMap;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::{info, LevelFilter};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::amm::AmmMarket;
+use crate::ledger::Ledgered;
+use crate::market::{Market, ValidationError};
+use crate::order_book::OrderBookMarket;
+use crate::persistence::PersistentMarket;
+use crate::single_market::{self, MarketServer};
+use crate::threaded_market;
+
+fn default_fee() -> u32 {
+    30
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum MarketKind {
+    // pools are [widget, reserve, widget, reserve] for two different widgets with positive
+    // reserves, all charging the fee in basis points. no other kind has a fee
+    Amm {
+        pools: Vec<(String, i32, String, i32)>,
+        #[serde(default = "default_fee")]
+        fee: u32,
+    },
+    OrderBook,
+    // a market the library doesn't provide, starting with this inventory
+    Custom {
+        #[serde(default)]
+        inventory: HashMap<String, i32>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub market: MarketKind,
+    // what new accounts start with
+    #[serde(default)]
+    pub account: HashMap<String, i32>,
+    // one of off, error, warn, info, debug or trace
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    // directory the market is journaled to and recovered from
+    #[serde(default)]
+    pub state: Option<PathBuf>,
+    // directory open accounts are settled into when the server is stopped
+    #[serde(default)]
+    pub settle: Option<PathBuf>,
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    // spreads connections over this many threads (see threaded_market)
+    #[serde(default)]
+    pub threads: Option<usize>,
+}
+
+impl ServerConfig {
+    // reads and checks a config
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ServerConfig, Box<dyn std::error::Error>> {
+        let config: ServerConfig = serde_json::from_str(&fs::read_to_string(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // catches settings that would only fail once the server is running
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.socket_addr()?;
+        self.log_filter()?;
+        if let MarketKind::Amm { pools, fee } = &self.market {
+            if *fee >= 10000 {
+                return Err(format!("fee of {} basis points is not less than 10000", fee).into());
+            }
+            // the amm checks its own pools
+            amm(&self.account, pools, *fee)?;
+        }
+        if self.max_connections == Some(0) {
+            return Err("max_connections has to allow at least one connection".into());
+        }
+        if self.threads.is_some() && self.settle.is_some() {
+            return Err("threaded servers can't settle their accounts".into());
+        }
+        Ok(())
+    }
+
+    pub fn socket_addr(&self) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+        self.address.to_socket_addrs()?.next().ok_or_else(|| format!("could not resolve {}", self.address).into())
+    }
+
+    pub fn log_filter(&self) -> Result<LevelFilter, Box<dyn std::error::Error>> {
+        LevelFilter::from_str(&self.log_level).map_err(|_| format!("{} is not a log level", self.log_level).into())
+    }
+
+    // sets up logging at the configured level, unless a logger was already set up
+    pub fn init_logger(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let _ = env_logger::builder().filter(None, self.log_filter()?).try_init();
+        Ok(())
+    }
+}
+
+fn amm(account: &HashMap<String, i32>, pools: &[(String, i32, String, i32)], fee: u32) -> Result<AmmMarket, ValidationError> {
+    pools.iter().try_fold(AmmMarket::new(account.clone()), |market, (first, first_reserve, second, second_reserve)| {
+        market.try_with_pool((first, *first_reserve), (second, *second_reserve), fee)
+    })
+}
+
+// builds and serves one of the library's markets as configured
+pub async fn run_from_config(config: &ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    match &config.market {
+        MarketKind::Amm { pools, fee } => {
+            run_market_from_config(config, amm(&config.account, pools, *fee)?).await
+        }
+        MarketKind::OrderBook => run_market_from_config(config, OrderBookMarket::new(config.account.clone())).await,
+        MarketKind::Custom { .. } => Err("custom markets have to be built and run with run_market_from_config".into()),
+    }
+}
+
+// serves a market that was already built, with the rest of the config
+pub async fn run_market_from_config<M>(config: &ServerConfig, market: M) -> Result<(), Box<dyn std::error::Error>>
+where
+    M: 'static + Market + Clone + Send + Serialize + DeserializeOwned,
+{
+    config.validate()?;
+    config.init_logger()?;
+    let market = Ledgered::new(market);
+    match &config.state {
+        Some(dir) => serve(config, PersistentMarket::open(dir, market)?).await,
+        _ => serve(config, market).await,
+    }
+}
+
+async fn serve<M: 'static + Market + Clone + Send>(config: &ServerConfig, market: M) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = config.socket_addr()?;
+    info!("starting market server at {} with contents:", addr);
+    market.get_market()?.iter().for_each(|(k, v)| info!(" - {}: {}", k, v));
+
+    if let Some(threads) = config.threads {
+        return threaded_market::run(addr, market, threads, config.max_connections).await;
+    }
+    let mut server = MarketServer::new(market);
+    if let Some(limit) = config.max_connections {
+        server = server.with_max_connections(limit);
+    }
+    match &config.settle {
+        Some(dir) => single_market::run_server_until(addr, server, single_market::shutdown_signal(), dir).await,
+        _ => single_market::run_server(addr, server).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_config() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"address": "127.0.0.1:8080", "market": {"kind": "amm", "pools": [["foo", 1000, "bar", 1000]]}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.market, MarketKind::Amm { pools: vec![("foo".to_string(), 1000, "bar".to_string(), 1000)], fee: 30 });
+        assert_eq!((config.log_level.as_str(), config.state.as_ref(), config.max_connections), ("info", None, None));
+        config.validate().unwrap();
+
+        // custom markets get their inventory
+        let custom: ServerConfig = serde_json::from_str(
+            r#"{"address": "127.0.0.1:8080", "market": {"kind": "custom", "inventory": {"foo": 5}}, "log_level": "debug"}"#,
+        )
+        .unwrap();
+        assert_eq!(custom.market, MarketKind::Custom { inventory: [("foo".to_string(), 5)].iter().cloned().collect() });
+        assert_eq!(custom.log_filter().unwrap(), LevelFilter::Debug);
+
+        // typos are caught when the config is read instead of being ignored
+        serde_json::from_str::<ServerConfig>(r#"{"address": "127.0.0.1:8080", "market": {"kind": "order-book"}, "stat": "dir"}"#)
+            .expect_err("stat isn't a setting");
+        // and settings that can't work are caught before serving
+        let broken = |change: fn(&mut ServerConfig)| {
+            let mut config = config.clone();
+            change(&mut config);
+            config.validate().expect_err("config shouldn't be valid")
+        };
+        broken(|config| config.address = "not an address".to_string());
+        broken(|config| config.log_level = "loud".to_string());
+        broken(|config| config.market = MarketKind::Amm { pools: Vec::new(), fee: 10000 });
+        let pools = |pools: &[(&str, i32, &str, i32)]| MarketKind::Amm {
+            pools: pools.iter().map(|&(first, first_reserve, second, second_reserve)| (first.to_string(), first_reserve, second.to_string(), second_reserve)).collect(),
+            fee: 30,
+        };
+        for broken_pools in [
+            pools(&[("foo", 1000, "foo", 1000)]),
+            pools(&[("foo", 0, "bar", 1000)]),
+            pools(&[("foo", 1000, "bar", -5)]),
+            pools(&[("foo", 1000, "bar", 1000), ("bar", 10, "foo", 10)]),
+            pools(&[("foo", i32::MAX, "bar", 1000), ("foo", 1, "baz", 1000)]),
+        ]
+        .iter()
+        {
+            let mut config = config.clone();
+            config.market = broken_pools.clone();
+            config.validate().expect_err("pools shouldn't be valid");
+        }
+        let mut shared = config.clone();
+        shared.market = pools(&[("foo", 1000, "bar", 1000), ("foo", 1000, "baz", 1000)]);
+        shared.validate().unwrap();
+        broken(|config| config.max_connections = Some(0));
+        broken(|config| {
+            config.threads = Some(2);
+            config.settle = Some(PathBuf::from("settled"));
+        });
+    }
+}
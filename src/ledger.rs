@@ -0,0 +1,223 @@
+// a market wrapper that keeps a ledger of every trade its accounts make
+//
+// after every trade or order the wrapped market is asked what each account bought and
+// sold, so both sides of orders matched in a book are recorded. markets that don't say
+// only have trades recorded, with what was bought and paid read off the account before
+// and after. bundles are recorded leg by leg, and a failed bundle leaves no fills behind
+// since the whole ledger is rolled back with the market. an account's fills are dropped
+// when it leaves.
+//
+// every fill from one call shares its sequence number. fills are stamped with when the
+// trade was made, so trades replayed from a journal since the last snapshot get the
+// time they were replayed
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::market::{Execution, Fill, Market, Order, Quote, ValidationError};
+use crate::transfer::AccountBundle;
+
+// the most fills returned by one call to history
+pub const HISTORY_PAGE: usize = 100;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ledgered<M: Market> {
+    market: M,
+    // sequence number of the last trade made
+    sequence: u64,
+    // every account's fills, oldest first
+    fills: HashMap<String, Vec<Fill>>,
+}
+
+impl<M: Market> Ledgered<M> {
+    pub fn new(market: M) -> Ledgered<M> {
+        Ledgered { market, sequence: 0, fills: HashMap::new() }
+    }
+
+    // records what every account traded in one call as a new trade
+    fn record(&mut self, executions: Vec<Execution>) {
+        if executions.is_empty() {
+            return;
+        }
+        self.sequence += 1;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        for execution in executions {
+            let fill = Fill {
+                sequence: self.sequence,
+                timestamp,
+                buy: execution.buy,
+                buy_amount: execution.buy_amount,
+                sell: execution.sell,
+                sell_amount: execution.sell_amount,
+            };
+            self.fills.entry(execution.account).or_default().push(fill);
+        }
+    }
+}
+
+impl<M: Market> Market for Ledgered<M> {
+    fn get_market(&self) -> Result<&HashMap<String, i32>, ValidationError> {
+        self.market.get_market()
+    }
+
+    fn get_account(&self, id: &str) -> Result<&HashMap<String, i32>, ValidationError> {
+        self.market.get_account(id)
+    }
+
+    fn get_accounts(&self) -> Result<Vec<String>, ValidationError> {
+        self.market.get_accounts()
+    }
+
+    fn get_quotes(&self) -> Result<Vec<Quote>, ValidationError> {
+        self.market.get_quotes()
+    }
+
+    fn create_account(&mut self) -> Result<String, ValidationError> {
+        self.market.create_account()
+    }
+
+    fn add_account(&mut self, account: HashMap<String, i32>) -> Result<String, ValidationError> {
+        self.market.add_account(account)
+    }
+
+    fn remove_account(&mut self, id: &str) -> Result<HashMap<String, i32>, ValidationError> {
+        let account = self.market.remove_account(id)?;
+        self.fills.remove(id);
+        Ok(account)
+    }
+
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+        self.market.quote(id, buy, sell, amount)
+    }
+
+    fn submit_trade(&mut self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ValidationError> {
+        let before = self.market.get_account(id)?.clone();
+        self.market.submit_trade(id, buy, sell, amount)?;
+        let mut executions = self.market.last_executions();
+        if executions.is_empty() {
+            let after = self.market.get_account(id)?;
+            let count = |account: &HashMap<String, i32>, widget: &str| account.get(widget).copied().unwrap_or(0);
+            executions.push(Execution {
+                account: id.to_string(),
+                buy: buy.to_string(),
+                buy_amount: count(after, buy) - count(&before, buy),
+                sell: sell.to_string(),
+                sell_amount: count(&before, sell) - count(after, sell),
+            });
+        }
+        self.record(executions);
+        Ok(())
+    }
+
+    fn place_order(&mut self, id: &str, sell: &str, sell_amount: i32, buy: &str, buy_amount: i32) -> Result<Order, ValidationError> {
+        let order = self.market.place_order(id, sell, sell_amount, buy, buy_amount)?;
+        self.record(self.market.last_executions());
+        Ok(order)
+    }
+
+    fn cancel_order(&mut self, id: &str, order: u64) -> Result<Order, ValidationError> {
+        self.market.cancel_order(id, order)
+    }
+
+    fn get_orders(&self) -> Result<Vec<Order>, ValidationError> {
+        self.market.get_orders()
+    }
+
+    fn last_executions(&self) -> Vec<Execution> {
+        self.market.last_executions()
+    }
+
+    fn export_account(&mut self, id: &str, destination: &str) -> Result<AccountBundle, ValidationError> {
+        let bundle = self.market.export_account(id, destination)?;
+        self.fills.remove(id);
+        Ok(bundle)
+    }
+
+    fn import_account(&mut self, bundle: &AccountBundle) -> Result<String, ValidationError> {
+        self.market.import_account(bundle)
+    }
+
+    fn history(&self, id: &str, since: u64) -> Result<Vec<Fill>, ValidationError> {
+        self.market.get_account(id)?;
+        let fills = match self.fills.get(id) {
+            Some(fills) => fills,
+            None => return Ok(Vec::new()),
+        };
+        let start = fills.partition_point(|fill| fill.sequence <= since);
+        Ok(fills[start..].iter().take(HISTORY_PAGE).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::amm::AmmMarket;
+    use crate::market::Leg;
+    use crate::order_book::OrderBookMarket;
+
+    fn starting_account() -> HashMap<String, i32> {
+        ["foo", "bar"].iter().map(|&widget| (widget.to_string(), 100)).collect()
+    }
+
+    #[test]
+    fn test_ledger() {
+        let mut market = Ledgered::new(AmmMarket::new(starting_account()).with_pool(("foo", 1000), ("bar", 1000), 0));
+        let id = market.create_account().unwrap();
+        let other = market.create_account().unwrap();
+        assert!(market.history(&id, 0).unwrap().is_empty());
+        market.history("fake id", 0).expect_err("shouldn't have been an account!");
+
+        // fills record what was actually paid
+        market.submit_trade(&id, "foo", "bar", 10).unwrap();
+        market.submit_trade(&other, "bar", "foo", 5).unwrap();
+        market.submit_trade(&id, "bar", "foo", 3).unwrap();
+        let fills = market.history(&id, 0).unwrap();
+        assert_eq!(fills.iter().map(|fill| fill.sequence).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!((fills[0].buy.as_str(), fills[0].buy_amount), ("foo", 10));
+        assert_eq!(fills[0].sell_amount, 100 - market.get_account(&id).unwrap()["bar"] + 3);
+        assert_eq!(market.history(&id, 1).unwrap(), fills[1..].to_vec());
+        assert!(market.history(&id, 3).unwrap().is_empty());
+
+        // failed trades and bundles leave nothing behind
+        market.submit_trade(&id, "foo", "baz", 1).expect_err("there is no baz");
+        let legs = vec![
+            Leg { buy: "foo".to_string(), sell: "bar".to_string(), amount: 1 },
+            Leg { buy: "foo".to_string(), sell: "bar".to_string(), amount: 1000 },
+        ];
+        market.submit_bundle(&id, &legs).expect_err("the pool only has 1000 foo");
+        assert_eq!(market.history(&id, 0).unwrap().len(), 2);
+        market.submit_bundle(&id, &legs[..1]).unwrap();
+        assert_eq!(market.history(&id, 3).unwrap()[0].sequence, 4);
+
+        // history comes a page at a time
+        for (buy, sell) in [("foo", "bar"), ("bar", "foo")].iter().cycle().take(HISTORY_PAGE) {
+            market.submit_trade(&other, buy, sell, 1).unwrap();
+        }
+        let page = market.history(&other, 0).unwrap();
+        assert_eq!(page.len(), HISTORY_PAGE);
+        assert_eq!(market.history(&other, page.last().unwrap().sequence).unwrap().len(), 1);
+
+        // and goes away with the account
+        market.remove_account(&id).unwrap();
+        market.history(&id, 0).expect_err("account left");
+
+        // orders matched in a book are recorded for both accounts
+        let mut book = Ledgered::new(OrderBookMarket::new(starting_account()));
+        let (maker, taker) = (book.create_account().unwrap(), book.create_account().unwrap());
+        book.place_order(&maker, "foo", 10, "bar", 20).unwrap();
+        assert!(book.history(&maker, 0).unwrap().is_empty());
+        book.place_order(&taker, "bar", 10, "foo", 5).unwrap();
+        let fills = |book: &Ledgered<OrderBookMarket>, id: &str| -> Vec<_> {
+            book.history(id, 0).unwrap().into_iter().map(|fill| (fill.sequence, fill.buy, fill.buy_amount, fill.sell, fill.sell_amount)).collect()
+        };
+        assert_eq!(fills(&book, &taker), vec![(1, "foo".to_string(), 5, "bar".to_string(), 10)]);
+        assert_eq!(fills(&book, &maker), vec![(1, "bar".to_string(), 10, "foo".to_string(), 5)]);
+
+        // and so are the resting orders a trade takes
+        book.submit_trade(&taker, "foo", "bar", 5).unwrap();
+        assert_eq!(fills(&book, &taker)[1], (2, "foo".to_string(), 5, "bar".to_string(), 10));
+        assert_eq!(fills(&book, &maker)[1], (2, "bar".to_string(), 10, "foo".to_string(), 5));
+    }
+}
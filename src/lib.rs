@@ -1,7 +1,23 @@
+pub mod amm;
+pub mod async_market;
 pub mod client;
+pub mod config;
+pub mod ledger;
 pub mod market;
+pub mod multi_market;
+pub mod order_book;
+pub mod persistence;
+pub mod predicate;
+pub mod recording;
+pub mod settlement;
+pub mod simulation;
 pub mod single_market;
+pub mod testing;
+pub mod threaded_market;
+pub mod transfer;
+pub mod validator;
 
+#[allow(unused_parens, clippy::match_single_binding)]
 pub mod widget_capnp {
     include!(concat!(env!("OUT_DIR"), "/schema/widget_capnp.rs"));
 }
@@ -2,11 +2,12 @@ use std::fs;
 use std::net::ToSocketAddrs;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use clap::{App, Arg};
+use clap::{value_t, App, Arg};
+use futures::StreamExt;
 use log::{error, info};
-use serde_json;
 
-use widget_market::client;
+use widget_market::client::{self, ClientError};
+use widget_market::market::Leg;
 
 pub fn id_arg() -> Arg<'static, 'static> {
     Arg::with_name("id")
@@ -28,6 +29,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .long("account")
                 .takes_value(true)
                 .help("path to an account as a json"))
+            .arg(Arg::with_name("bundle")
+                .long("bundle")
+                .takes_value(true)
+                .conflicts_with("account")
+                .help("path to a bundle from leaving another market"))
             .after_help("requests to join a market server, returning an account id"))
         .subcommand(App::new("check")
             .about("checks an account's market view")
@@ -36,23 +42,84 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .subcommand(App::new("trade")
             .arg(Arg::with_name("buy").required(true))
             .arg(Arg::with_name("sell").required(true))
+            .arg(Arg::with_name("amount").default_value("1"))
             .about("requests a widget trade")
-            .after_help("requests a trade be made, returning if it was accepted")
+            .after_help("requests that some amount of a widget be bought, returning if the whole amount was traded")
+            .arg(id_arg()))
+        .subcommand(App::new("bundle")
+            .arg(Arg::with_name("legs")
+                .required(true)
+                .help("path to a json list of trades as [buy, sell, amount]"))
+            .about("requests several widget trades at once")
+            .after_help("requests that every trade in a bundle be made, or none of them")
+            .arg(id_arg()))
+        .subcommand(App::new("quote")
+            .arg(Arg::with_name("buy").required(true))
+            .arg(Arg::with_name("sell").required(true))
+            .arg(Arg::with_name("amount").default_value("1"))
+            .about("prices a widget trade")
+            .after_help("asks what buying some amount of a widget would cost, without trading")
             .arg(id_arg()))
         .subcommand(App::new("leave")
             .arg(Arg::with_name("output")
                 .long("output")
                 .takes_value(true)
                 .help("path to write the account data"))
+            .arg(Arg::with_name("destination")
+                .long("destination")
+                .takes_value(true)
+                .help("market to move the account to; writes a signed bundle instead of the account"))
             .about("leaves a market")
             .after_help("leaves a market, returning a score")
             .arg(id_arg()))
+        .subcommand(App::new("place")
+            .arg(Arg::with_name("sell").required(true))
+            .arg(Arg::with_name("sell_amount").required(true))
+            .arg(Arg::with_name("buy").required(true))
+            .arg(Arg::with_name("buy_amount").required(true))
+            .about("places a limit order")
+            .after_help("places an order to sell widgets for at least some amount of another widget, returning the order id")
+            .arg(id_arg()))
+        .subcommand(App::new("cancel")
+            .arg(Arg::with_name("order").required(true))
+            .about("cancels a resting order")
+            .after_help("cancels a resting order, returning what was left of it to the account")
+            .arg(id_arg()))
+        .subcommand(App::new("orders")
+            .about("lists resting orders")
+            .after_help("lists every order resting in the market's book"))
+        .subcommand(App::new("history")
+            .arg(Arg::with_name("since")
+                .long("since")
+                .takes_value(true)
+                .default_value("0")
+                .help("only list trades numbered after this"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["table", "json"])
+                .default_value("table")
+                .help("how to print the trades"))
+            .about("lists an account's trades")
+            .after_help("lists every trade an account made, oldest first")
+            .arg(id_arg()))
+        .subcommand(App::new("watch")
+            .about("watches trades as they happen")
+            .after_help("prints every trade made in the market and how it moved the market, until interrupted"))
+        .subcommand(App::new("markets")
+            .about("lists hosted markets")
+            .after_help("lists the names of the markets hosted by an exchange server"))
         .arg(Arg::with_name("address")
             .short("a")
             .long("address")
             .takes_value(true)
             .required(true)
             .help("address of the server"))
+        .arg(Arg::with_name("market")
+            .short("m")
+            .long("market")
+            .takes_value(true)
+            .help("name of the market to use on an exchange server"))
         .get_matches();
 
         let addr = args
@@ -61,57 +128,168 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .to_socket_addrs()?
             .next()
             .expect("could not parse address");
+        let market = args.value_of("market").map(str::to_string);
         let (command, args) = args.subcommand();
         let args = args.unwrap();
 
         env_logger::builder().filter(None, log::LevelFilter::Info).init();
-        tokio::task::LocalSet::new()
+        let result: Result<(), ClientError> = tokio::task::LocalSet::new()
             .run_until(async move {
+                // listing markets talks to the exchange itself
+                if command == "markets" {
+                    for name in client::ExchangeClient::new(&addr).await?.list_markets().await? {
+                        println!("{}", name);
+                    }
+                    return Ok(());
+                }
+
                 // create the rpc client
-                let service = client::WidgetMarketClient::new(&addr).await.unwrap();
+                let service = match &market {
+                    Some(name) => client::ExchangeClient::new(&addr).await?.get_market(name).await?,
+                    None => client::WidgetMarketClient::new(&addr).await?,
+                };
 
                 // parse the command
                 match command {
                     "join" => {
-                        let id = match args.value_of("account") {
-                            Some(path) => service.join_with_account(
-                                serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()).await,
-                            _ => service.join().await,
+                        let id = match (args.value_of("account"), args.value_of("bundle")) {
+                            (Some(path), _) => service.join_with_account(
+                                serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()).await?,
+                            (_, Some(path)) => service.join_with_bundle(
+                                &serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()).await?,
+                            _ => service.join().await?,
                         };
                         info!("joined market at {} with id {}", addr, id);
                         println!("{}", id);
                     }
                     "check" => {
                         let id = args.value_of("id").expect("no id was provided");
-                        let snapshot = service.check(id).await;
-                        info!("market: {:?}", snapshot.1);
-                        info!("{} account: {:?}", id, snapshot.0);
+                        let snapshot = service.check(id).await?;
+                        info!("market: {:?}", snapshot.market);
+                        info!("{} account: {:?}", id, snapshot.account);
+                        snapshot.quotes.iter().for_each(|quote| {
+                            info!("{} {} costs {} {}", quote.buy_amount, quote.buy, quote.sell_amount, quote.sell);
+                        });
                     }
                     "trade" => {
                         let id = args.value_of("id").expect("no id was provided");
                         let buy = args.value_of("buy").unwrap();
                         let sell = args.value_of("sell").unwrap();
-                        let result = service.trade(id, buy, sell).await;
-                        info!("{} proposed {} -> {}", id, buy, sell);
-                        info!("{}", match result {Ok(_) => "submitted".to_string(), Err(e) => e});
+                        let amount = value_t!(args, "amount", i32).unwrap_or_else(|e| e.exit());
+                        info!("{} proposed buying {} {} with {}", id, amount, buy, sell);
+                        service.trade(id, buy, sell, amount).await?;
+                        info!("submitted");
+                    }
+                    "bundle" => {
+                        let id = args.value_of("id").expect("no id was provided");
+                        let legs: Vec<(String, String, i32)> = serde_json::from_str(
+                            &fs::read_to_string(args.value_of("legs").unwrap()).unwrap()).unwrap();
+                        let legs: Vec<Leg> = legs
+                            .into_iter()
+                            .map(|(buy, sell, amount)| Leg { buy, sell, amount })
+                            .collect();
+                        info!("{} proposed a bundle of {} trades", id, legs.len());
+                        service.submit_bundle(id, &legs).await?;
+                        info!("submitted");
+                    }
+                    "quote" => {
+                        let id = args.value_of("id").expect("no id was provided");
+                        let buy = args.value_of("buy").unwrap();
+                        let sell = args.value_of("sell").unwrap();
+                        let amount = value_t!(args, "amount", i32).unwrap_or_else(|e| e.exit());
+                        let quote = service.quote(id, buy, sell, amount).await?;
+                        info!("{} would pay {} {} and receive {} {}", id, quote.sell_amount, quote.sell, quote.buy_amount, quote.buy);
+                        println!("{} {}", quote.sell_amount, quote.buy_amount);
                     }
                     "leave" => {
                         let id = args.value_of("id").expect("no id was provided");
-                        let account = service.leave(id).await;
+                        let output = match args.value_of("destination") {
+                            Some(destination) => serde_json::to_string(&service.leave_for(id, destination).await?).unwrap(),
+                            _ => serde_json::to_string(&service.leave(id).await?).unwrap(),
+                        };
                         let path = match args.value_of("output") {
                             Some(path) => path.to_string(),
                             _ => format!("{}_{}.json", id, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()),
                         };
                         info!("writing account details to {}", path);
-                        if let Err(error) = fs::write(path, serde_json::to_string(&account).unwrap()) {
+                        if let Err(error) = fs::write(path, output) {
                             error!("an error occurred while writing the account: {}", error);
                         }
                     }
+                    "place" => {
+                        let id = args.value_of("id").expect("no id was provided");
+                        let sell = args.value_of("sell").unwrap();
+                        let sell_amount = value_t!(args, "sell_amount", i32).unwrap_or_else(|e| e.exit());
+                        let buy = args.value_of("buy").unwrap();
+                        let buy_amount = value_t!(args, "buy_amount", i32).unwrap_or_else(|e| e.exit());
+                        info!("{} proposed {} {} -> {} {}", id, sell_amount, sell, buy_amount, buy);
+                        let order = service.place_order(id, sell, sell_amount, buy, buy_amount).await?;
+                        info!("order {} has {} {} remaining", order.id, order.remaining, order.sell);
+                        println!("{}", order.id);
+                    }
+                    "cancel" => {
+                        let id = args.value_of("id").expect("no id was provided");
+                        let order = value_t!(args, "order", u64).unwrap_or_else(|e| e.exit());
+                        let order = service.cancel_order(id, order).await?;
+                        info!("cancelled order {}, returning {} {}", order.id, order.remaining, order.sell);
+                    }
+                    "orders" => {
+                        for order in service.list_orders().await? {
+                            info!(
+                                "order {}: {}/{} {} for {} {}",
+                                order.id, order.remaining, order.sell_amount, order.sell, order.buy_amount, order.buy);
+                        }
+                    }
+                    "history" => {
+                        let id = args.value_of("id").expect("no id was provided");
+                        let mut since = value_t!(args, "since", u64).unwrap_or_else(|e| e.exit());
+                        // the market hands back a page at a time
+                        let mut fills = Vec::new();
+                        loop {
+                            let page = service.history(id, since).await?;
+                            match page.last() {
+                                Some(fill) => since = fill.sequence,
+                                None => break,
+                            }
+                            fills.extend(page);
+                        }
+                        info!("{} made {} trades", id, fills.len());
+                        if args.value_of("format") == Some("json") {
+                            println!("{}", serde_json::to_string(&fills).unwrap());
+                        } else {
+                            println!("{:>8} {:>13} {:>8} {:>10} {:>8} {:>10}", "sequence", "time", "bought", "widget", "sold", "widget");
+                            for fill in fills {
+                                println!(
+                                    "{:>8} {:>13} {:>8} {:>10} {:>8} {:>10}",
+                                    fill.sequence, fill.timestamp, fill.buy_amount, fill.buy, fill.sell_amount, fill.sell);
+                            }
+                        }
+                    }
+                    "watch" => {
+                        let mut trades = service.subscribe().await?;
+                        info!("watching market at {}", addr);
+                        while let Some(event) = trades.next().await {
+                            for leg in &event.legs {
+                                info!("{} {} bought with {}", leg.amount, leg.buy, leg.sell);
+                            }
+                            let mut changes: Vec<_> = event.changes.iter().collect();
+                            changes.sort();
+                            info!("market moved by {:?}", changes);
+                            println!("{}", serde_json::to_string(&event).unwrap());
+                        }
+                        info!("market at {} closed the connection", addr);
+                    }
                     // throw here
                     _ => (),
                 };
 
                 Ok(())
             })
-            .await
+            .await;
+
+        if let Err(error) = result {
+            error!("{}", error);
+            std::process::exit(1);
+        }
+        Ok(())
 }
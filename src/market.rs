@@ -1,20 +1,279 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
 
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+
+use crate::predicate::Predicate;
+use crate::transfer::AccountBundle;
+
+// machine-readable reasons for a validation failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    Other,
+    UnknownAccount,
+    DuplicateAccount,
+    UnknownWidget,
+    IdenticalWidgets,
+    InsufficientWidgets,
+    InvalidAmount,
+    UnknownOrder,
+    Unsupported,
+    PredicateFailed,
+    UnknownMarket,
+    InvalidBundle,
+    TradeLimitExceeded,
+    PositionLimitExceeded,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ValidationError {
-    AccountError(String),
-    MarketError(String),
-    TradeError(String),
+    AccountError(ErrorCode, String),
+    MarketError(ErrorCode, String),
+    TradeError(ErrorCode, String),
+}
+
+impl ValidationError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ValidationError::AccountError(code, _)
+            | ValidationError::MarketError(code, _)
+            | ValidationError::TradeError(code, _) => *code,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ValidationError::AccountError(_, message)
+            | ValidationError::MarketError(_, message)
+            | ValidationError::TradeError(_, message) => message,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+// a limit order to sell some widgets for at least some amount of another widget
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Order {
+    pub id: u64,
+    pub sell: String,
+    pub sell_amount: i32,
+    pub buy: String,
+    pub buy_amount: i32,
+    // how much of the sold widget is still waiting to be filled
+    pub remaining: i32,
+}
+
+// paying sell_amount of sell gets buy_amount of buy
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Quote {
+    pub buy: String,
+    pub buy_amount: i32,
+    pub sell: String,
+    pub sell_amount: i32,
+}
+
+// one trade in a bundle; buys amount of buy with sell
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Leg {
+    pub buy: String,
+    pub sell: String,
+    pub amount: i32,
+}
+
+// a trade an account made, as kept in a market's ledger
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Fill {
+    // numbers every trade in the market in the order they were made, starting at 1
+    pub sequence: u64,
+    // milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub buy: String,
+    pub buy_amount: i32,
+    pub sell: String,
+    pub sell_amount: i32,
+}
+
+// what one account bought and sold in a single call, as a market reports it to a ledger
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Execution {
+    pub account: String,
+    pub buy: String,
+    pub buy_amount: i32,
+    pub sell: String,
+    pub sell_amount: i32,
+}
+
+// a trade made in the market, as told to anyone subscribed to it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TradeEvent {
+    // a single trade has one leg; a bundle has all of its legs
+    pub legs: Vec<Leg>,
+    // how much each of the market's widget counts moved; unchanged widgets are left out
+    pub changes: HashMap<String, i32>,
+}
+
+// how much each widget count moved from before to after; unchanged widgets are left out
+pub fn changes(before: &HashMap<String, i32>, after: &HashMap<String, i32>) -> HashMap<String, i32> {
+    before
+        .keys()
+        .chain(after.keys())
+        .map(|widget| (widget.clone(), after.get(widget).unwrap_or(&0) - before.get(widget).unwrap_or(&0)))
+        .filter(|(_, change)| *change != 0)
+        .collect()
+}
+
+// makes a call on the market and returns how far it moved the market's widget counts
+pub(crate) fn traced<M: Market>(market: &mut M, call: impl FnOnce(&mut M) -> Result<(), ValidationError>) -> Result<HashMap<String, i32>, ValidationError> {
+    let before = market.get_market()?.clone();
+    call(market)?;
+    Ok(changes(&before, market.get_market()?))
+}
+
+// the market as seen by an account
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub account: HashMap<String, i32>,
+    pub market: HashMap<String, i32>,
+    pub quotes: Vec<Quote>,
+}
+
+pub(crate) fn unsupported(operation: &str) -> ValidationError {
+    ValidationError::MarketError(ErrorCode::Unsupported, format!("market does not support {}", operation))
+}
+
+// scales the market's quote for the pair up to amount in whole quotes
+pub(crate) fn scale_quote(quotes: Vec<Quote>, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+    if amount <= 0 {
+        return Err(ValidationError::TradeError(ErrorCode::InvalidAmount, "quote amount must be positive".to_string()));
+    }
+    let quote = quotes
+        .into_iter()
+        .find(|quote| quote.buy == buy && quote.sell == sell && quote.buy_amount > 0)
+        .ok_or_else(|| ValidationError::MarketError(ErrorCode::Unsupported, format!("market has no quote for {} in {}", buy, sell)))?;
+    // worked out in i64 so a huge amount is rejected instead of overflowing
+    let quotes = (amount as i64 + quote.buy_amount as i64 - 1) / quote.buy_amount as i64;
+    let scale = |widgets: i32, widget: &str| {
+        i32::try_from(widgets as i64 * quotes)
+            .map_err(|_| ValidationError::TradeError(ErrorCode::InvalidAmount, format!("quote for {} {} has too much {}", amount, buy, widget)))
+    };
+    Ok(Quote {
+        buy_amount: scale(quote.buy_amount, buy)?,
+        sell_amount: scale(quote.sell_amount, sell)?,
+        ..quote
+    })
+}
+
+// rejects an account joining with a negative count of any widget; every market checks
+// the accounts it's given with this before adding them
+pub fn check_counts(account: &HashMap<String, i32>) -> Result<(), ValidationError> {
+    match account.iter().find(|(_, &count)| count < 0) {
+        Some((widget, count)) => Err(ValidationError::AccountError(ErrorCode::InvalidAmount, format!("can't join with {} {}", count, widget))),
+        None => Ok(()),
+    }
 }
 
 pub trait Market {
     // market viewing
     fn get_market(&self) -> Result<&HashMap<String, i32>, ValidationError>;
     fn get_account(&self, id: &str) -> Result<&HashMap<String, i32>, ValidationError>;
+    // ids of every open account, sorted. markets that can't list their accounts reject it
+    fn get_accounts(&self) -> Result<Vec<String>, ValidationError> {
+        Err(unsupported("account listing"))
+    }
+    // current prices, for markets where they move
+    fn get_quotes(&self) -> Result<Vec<Quote>, ValidationError> {
+        Ok(Vec::new())
+    }
     // account modification
     fn create_account(&mut self) -> Result<String, ValidationError>;
     fn add_account(&mut self, account: HashMap<String, i32>) -> Result<String, ValidationError>;
     fn remove_account(&mut self, id: &str) -> Result<HashMap<String, i32>, ValidationError>;
-    // market modification
-    fn submit_trade(&mut self, id: &str, buy: &str, sell: &str) -> Result<(), ValidationError>;
+    // what buying some amount of a widget would cost right now. by default this
+    // scales the market's quote for the pair in whole quotes
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+        self.get_account(id)?;
+        scale_quote(self.get_quotes()?, buy, sell, amount)
+    }
+    // market modification; a trade buys amount of buy with sell, and either the whole
+    // amount is traded or nothing changes
+    fn submit_trade(&mut self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ValidationError>;
+    // makes the trade only if the condition holds for the account right before it
+    fn submit_trade_if(&mut self, id: &str, buy: &str, sell: &str, amount: i32, condition: &Predicate) -> Result<(), ValidationError> {
+        if !condition.evaluate(self, id)? {
+            return Err(ValidationError::TradeError(ErrorCode::PredicateFailed, "trade condition does not hold".to_string()));
+        }
+        self.submit_trade(id, buy, sell, amount)
+    }
+    // makes every trade in the bundle in order, or none of them. by default the
+    // market is copied first and put back if any leg fails
+    fn submit_bundle(&mut self, id: &str, legs: &[Leg]) -> Result<(), ValidationError>
+    where
+        Self: Sized + Clone,
+    {
+        self.get_account(id)?;
+        if legs.is_empty() {
+            return Err(ValidationError::TradeError(ErrorCode::InvalidAmount, "bundle has no legs".to_string()));
+        }
+        let before = self.clone();
+        for leg in legs {
+            if let Err(error) = self.submit_trade(id, &leg.buy, &leg.sell, leg.amount) {
+                *self = before;
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+    // what each account bought and sold in the last trade or order, so a ledger can record
+    // both sides of a match. markets that don't say have their trades read off the account
+    fn last_executions(&self) -> Vec<Execution> {
+        Vec::new()
+    }
+    // order book operations; markets without a book reject them
+    fn place_order(&mut self, _id: &str, _sell: &str, _sell_amount: i32, _buy: &str, _buy_amount: i32) -> Result<Order, ValidationError> {
+        Err(unsupported("orders"))
+    }
+    fn cancel_order(&mut self, _id: &str, _order: u64) -> Result<Order, ValidationError> {
+        Err(unsupported("orders"))
+    }
+    fn get_orders(&self) -> Result<Vec<Order>, ValidationError> {
+        Err(unsupported("orders"))
+    }
+    // moving accounts between markets; exporting removes the account and signs it for
+    // the destination. markets that can't sign bundles reject both and leave the account alone
+    fn export_account(&mut self, _id: &str, _destination: &str) -> Result<AccountBundle, ValidationError> {
+        Err(unsupported("transfers"))
+    }
+    fn import_account(&mut self, _bundle: &AccountBundle) -> Result<String, ValidationError> {
+        Err(unsupported("transfers"))
+    }
+    // the account's trades with a sequence number after since, oldest first and at most
+    // a page of them. markets that don't keep a ledger reject it
+    fn history(&self, _id: &str, _since: u64) -> Result<Vec<Fill>, ValidationError> {
+        Err(unsupported("history"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scale_quote() {
+        let quotes = vec![Quote { buy: "foo".to_string(), buy_amount: 2, sell: "bar".to_string(), sell_amount: 3 }];
+        let quote = scale_quote(quotes.clone(), "foo", "bar", 5).unwrap();
+        assert_eq!((quote.buy_amount, quote.sell_amount), (6, 9));
+        assert_eq!(scale_quote(quotes.clone(), "bar", "foo", 5).unwrap_err().code(), ErrorCode::Unsupported);
+        assert_eq!(scale_quote(quotes.clone(), "foo", "bar", 0).unwrap_err().code(), ErrorCode::InvalidAmount);
+
+        // amounts too big to quote are rejected rather than overflowing
+        assert_eq!(scale_quote(quotes, "foo", "bar", i32::MAX).unwrap_err().code(), ErrorCode::InvalidAmount);
+    }
 }
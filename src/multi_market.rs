@@ -0,0 +1,62 @@
+// a server that hosts several named markets on one port behind an exchange
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use log::{error, info};
+
+use crate::market::{ErrorCode, Market, ValidationError};
+use crate::single_market::{self, MarketServer};
+use crate::widget_capnp;
+
+// the registry of hosted markets; each one is served exactly like a single market
+#[derive(Default)]
+pub struct ExchangeServer {
+    markets: BTreeMap<String, widget_capnp::market::Client>,
+}
+
+impl ExchangeServer {
+    pub fn new() -> ExchangeServer {
+        ExchangeServer::default()
+    }
+
+    // hosts a market under a name
+    pub fn with_market<M: 'static + Market + Clone>(mut self, name: &str, market: M) -> ExchangeServer {
+        assert!(!self.markets.contains_key(name), "there is already a market named {}", name);
+        self.markets.insert(name.to_string(), capnp_rpc::new_client(MarketServer::new(market)));
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.markets.keys()
+    }
+}
+
+impl widget_capnp::exchange::Server for ExchangeServer {
+    fn list_markets(&mut self, _: widget_capnp::exchange::ListMarketsParams, mut results: widget_capnp::exchange::ListMarketsResults) -> Promise<(), capnp::Error> {
+        info!("market list requested");
+        let mut builder = results.get().init_names(self.markets.len() as u32);
+        self.markets.keys().enumerate().for_each(|(i, name)| builder.set(i as u32, name));
+        Promise::ok(())
+    }
+
+    fn get_market(&mut self, params: widget_capnp::exchange::GetMarketParams, mut results: widget_capnp::exchange::GetMarketResults) -> Promise<(), capnp::Error> {
+        let name = pry!(pry!(params.get()).get_name());
+        info!("market {} requested", name);
+        match self.markets.get(name) {
+            Some(market) => results.get().set_market(market.clone()),
+            None => {
+                let error = ValidationError::MarketError(ErrorCode::UnknownMarket, format!("there is no market named {}", name));
+                error!("{:?}", error);
+                single_market::set_error(&error, results.get().init_error());
+            }
+        }
+        Promise::ok(())
+    }
+}
+
+pub async fn run(addr: SocketAddr, exchange: ExchangeServer) -> Result<(), Box<dyn std::error::Error>> {
+    let exchange_client: widget_capnp::exchange::Client = capnp_rpc::new_client(exchange);
+    single_market::serve(addr, exchange_client.client).await
+}
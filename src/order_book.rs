@@ -0,0 +1,457 @@
+// a market where accounts trade with each other through limit orders
+//
+// the market has the following properties:
+//  - new accounts are given a copy of the starting account
+//  - added accounts start with their provided widgets, ignoring unknown widgets
+//  - placing an order escrows the widgets being sold until it fills or is cancelled
+//  - orders match against resting orders with price-time priority at the resting order's price
+//  - whatever part of an order doesn't match rests in the book
+//  - the market reports the total of each widget resting in the book
+//  - quotes walk the book from the best ask until the amount is covered
+//  - a trade buys from the best asks and must fill completely
+//  - accounts that leave have their resting orders cancelled first
+//  - trades and orders report what every account on either side of them bought and sold
+//  - trades, orders and cancellations that would take a count past what fits are rejected
+//    before anything changes
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::market::{check_counts, ErrorCode, Execution, Market, Order, Quote, ValidationError};
+
+// an order in the book along with the account that placed it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RestingOrder {
+    account: String,
+    order: Order,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderBookMarket {
+    starting_account: HashMap<String, i32>,
+    market: HashMap<String, i32>,
+    accounts: HashMap<String, HashMap<String, i32>>,
+    // resting orders in the order they were placed
+    book: Vec<RestingOrder>,
+    next_account: u64,
+    next_order: u64,
+    // what each account traded in the last trade or order
+    #[serde(skip)]
+    executions: Vec<Execution>,
+}
+
+// orders the asks of two orders selling the same widget; a lower ask is better
+fn compare_asks(first: &Order, second: &Order) -> Ordering {
+    (first.buy_amount as i64 * second.sell_amount as i64).cmp(&(second.buy_amount as i64 * first.sell_amount as i64))
+}
+
+// whether the incoming order is willing to pay the resting order's ask
+fn crosses(incoming: &Order, resting: &Order) -> bool {
+    incoming.sell_amount as i64 * resting.sell_amount as i64 >= resting.buy_amount as i64 * incoming.buy_amount as i64
+}
+
+// a fill against a resting order, as (book index, bought, cost)
+type BookFill = (usize, i32, i32);
+
+// rejects a count that doesn't fit in the market's counts
+fn fits(count: i64, widget: &str) -> Result<i32, ValidationError> {
+    i32::try_from(count).map_err(|_| ValidationError::TradeError(ErrorCode::InvalidAmount, format!("{} {} is more than can be counted", count, widget)))
+}
+
+impl OrderBookMarket {
+    // creates an empty book where every new account starts with the given widgets
+    pub fn new(starting_account: HashMap<String, i32>) -> OrderBookMarket {
+        OrderBookMarket {
+            market: starting_account.keys().map(|widget| (widget.to_owned(), 0)).collect(),
+            starting_account,
+            accounts: HashMap::new(),
+            book: Vec::new(),
+            next_account: 0,
+            next_order: 0,
+            executions: Vec::new(),
+        }
+    }
+
+    fn has_account(&self, id: &str) -> Result<(), ValidationError> {
+        if self.accounts.contains_key(id) {
+            Ok(())
+        } else {
+            Err(ValidationError::AccountError(ErrorCode::UnknownAccount, format!("account {} does not exist", id)))
+        }
+    }
+
+    fn has_widget(&self, widget: &str) -> Result<(), ValidationError> {
+        if self.market.contains_key(widget) {
+            Ok(())
+        } else {
+            Err(ValidationError::TradeError(ErrorCode::UnknownWidget, format!("{} is not in market", widget)))
+        }
+    }
+
+    // makes sure every change a call would make to the accounts fits in their counts,
+    // before any of them is made. changes are added up in order, so debits go first
+    fn check_changes(&self, changes: &[(&str, &str, i32)]) -> Result<(), ValidationError> {
+        let mut totals: HashMap<(&str, &str), i64> = HashMap::new();
+        for &(id, widget, amount) in changes {
+            let total = totals
+                .entry((id, widget))
+                .or_insert_with(|| self.accounts.get(id).and_then(|account| account.get(widget)).copied().unwrap_or(0) as i64);
+            *total += amount as i64;
+            fits(*total, widget)?;
+        }
+        Ok(())
+    }
+
+    // deposits are only made once check_changes has passed them
+    fn deposit(&mut self, id: &str, widget: &str, amount: i32) {
+        if let Some(account) = self.accounts.get_mut(id) {
+            *account.entry(widget.to_string()).or_insert(0) += amount;
+        }
+    }
+
+    // adds to what the account traded in this call, one execution for each way it traded
+    fn execute(&mut self, id: &str, buy: &str, buy_amount: i32, sell: &str, sell_amount: i32) {
+        match self.executions.iter_mut().find(|execution| execution.account == id && execution.buy == buy && execution.sell == sell) {
+            Some(execution) => {
+                execution.buy_amount += buy_amount;
+                execution.sell_amount += sell_amount;
+            }
+            None => self.executions.push(Execution {
+                account: id.to_string(),
+                buy: buy.to_string(),
+                buy_amount,
+                sell: sell.to_string(),
+                sell_amount,
+            }),
+        }
+    }
+
+    fn validate_trade(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ValidationError> {
+        self.has_account(id)?;
+        self.has_widget(buy)?;
+        self.has_widget(sell)?;
+        if buy == sell {
+            Err(ValidationError::TradeError(ErrorCode::IdenticalWidgets, format!("both widgets are {}", buy)))
+        } else if amount <= 0 {
+            Err(ValidationError::TradeError(ErrorCode::InvalidAmount, "trade amount must be positive".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    // the fills needed to buy amount of buy from the best asks, and what they cost altogether
+    fn take_asks(&self, buy: &str, sell: &str, amount: i32) -> Result<(Vec<BookFill>, i32), ValidationError> {
+        let mut asks: Vec<usize> = self.book
+            .iter()
+            .enumerate()
+            .filter(|(_, resting)| resting.order.sell == buy && resting.order.buy == sell)
+            .map(|(i, _)| i)
+            .collect();
+        asks.sort_by(|&first, &second| compare_asks(&self.book[first].order, &self.book[second].order));
+
+        let mut needed = amount;
+        let mut fills = Vec::new();
+        let mut total: i64 = 0;
+        for i in asks {
+            let ask = &self.book[i].order;
+            let bought = needed.min(ask.remaining);
+            let cost = (bought as i64 * ask.buy_amount as i64 + ask.sell_amount as i64 - 1) / ask.sell_amount as i64;
+            fills.push((i, bought, fits(cost, sell)?));
+            total += cost;
+            needed -= bought;
+            if needed == 0 {
+                return Ok((fills, fits(total, sell)?));
+            }
+        }
+        Err(ValidationError::TradeError(ErrorCode::InsufficientWidgets, format!("not enough {} in book", buy)))
+    }
+
+    // the fills the incoming order would get from the book, best ask first and oldest first
+    // among equal asks. costs never add up to more than the order is selling
+    fn match_order(&self, incoming: &Order) -> Vec<BookFill> {
+        let mut candidates: Vec<usize> = self.book
+            .iter()
+            .enumerate()
+            .filter(|(_, resting)| resting.order.sell == incoming.buy && resting.order.buy == incoming.sell)
+            .filter(|(_, resting)| crosses(incoming, &resting.order))
+            .map(|(i, _)| i)
+            .collect();
+        // the sort is stable, so time priority is kept between equal asks
+        candidates.sort_by(|&first, &second| compare_asks(&self.book[first].order, &self.book[second].order));
+
+        let mut remaining = incoming.remaining as i64;
+        let mut fills = Vec::new();
+        for i in candidates {
+            let resting = &self.book[i].order;
+            // how much of the resting order we can afford, and what it costs at the resting ask
+            let bought = (resting.remaining as i64).min(remaining * resting.sell_amount as i64 / resting.buy_amount as i64);
+            if bought == 0 {
+                break;
+            }
+            let cost = (bought * resting.buy_amount as i64 + resting.sell_amount as i64 - 1) / resting.sell_amount as i64;
+            // rounding in the resting order's favor can push the price past our limit
+            if bought * (incoming.sell_amount as i64) < cost * (incoming.buy_amount as i64) {
+                break;
+            }
+            fills.push((i, bought as i32, cost as i32));
+            remaining -= cost;
+            if remaining == 0 {
+                break;
+            }
+        }
+        fills
+    }
+
+    // makes the fills for the incoming order
+    fn fill_order(&mut self, id: &str, incoming: &mut Order, fills: Vec<BookFill>) {
+        for (i, bought, cost) in fills {
+            incoming.remaining -= cost;
+            self.book[i].order.remaining -= bought;
+            self.market.entry(incoming.buy.clone()).and_modify(|widgets| *widgets -= bought);
+            let maker = self.book[i].account.clone();
+            self.deposit(id, &incoming.buy, bought);
+            self.deposit(&maker, &incoming.sell, cost);
+            self.execute(id, &incoming.buy, bought, &incoming.sell, cost);
+            self.execute(&maker, &incoming.sell, cost, &incoming.buy, bought);
+        }
+        self.book.retain(|resting| resting.order.remaining > 0);
+    }
+}
+
+impl Market for OrderBookMarket {
+    fn get_market(&self) -> Result<&HashMap<String, i32>, ValidationError> {
+        Ok(&self.market)
+    }
+
+    fn get_account(&self, id: &str) -> Result<&HashMap<String, i32>, ValidationError> {
+        self.has_account(id)?;
+        Ok(&self.accounts[id])
+    }
+
+    fn get_accounts(&self) -> Result<Vec<String>, ValidationError> {
+        let mut ids: Vec<String> = self.accounts.keys().cloned().collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+        self.validate_trade(id, buy, sell, amount)?;
+        let (_, cost) = self.take_asks(buy, sell, amount)?;
+        Ok(Quote { buy: buy.to_string(), buy_amount: amount, sell: sell.to_string(), sell_amount: cost })
+    }
+
+    fn create_account(&mut self) -> Result<String, ValidationError> {
+        self.add_account(self.starting_account.clone())
+    }
+
+    fn add_account(&mut self, account: HashMap<String, i32>) -> Result<String, ValidationError> {
+        check_counts(&account)?;
+        let id = format!("account-{}", self.next_account);
+        self.next_account += 1;
+        let mut account: HashMap<String, i32> = account
+            .into_iter()
+            .filter(|(widget, _)| self.market.contains_key(widget))
+            .collect();
+        self.market.keys().for_each(|widget| {
+            account.entry(widget.to_string()).or_insert(0);
+        });
+        self.accounts.insert(id.clone(), account);
+        Ok(id)
+    }
+
+    fn remove_account(&mut self, id: &str) -> Result<HashMap<String, i32>, ValidationError> {
+        self.has_account(id)?;
+        let orders: Vec<u64> = self.book
+            .iter()
+            .filter(|resting| resting.account == id)
+            .map(|resting| resting.order.id)
+            .collect();
+        for order in orders {
+            self.cancel_order(id, order)?;
+        }
+        Ok(self.accounts.remove(id).unwrap())
+    }
+
+    fn submit_trade(&mut self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ValidationError> {
+        self.executions.clear();
+        self.validate_trade(id, buy, sell, amount)?;
+        let (fills, cost) = self.take_asks(buy, sell, amount)?;
+        if self.accounts[id][sell] < cost {
+            return Err(ValidationError::TradeError(ErrorCode::InsufficientWidgets, format!("not enough {} in account {}", sell, id)));
+        }
+        let mut changes = vec![(id, sell, -cost), (id, buy, amount)];
+        changes.extend(fills.iter().map(|&(i, _, cost)| (self.book[i].account.as_str(), sell, cost)));
+        self.check_changes(&changes)?;
+
+        for (i, bought, cost) in fills {
+            self.book[i].order.remaining -= bought;
+            self.market.entry(buy.to_string()).and_modify(|widgets| *widgets -= bought);
+            let maker = self.book[i].account.clone();
+            self.deposit(&maker, sell, cost);
+            self.execute(&maker, sell, cost, buy, bought);
+        }
+        self.deposit(id, buy, amount);
+        self.deposit(id, sell, -cost);
+        self.execute(id, buy, amount, sell, cost);
+        self.book.retain(|resting| resting.order.remaining > 0);
+        Ok(())
+    }
+
+    fn place_order(&mut self, id: &str, sell: &str, sell_amount: i32, buy: &str, buy_amount: i32) -> Result<Order, ValidationError> {
+        self.executions.clear();
+        self.has_account(id)?;
+        self.has_widget(buy)?;
+        self.has_widget(sell)?;
+        if buy == sell {
+            return Err(ValidationError::TradeError(ErrorCode::IdenticalWidgets, format!("both widgets are {}", buy)));
+        }
+        if sell_amount <= 0 || buy_amount <= 0 {
+            return Err(ValidationError::TradeError(ErrorCode::InvalidAmount, "order amounts must be positive".to_string()));
+        }
+        if self.accounts[id][sell] < sell_amount {
+            return Err(ValidationError::TradeError(ErrorCode::InsufficientWidgets, format!("not enough {} in account {}", sell, id)));
+        }
+
+        let mut order = Order {
+            id: self.next_order,
+            sell: sell.to_string(),
+            sell_amount,
+            buy: buy.to_string(),
+            buy_amount,
+            remaining: sell_amount,
+        };
+        let fills = self.match_order(&order);
+        let mut changes = vec![(id, sell, -sell_amount)];
+        changes.extend(fills.iter().map(|&(_, bought, _)| (id, buy, bought)));
+        changes.extend(fills.iter().map(|&(i, _, cost)| (self.book[i].account.as_str(), sell, cost)));
+        self.check_changes(&changes)?;
+        let left = sell_amount - fills.iter().map(|&(_, _, cost)| cost).sum::<i32>();
+        fits(self.market[sell] as i64 + left as i64, sell)?;
+
+        self.next_order += 1;
+        self.deposit(id, sell, -sell_amount);
+        self.fill_order(id, &mut order, fills);
+        if order.remaining > 0 {
+            self.market.entry(sell.to_string()).and_modify(|widgets| *widgets += order.remaining);
+            self.book.push(RestingOrder { account: id.to_string(), order: order.clone() });
+        }
+        Ok(order)
+    }
+
+    fn cancel_order(&mut self, id: &str, order: u64) -> Result<Order, ValidationError> {
+        self.has_account(id)?;
+        match self.book.iter().position(|resting| resting.order.id == order && resting.account == id) {
+            Some(i) => {
+                self.check_changes(&[(id, &self.book[i].order.sell, self.book[i].order.remaining)])?;
+                let resting = self.book.remove(i);
+                self.market.entry(resting.order.sell.clone()).and_modify(|widgets| *widgets -= resting.order.remaining);
+                self.deposit(id, &resting.order.sell, resting.order.remaining);
+                Ok(resting.order)
+            }
+            None => Err(ValidationError::TradeError(ErrorCode::UnknownOrder, format!("account {} has no order {}", id, order))),
+        }
+    }
+
+    fn get_orders(&self) -> Result<Vec<Order>, ValidationError> {
+        Ok(self.book.iter().map(|resting| resting.order.clone()).collect())
+    }
+
+    fn last_executions(&self) -> Vec<Execution> {
+        self.executions.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn starting_account() -> HashMap<String, i32> {
+        ["foo", "bar"].iter().map(|&widget| (widget.to_string(), 10)).collect()
+    }
+
+    #[test]
+    fn test_order_book() {
+        let mut market = OrderBookMarket::new(starting_account());
+        let seller = market.create_account().unwrap();
+        let buyer = market.create_account().unwrap();
+
+        // nothing to trade against yet
+        market.submit_trade(&buyer, "foo", "bar", 1).expect_err("book should be empty");
+
+        // sell 3 foo for at least 6 bar; it rests and escrows the foo
+        let ask = market.place_order(&seller, "foo", 3, "bar", 6).unwrap();
+        assert_eq!(ask.remaining, 3);
+        assert_eq!(market.get_account(&seller).unwrap()["foo"], 7);
+        assert_eq!(market.get_market().unwrap()["foo"], 3);
+
+        // a cheaper ask placed later should fill first
+        let cheap = market.place_order(&seller, "foo", 1, "bar", 1).unwrap();
+
+        // quotes start from the cheapest ask
+        assert_eq!(market.quote(&buyer, "foo", "bar", 2).unwrap().sell_amount, 3);
+        market.quote(&buyer, "foo", "bar", 5).expect_err("only 4 foo are for sale");
+
+        // paying 1 bar per foo only crosses the cheap ask
+        let bid = market.place_order(&buyer, "bar", 1, "foo", 1).unwrap();
+        assert_eq!(bid.remaining, 0);
+        assert_eq!(market.get_orders().unwrap().len(), 1);
+        market.cancel_order(&seller, cheap.id).expect_err("cheap order should have filled");
+
+        // buy 2 foo paying up to 2 bar each
+        let bid = market.place_order(&buyer, "bar", 4, "foo", 2).unwrap();
+        assert_eq!(bid.remaining, 0);
+        assert_eq!(market.get_account(&buyer).unwrap()["foo"], 13);
+        assert_eq!(market.get_account(&buyer).unwrap()["bar"], 5);
+        assert_eq!(market.get_account(&seller).unwrap()["bar"], 15);
+
+        // only the owner can cancel, and cancelling refunds the escrow
+        market.cancel_order(&buyer, ask.id).expect_err("buyer doesn't own the ask");
+        let cancelled = market.cancel_order(&seller, ask.id).unwrap();
+        assert_eq!(cancelled.remaining, 1);
+        assert_eq!(market.get_account(&seller).unwrap()["foo"], 7);
+        assert_eq!(market.get_market().unwrap()["foo"], 0);
+
+        // trades take from the best asks and fill completely or not at all
+        market.place_order(&seller, "foo", 2, "bar", 2).unwrap();
+        market.place_order(&seller, "foo", 2, "bar", 4).unwrap();
+        market.submit_trade(&buyer, "foo", "bar", 5).expect_err("only 4 foo are for sale");
+        assert_eq!(market.get_account(&buyer).unwrap()["foo"], 13);
+        market.submit_trade(&buyer, "foo", "bar", 3).unwrap();
+        assert_eq!(market.get_account(&buyer).unwrap()["foo"], 16);
+        assert_eq!(market.get_account(&buyer).unwrap()["bar"], 1);
+        assert_eq!(market.get_account(&seller).unwrap()["bar"], 19);
+        assert_eq!(market.get_market().unwrap()["foo"], 1);
+        market.submit_trade(&buyer, "foo", "bar", 1).expect_err("not enough bar left");
+
+        // leaving cancels any resting orders
+        market.place_order(&seller, "foo", 3, "bar", 30).unwrap();
+        assert_eq!(market.remove_account(&seller).unwrap()["foo"], 4);
+        assert!(market.get_orders().unwrap().is_empty());
+
+        // bad orders are rejected
+        market.place_order(&buyer, "foo", 0, "bar", 1).expect_err("amounts must be positive");
+        market.place_order(&buyer, "foo", 100, "bar", 1).expect_err("not enough foo");
+        market.place_order(&buyer, "foo", 1, "baz", 1).expect_err("baz isn't in the market");
+
+        // costs and counts that don't fit are rejected before anything changes
+        let mut market = OrderBookMarket::new(starting_account());
+        let makers = [market.create_account().unwrap(), market.create_account().unwrap()];
+        for maker in &makers {
+            market.place_order(maker, "foo", 1, "bar", i32::MAX).unwrap();
+        }
+        let rich: HashMap<String, i32> = [("bar".to_string(), i32::MAX)].iter().cloned().collect();
+        let taker = market.add_account(rich).unwrap();
+        let before = (market.get_market().unwrap().clone(), market.get_orders().unwrap());
+        let is_invalid = |result: Result<(), ValidationError>| matches!(result, Err(error) if error.code() == ErrorCode::InvalidAmount);
+        assert!(is_invalid(market.quote(&taker, "foo", "bar", 2).map(drop)));
+        assert!(is_invalid(market.submit_trade(&taker, "foo", "bar", 2)));
+        // the maker already has 10 bar, so it can't be paid i32::MAX more
+        assert!(is_invalid(market.submit_trade(&taker, "foo", "bar", 1)));
+        assert!(is_invalid(market.place_order(&taker, "bar", i32::MAX, "foo", 1).map(drop)));
+        assert_eq!((market.get_market().unwrap().clone(), market.get_orders().unwrap()), before);
+        assert_eq!(market.get_account(&taker).unwrap()["bar"], i32::MAX);
+        assert!(makers.iter().all(|maker| market.get_account(maker).unwrap()["bar"] == 10));
+    }
+}
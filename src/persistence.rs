@@ -0,0 +1,346 @@
+// a market wrapper that writes every change to disk so a restarted server can recover
+//
+// the state directory holds a snapshot of the whole market and a journal of every change
+// made since. opening the directory loads the snapshot and replays the journal on top of
+// it. every so many changes a new snapshot is taken so the journal stays short. a change
+// is only kept once it is in the journal; if it can't be written the call is rejected.
+// to be able to back a call out, every change is made on a copy of the whole market that
+// replaces the original once journaled, so each write costs as much as a snapshot does in
+// memory. that's fine for the small markets this serves but grows with the account count
+//
+// replaying assumes the wrapped market is deterministic: making the same calls on the
+// same state must give the same results, including the ids of new accounts
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::market::{ErrorCode, Execution, Fill, Leg, Market, Order, Quote, ValidationError};
+use crate::transfer::AccountBundle;
+
+const SNAPSHOT: &str = "snapshot.json";
+const JOURNAL: &str = "journal.jsonl";
+
+// a change that made it into the market, along with what the market answered
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Change {
+    CreateAccount { id: String },
+    AddAccount { account: HashMap<String, i32>, id: String },
+    RemoveAccount { id: String },
+    Trade { id: String, buy: String, sell: String, amount: i32 },
+    Bundle { id: String, legs: Vec<Leg> },
+    PlaceOrder { id: String, sell: String, sell_amount: i32, buy: String, buy_amount: i32, order: u64 },
+    CancelOrder { id: String, order: u64 },
+    ExportAccount { id: String, destination: String },
+    ImportAccount { bundle: AccountBundle, id: String },
+}
+
+// changes are numbered so a journal that outlived its snapshot isn't applied twice
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    sequence: u64,
+    change: Change,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile<M> {
+    sequence: u64,
+    market: M,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// applies a journaled change, checking the market answers the way it did the first time
+fn replay<M: Market + Clone>(market: &mut M, change: &Change) -> Result<(), String> {
+    let same_id = |replayed: Result<String, ValidationError>, id: &str| match replayed {
+        Ok(replayed) if replayed == id => Ok(()),
+        Ok(replayed) => Err(format!("expected account {} but got {}", id, replayed)),
+        Err(error) => Err(error.to_string()),
+    };
+    match change {
+        Change::CreateAccount { id } => same_id(market.create_account(), id),
+        Change::AddAccount { account, id } => same_id(market.add_account(account.clone()), id),
+        Change::RemoveAccount { id } => market.remove_account(id).map(|_| ()).map_err(|error| error.to_string()),
+        Change::Trade { id, buy, sell, amount } => market.submit_trade(id, buy, sell, *amount).map_err(|error| error.to_string()),
+        Change::Bundle { id, legs } => market.submit_bundle(id, legs).map_err(|error| error.to_string()),
+        Change::PlaceOrder { id, sell, sell_amount, buy, buy_amount, order } => {
+            match market.place_order(id, sell, *sell_amount, buy, *buy_amount) {
+                Ok(placed) if placed.id == *order => Ok(()),
+                Ok(placed) => Err(format!("expected order {} but got {}", order, placed.id)),
+                Err(error) => Err(error.to_string()),
+            }
+        }
+        Change::CancelOrder { id, order } => market.cancel_order(id, *order).map(|_| ()).map_err(|error| error.to_string()),
+        Change::ExportAccount { id, destination } => market.export_account(id, destination).map(|_| ()).map_err(|error| error.to_string()),
+        Change::ImportAccount { bundle, id } => same_id(market.import_account(bundle), id),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PersistentMarket<M: Market> {
+    market: M,
+    dir: PathBuf,
+    // number of the last journaled change
+    sequence: u64,
+    since_snapshot: u64,
+    snapshot_every: u64,
+}
+
+impl<M: Market + Clone + Serialize + DeserializeOwned> PersistentMarket<M> {
+    // recovers the market saved in dir, or starts saving the given market if there is nothing there yet
+    pub fn open<P: AsRef<Path>>(dir: P, market: M) -> io::Result<PersistentMarket<M>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut persistent = PersistentMarket { market, dir, sequence: 0, since_snapshot: 0, snapshot_every: 1000 };
+
+        let snapshot_path = persistent.dir.join(SNAPSHOT);
+        if !snapshot_path.exists() {
+            info!("no snapshot in {}; starting from the given market", persistent.dir.display());
+            persistent.snapshot()?;
+            return Ok(persistent);
+        }
+        let snapshot: SnapshotFile<M> = serde_json::from_reader(BufReader::new(File::open(&snapshot_path)?))?;
+        persistent.market = snapshot.market;
+        persistent.sequence = snapshot.sequence;
+
+        let journal_path = persistent.dir.join(JOURNAL);
+        if journal_path.exists() {
+            let lines: Vec<String> = BufReader::new(File::open(&journal_path)?).lines().collect::<io::Result<_>>()?;
+            for (i, line) in lines.iter().enumerate() {
+                let entry: Entry = match serde_json::from_str(line) {
+                    Ok(entry) => entry,
+                    // a crash can cut off the last write; anything before that is corruption
+                    Err(error) if i + 1 == lines.len() => {
+                        warn!("ignoring partial journal entry: {}", error);
+                        break;
+                    }
+                    Err(error) => return Err(invalid_data(format!("bad journal entry {}: {}", i, error))),
+                };
+                if entry.sequence <= persistent.sequence {
+                    continue;
+                }
+                replay(&mut persistent.market, &entry.change)
+                    .map_err(|error| invalid_data(format!("unable to replay change {}: {}", entry.sequence, error)))?;
+                persistent.sequence = entry.sequence;
+                persistent.since_snapshot += 1;
+            }
+        }
+        info!("recovered market at change {} from {}", persistent.sequence, persistent.dir.display());
+        // start the next journal from a clean snapshot in case the last line was cut off
+        persistent.snapshot()?;
+        Ok(persistent)
+    }
+
+    // how many changes are journaled before the next snapshot is taken
+    pub fn with_snapshot_every(mut self, changes: u64) -> PersistentMarket<M> {
+        assert!(changes > 0, "snapshots need to be taken after at least one change");
+        self.snapshot_every = changes;
+        self
+    }
+
+    // writes the whole market to disk and starts a new journal
+    pub fn snapshot(&mut self) -> io::Result<()> {
+        // the snapshot is swapped in whole so a crash leaves either the old or the new one
+        let snapshot = SnapshotFile { sequence: self.sequence, market: &self.market };
+        let staged = self.dir.join(format!("{}.tmp", SNAPSHOT));
+        let mut file = File::create(&staged)?;
+        serde_json::to_writer(&mut file, &snapshot)?;
+        file.sync_all()?;
+        fs::rename(&staged, self.dir.join(SNAPSHOT))?;
+        File::create(self.dir.join(JOURNAL))?.sync_all()?;
+        self.since_snapshot = 0;
+        Ok(())
+    }
+
+    // writes a change to the end of the journal, cutting off anything half written if it fails
+    fn append(&mut self, change: Change) -> io::Result<()> {
+        let entry = Entry { sequence: self.sequence + 1, change };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let mut journal = OpenOptions::new().create(true).append(true).open(self.dir.join(JOURNAL))?;
+        let length = journal.metadata()?.len();
+        if let Err(error) = journal.write_all(line.as_bytes()).and_then(|_| journal.sync_data()) {
+            let _ = journal.set_len(length);
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    // makes a call on a copy of the market and only keeps the copy once the change is
+    // journaled, so the market in memory never gets ahead of the one on disk. a change
+    // that can't be journaled is rejected
+    fn apply<T>(&mut self, call: impl FnOnce(&mut M) -> Result<T, ValidationError>, change: impl FnOnce(&T) -> Change) -> Result<T, ValidationError> {
+        // see the module comment for what this copy costs
+        let mut market = self.market.clone();
+        let result = call(&mut market)?;
+        if let Err(error) = self.append(change(&result)) {
+            error!("unable to journal a change to {}: {}", self.dir.display(), error);
+            return Err(ValidationError::MarketError(ErrorCode::Other, format!("unable to save the change: {}", error)));
+        }
+        self.market = market;
+        self.sequence += 1;
+        self.since_snapshot += 1;
+        // the change is already safe in the journal, so a failed snapshot is tried again later
+        if self.since_snapshot >= self.snapshot_every {
+            if let Err(error) = self.snapshot() {
+                warn!("unable to snapshot to {}: {}", self.dir.display(), error);
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl<M: Market + Clone + Serialize + DeserializeOwned> Market for PersistentMarket<M> {
+    fn get_market(&self) -> Result<&HashMap<String, i32>, ValidationError> {
+        self.market.get_market()
+    }
+
+    fn get_account(&self, id: &str) -> Result<&HashMap<String, i32>, ValidationError> {
+        self.market.get_account(id)
+    }
+
+    fn get_accounts(&self) -> Result<Vec<String>, ValidationError> {
+        self.market.get_accounts()
+    }
+
+    fn get_quotes(&self) -> Result<Vec<Quote>, ValidationError> {
+        self.market.get_quotes()
+    }
+
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+        self.market.quote(id, buy, sell, amount)
+    }
+
+    fn get_orders(&self) -> Result<Vec<Order>, ValidationError> {
+        self.market.get_orders()
+    }
+
+    fn last_executions(&self) -> Vec<Execution> {
+        self.market.last_executions()
+    }
+
+    fn create_account(&mut self) -> Result<String, ValidationError> {
+        self.apply(|market| market.create_account(), |id| Change::CreateAccount { id: id.clone() })
+    }
+
+    fn add_account(&mut self, account: HashMap<String, i32>) -> Result<String, ValidationError> {
+        let journaled = account.clone();
+        self.apply(|market| market.add_account(account), |id| Change::AddAccount { account: journaled, id: id.clone() })
+    }
+
+    fn remove_account(&mut self, id: &str) -> Result<HashMap<String, i32>, ValidationError> {
+        self.apply(|market| market.remove_account(id), |_| Change::RemoveAccount { id: id.to_string() })
+    }
+
+    fn submit_trade(&mut self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ValidationError> {
+        self.apply(
+            |market| market.submit_trade(id, buy, sell, amount),
+            |_| Change::Trade { id: id.to_string(), buy: buy.to_string(), sell: sell.to_string(), amount },
+        )
+    }
+
+    // only a bundle that went through is journaled, so nothing needs to be rolled back on disk
+    fn submit_bundle(&mut self, id: &str, legs: &[Leg]) -> Result<(), ValidationError>
+    where
+        Self: Sized + Clone,
+    {
+        self.apply(|market| market.submit_bundle(id, legs), |_| Change::Bundle { id: id.to_string(), legs: legs.to_vec() })
+    }
+
+    fn place_order(&mut self, id: &str, sell: &str, sell_amount: i32, buy: &str, buy_amount: i32) -> Result<Order, ValidationError> {
+        self.apply(
+            |market| market.place_order(id, sell, sell_amount, buy, buy_amount),
+            |order| Change::PlaceOrder {
+                id: id.to_string(),
+                sell: sell.to_string(),
+                sell_amount,
+                buy: buy.to_string(),
+                buy_amount,
+                order: order.id,
+            },
+        )
+    }
+
+    fn cancel_order(&mut self, id: &str, order: u64) -> Result<Order, ValidationError> {
+        self.apply(|market| market.cancel_order(id, order), |_| Change::CancelOrder { id: id.to_string(), order })
+    }
+
+    fn export_account(&mut self, id: &str, destination: &str) -> Result<AccountBundle, ValidationError> {
+        self.apply(
+            |market| market.export_account(id, destination),
+            |_| Change::ExportAccount { id: id.to_string(), destination: destination.to_string() },
+        )
+    }
+
+    fn import_account(&mut self, bundle: &AccountBundle) -> Result<String, ValidationError> {
+        self.apply(|market| market.import_account(bundle), |id| Change::ImportAccount { bundle: bundle.clone(), id: id.clone() })
+    }
+
+    fn history(&self, id: &str, since: u64) -> Result<Vec<Fill>, ValidationError> {
+        self.market.history(id, since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use crate::amm::AmmMarket;
+
+    fn new_market() -> AmmMarket {
+        let account = ["foo", "bar"].iter().map(|&widget| (widget.to_string(), 100)).collect();
+        AmmMarket::new(account).with_pool(("foo", 1000), ("bar", 1000), 30)
+    }
+
+    #[test]
+    fn test_persistent_market() {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let dir = std::env::temp_dir().join(format!("widget-market-{}-{}", std::process::id(), nanos));
+
+        let mut market = PersistentMarket::open(&dir, new_market()).unwrap().with_snapshot_every(4);
+        let first = market.create_account().unwrap();
+        let second = market.add_account(market.get_account(&first).unwrap().clone()).unwrap();
+        market.submit_trade(&first, "foo", "bar", 5).unwrap();
+        market.submit_trade(&first, "foo", "baz", 5).expect_err("there is no baz");
+        market.submit_bundle(&second, &[Leg { buy: "bar".to_string(), sell: "foo".to_string(), amount: 3 }]).unwrap();
+        market.submit_trade(&second, "foo", "bar", 2).unwrap();
+        market.remove_account(&first).unwrap();
+
+        // restarting from a different market recovers the saved one
+        let recovered = PersistentMarket::open(&dir, AmmMarket::new(HashMap::new())).unwrap();
+        assert_eq!(recovered.get_market().unwrap(), market.get_market().unwrap());
+        assert_eq!(recovered.get_account(&second).unwrap(), market.get_account(&second).unwrap());
+        recovered.get_account(&first).expect_err("first account left");
+        assert_eq!(recovered.get_quotes().unwrap(), market.get_quotes().unwrap());
+
+        // a journal cut off mid-write only loses the last change
+        let mut recovered = recovered;
+        let third = recovered.create_account().unwrap();
+        let mut journal = OpenOptions::new().append(true).open(dir.join(JOURNAL)).unwrap();
+        journal.write_all(b"{\"sequence\": 100, \"change\": {\"Trade\"").unwrap();
+        let mut recovered = PersistentMarket::open(&dir, new_market()).unwrap();
+        let before = recovered.get_account(&third).unwrap().clone();
+
+        // a change that can't be journaled is rejected and leaves the market as it was
+        fs::remove_file(dir.join(JOURNAL)).unwrap();
+        fs::create_dir(dir.join(JOURNAL)).unwrap();
+        recovered.submit_trade(&third, "foo", "bar", 5).expect_err("the journal is a directory");
+        assert_eq!(recovered.get_account(&third).unwrap(), &before);
+        fs::remove_dir(dir.join(JOURNAL)).unwrap();
+        recovered.submit_trade(&third, "foo", "bar", 5).unwrap();
+
+        // a journal that doesn't match the snapshot is refused
+        let mut journal = OpenOptions::new().append(true).open(dir.join(JOURNAL)).unwrap();
+        journal.write_all(b"{\"sequence\": 100, \"change\": {\"RemoveAccount\": {\"id\": \"nobody\"}}}\n").unwrap();
+        PersistentMarket::open(&dir, new_market()).expect_err("nobody never joined");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+// conditions that have to hold for a trade to be made
+//
+// predicates only look at widget counts, so any market can evaluate them through
+// its views of the market and the trading account. widgets missing from a view
+// are counted as zero
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::market::{Market, ValidationError};
+
+// whose widgets a comparison looks at
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Holder {
+    Market,
+    Account,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Op {
+    fn apply(self, count: i32, value: i32) -> bool {
+        match self {
+            Op::Less => count < value,
+            Op::LessOrEqual => count <= value,
+            Op::Equal => count == value,
+            Op::GreaterOrEqual => count >= value,
+            Op::Greater => count > value,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Predicate {
+    // compares how many of a widget the holder has against a value
+    Compare { holder: Holder, widget: String, op: Op, value: i32 },
+    // holds if every predicate holds; an empty list always holds
+    All(Vec<Predicate>),
+    // holds if any predicate holds; an empty list never holds
+    Any(Vec<Predicate>),
+    Not(Box<Predicate>),
+}
+
+impl Predicate {
+    pub fn market(widget: &str, op: Op, value: i32) -> Predicate {
+        Predicate::Compare { holder: Holder::Market, widget: widget.to_string(), op, value }
+    }
+
+    pub fn account(widget: &str, op: Op, value: i32) -> Predicate {
+        Predicate::Compare { holder: Holder::Account, widget: widget.to_string(), op, value }
+    }
+
+    // checks the predicate against the market as seen by the account
+    pub fn evaluate<M: Market + ?Sized>(&self, market: &M, id: &str) -> Result<bool, ValidationError> {
+        self.check(&|holder, widget| {
+            let counts = match holder {
+                Holder::Market => market.get_market()?,
+                Holder::Account => market.get_account(id)?,
+            };
+            Ok(counts.get(widget).copied().unwrap_or(0))
+        })
+    }
+
+    // checks the predicate against widget counts that were already looked up
+    pub fn evaluate_counts(&self, market: &HashMap<String, i32>, account: &HashMap<String, i32>) -> bool {
+        let holds = self.check(&|holder, widget| {
+            let counts = match holder {
+                Holder::Market => market,
+                Holder::Account => account,
+            };
+            Ok(counts.get(widget).copied().unwrap_or(0))
+        });
+        holds.unwrap_or(false)
+    }
+
+    // counts are only looked up as comparisons need them
+    fn check<F: Fn(Holder, &str) -> Result<i32, ValidationError>>(&self, count: &F) -> Result<bool, ValidationError> {
+        match self {
+            Predicate::Compare { holder, widget, op, value } => Ok(op.apply(count(*holder, widget)?, *value)),
+            Predicate::All(predicates) => {
+                for predicate in predicates {
+                    if !predicate.check(count)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Predicate::Any(predicates) => {
+                for predicate in predicates {
+                    if predicate.check(count)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Predicate::Not(predicate) => Ok(!predicate.check(count)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::amm::AmmMarket;
+
+    #[test]
+    fn test_predicate() {
+        let account: HashMap<String, i32> = [("foo".to_string(), 5), ("bar".to_string(), 20)].iter().cloned().collect();
+        let mut market = AmmMarket::new(account).with_pool(("foo", 100), ("bar", 600), 0);
+        let id = market.create_account().unwrap();
+
+        assert!(Predicate::market("bar", Op::GreaterOrEqual, 500).evaluate(&market, &id).unwrap());
+        assert!(Predicate::account("foo", Op::Less, 10).evaluate(&market, &id).unwrap());
+        assert!(!Predicate::account("foo", Op::Equal, 4).evaluate(&market, &id).unwrap());
+        assert!(Predicate::account("baz", Op::Equal, 0).evaluate(&market, &id).unwrap());
+
+        // combinators
+        let both = Predicate::All(vec![
+            Predicate::market("bar", Op::Greater, 500),
+            Predicate::account("bar", Op::LessOrEqual, 10),
+        ]);
+        assert!(!both.evaluate(&market, &id).unwrap());
+        assert!(Predicate::Not(Box::new(both.clone())).evaluate(&market, &id).unwrap());
+        let either = match both {
+            Predicate::All(predicates) => Predicate::Any(predicates),
+            _ => unreachable!(),
+        };
+        assert!(either.evaluate(&market, &id).unwrap());
+        assert!(Predicate::All(vec![]).evaluate(&market, &id).unwrap());
+        assert!(!Predicate::Any(vec![]).evaluate(&market, &id).unwrap());
+
+        // account comparisons need a real account
+        Predicate::account("foo", Op::Less, 10).evaluate(&market, "fake id").expect_err("shouldn't have been an account!");
+
+        // conditional trades only go through when the predicate holds
+        let rich = Predicate::account("bar", Op::GreaterOrEqual, 100);
+        market.submit_trade_if(&id, "foo", "bar", 1, &rich).expect_err("account only has 20 bar");
+        assert_eq!(market.get_account(&id).unwrap()["foo"], 5);
+        let poor = Predicate::Not(Box::new(rich));
+        market.submit_trade_if(&id, "foo", "bar", 1, &poor).unwrap();
+        assert_eq!(market.get_account(&id).unwrap()["foo"], 6);
+    }
+}
@@ -0,0 +1,280 @@
+// recordings of the calls a market server answered, for replaying against another market
+//
+// a recording is a json line per call with when it arrived, what was asked and what the
+// market answered. replaying makes the same calls on a fresh market and reports every
+// call that was answered differently. ids handed out by join are matched up as they are
+// replayed, so markets with random ids can still be compared
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::market::{Fill, Leg, Market, Order, Quote, Snapshot, ValidationError};
+use crate::predicate::Predicate;
+use crate::transfer::AccountBundle;
+
+// a request made of the market
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Call {
+    Join { account: Option<HashMap<String, i32>>, bundle: Option<AccountBundle> },
+    Check { id: String },
+    Quote { id: String, buy: String, sell: String, amount: i32 },
+    Trade { id: String, buy: String, sell: String, amount: i32, condition: Option<Predicate> },
+    Bundle { id: String, legs: Vec<Leg> },
+    Leave { id: String, destination: Option<String> },
+    PlaceOrder { id: String, sell: String, sell_amount: i32, buy: String, buy_amount: i32 },
+    CancelOrder { id: String, order: u64 },
+    ListOrders,
+    History { id: String, since: u64 },
+}
+
+impl Call {
+    fn id_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Call::Check { id }
+            | Call::Quote { id, .. }
+            | Call::Trade { id, .. }
+            | Call::Bundle { id, .. }
+            | Call::Leave { id, .. }
+            | Call::PlaceOrder { id, .. }
+            | Call::CancelOrder { id, .. }
+            | Call::History { id, .. } => Some(id),
+            Call::Join { .. } | Call::ListOrders => None,
+        }
+    }
+}
+
+// what the market answered
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Outcome {
+    Joined(String),
+    Checked(Snapshot),
+    Quoted(Quote),
+    Done,
+    // bundles carry a random nonce, so only the account is kept
+    Left(HashMap<String, i32>),
+    Order(Order),
+    Orders(Vec<Order>),
+    Fills(Vec<Fill>),
+    Rejected(ValidationError),
+}
+
+impl From<String> for Outcome {
+    fn from(id: String) -> Outcome {
+        Outcome::Joined(id)
+    }
+}
+
+impl From<Snapshot> for Outcome {
+    fn from(snapshot: Snapshot) -> Outcome {
+        Outcome::Checked(snapshot)
+    }
+}
+
+impl From<Quote> for Outcome {
+    fn from(quote: Quote) -> Outcome {
+        Outcome::Quoted(quote)
+    }
+}
+
+impl From<()> for Outcome {
+    fn from(_: ()) -> Outcome {
+        Outcome::Done
+    }
+}
+
+impl From<(HashMap<String, i32>, Option<AccountBundle>)> for Outcome {
+    fn from((account, _): (HashMap<String, i32>, Option<AccountBundle>)) -> Outcome {
+        Outcome::Left(account)
+    }
+}
+
+impl From<Order> for Outcome {
+    fn from(order: Order) -> Outcome {
+        Outcome::Order(order)
+    }
+}
+
+impl From<Vec<Order>> for Outcome {
+    fn from(orders: Vec<Order>) -> Outcome {
+        Outcome::Orders(orders)
+    }
+}
+
+impl From<Vec<Fill>> for Outcome {
+    fn from(fills: Vec<Fill>) -> Outcome {
+        Outcome::Fills(fills)
+    }
+}
+
+impl<T: Into<Outcome>> From<Result<T, ValidationError>> for Outcome {
+    fn from(result: Result<T, ValidationError>) -> Outcome {
+        match result {
+            Ok(value) => value.into(),
+            Err(error) => Outcome::Rejected(error),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    // milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub call: Call,
+    pub outcome: Outcome,
+}
+
+// writes records to a file as they happen
+pub struct Recorder {
+    writer: RefCell<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        Ok(Recorder { writer: RefCell::new(BufWriter::new(File::create(path)?)) })
+    }
+
+    pub fn record(&self, call: Call, outcome: Outcome) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let mut writer = self.writer.borrow_mut();
+        serde_json::to_writer(&mut *writer, &Record { timestamp, call, outcome })?;
+        writer.write_all(b"\n")?;
+        // flush every call so a crashed server still leaves its recording behind
+        writer.flush()
+    }
+}
+
+pub fn read_records<P: AsRef<Path>>(path: P) -> io::Result<Vec<Record>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+// makes a call the same way the server does
+pub fn execute<M: Market + Clone>(market: &mut M, call: &Call) -> Outcome {
+    match call {
+        Call::Join { bundle: Some(bundle), .. } => market.import_account(bundle).into(),
+        Call::Join { account: Some(account), .. } => market.add_account(account.clone()).into(),
+        Call::Join { .. } => market.create_account().into(),
+        Call::Check { id } => market
+            .get_market()
+            .and_then(|view| {
+                Ok(Snapshot { account: market.get_account(id)?.clone(), market: view.clone(), quotes: market.get_quotes()? })
+            })
+            .into(),
+        Call::Quote { id, buy, sell, amount } => market.quote(id, buy, sell, *amount).into(),
+        Call::Trade { id, buy, sell, amount, condition: Some(condition) } => {
+            market.submit_trade_if(id, buy, sell, *amount, condition).into()
+        }
+        Call::Trade { id, buy, sell, amount, condition: None } => market.submit_trade(id, buy, sell, *amount).into(),
+        Call::Bundle { id, legs } => market.submit_bundle(id, legs).into(),
+        Call::Leave { id, destination: Some(destination) } => {
+            market.export_account(id, destination).map(|bundle| (bundle.account.clone(), Some(bundle))).into()
+        }
+        Call::Leave { id, destination: None } => market.remove_account(id).map(|account| (account, None)).into(),
+        Call::PlaceOrder { id, sell, sell_amount, buy, buy_amount } => {
+            market.place_order(id, sell, *sell_amount, buy, *buy_amount).into()
+        }
+        Call::CancelOrder { id, order } => market.cancel_order(id, *order).into(),
+        Call::ListOrders => market.get_orders().into(),
+        Call::History { id, since } => market.history(id, *since).into(),
+    }
+}
+
+// a call that the replayed market answered differently
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    // position of the call in the recording
+    pub index: usize,
+    pub call: Call,
+    pub recorded: Outcome,
+    pub replayed: Outcome,
+}
+
+// replays a recording against a market, returning every call that diverged
+pub fn replay<M: Market + Clone>(market: &mut M, records: &[Record]) -> Vec<Divergence> {
+    // recorded id -> id the replayed market handed out
+    let mut ids: HashMap<String, String> = HashMap::new();
+    let mut divergences = Vec::new();
+    for (index, record) in records.iter().enumerate() {
+        let mut call = record.call.clone();
+        if let Some(id) = call.id_mut() {
+            if let Some(replayed) = ids.get(id) {
+                *id = replayed.clone();
+            }
+        }
+        let replayed = execute(market, &call);
+        let matches = match (&record.outcome, &replayed) {
+            (Outcome::Joined(recorded), Outcome::Joined(replayed)) => {
+                ids.insert(recorded.clone(), replayed.clone());
+                true
+            }
+            // fills are stamped with when they were made, which can't be replayed
+            (Outcome::Fills(recorded), Outcome::Fills(replayed)) => {
+                let unstamped = |fills: &[Fill]| fills.iter().map(|fill| Fill { timestamp: 0, ..fill.clone() }).collect::<Vec<_>>();
+                unstamped(recorded) == unstamped(replayed)
+            }
+            (recorded, replayed) => recorded == replayed,
+        };
+        if !matches {
+            divergences.push(Divergence { index, call, recorded: record.outcome.clone(), replayed });
+        }
+    }
+    divergences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::amm::AmmMarket;
+    use crate::order_book::OrderBookMarket;
+
+    fn starting_account() -> HashMap<String, i32> {
+        ["foo", "bar"].iter().map(|&widget| (widget.to_string(), 100)).collect()
+    }
+
+    fn record(call: Call, outcome: Outcome) -> Record {
+        Record { timestamp: 0, call, outcome }
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut market = AmmMarket::new(starting_account()).with_pool(("foo", 100), ("bar", 100), 30);
+        let calls = vec![
+            Call::Join { account: None, bundle: None },
+            Call::Trade { id: "account-0".to_string(), buy: "foo".to_string(), sell: "bar".to_string(), amount: 20, condition: None },
+            Call::Trade { id: "account-0".to_string(), buy: "foo".to_string(), sell: "baz".to_string(), amount: 2, condition: None },
+            Call::Check { id: "account-0".to_string() },
+            Call::Leave { id: "account-0".to_string(), destination: None },
+        ];
+        let records: Vec<Record> = calls.into_iter().map(|call| {
+            let outcome = execute(&mut market, &call);
+            record(call, outcome)
+        }).collect();
+        assert!(matches!(records[2].outcome, Outcome::Rejected(_)));
+
+        // records survive being written out
+        let line = serde_json::to_string(&records[3]).unwrap();
+        assert_eq!(serde_json::from_str::<Record>(&line).unwrap(), records[3]);
+
+        // the same market replays without any differences, even with different ids
+        let mut same = AmmMarket::new(starting_account()).with_pool(("foo", 100), ("bar", 100), 30);
+        same.create_account().unwrap();
+        assert!(replay(&mut same, &records).is_empty());
+
+        // a market with a different fee prices the trade differently
+        let mut cheaper = AmmMarket::new(starting_account()).with_pool(("foo", 100), ("bar", 100), 0);
+        let divergences = replay(&mut cheaper, &records);
+        assert_eq!(divergences.iter().map(|divergence| divergence.index).collect::<Vec<_>>(), vec![3, 4]);
+
+        // and a market that can't make trades diverges as soon as one is asked for
+        let mut book = OrderBookMarket::new(starting_account());
+        assert_eq!(replay(&mut book, &records)[0].index, 1);
+    }
+}
@@ -0,0 +1,76 @@
+// settling a market's accounts when it shuts down
+//
+// every account that is still open is removed from the market, which cancels any orders
+// it had resting, and written to <id>.json the same way the cli's leave writes it. what
+// is left in the market afterwards is written to market.json
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use log::info;
+
+use crate::async_market::AsyncMarket;
+
+// where the market's final inventory is written in a settlement directory
+pub const MARKET_FILE: &str = "market.json";
+
+fn write(path: &Path, counts: &HashMap<String, i32>) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(path, serde_json::to_string(counts)?)?;
+    Ok(())
+}
+
+// settles every open account into the directory, returning the ids that were settled
+pub async fn settle<A: AsyncMarket>(market: &A, dir: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let ids = market.get_accounts().await?;
+    for id in &ids {
+        let account = market.remove_account(id).await?;
+        write(&dir.join(format!("{}.json", id)), &account)?;
+    }
+    write(&dir.join(MARKET_FILE), &market.get_market().await?)?;
+    info!("settled {} accounts to {}", ids.len(), dir.display());
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use futures::executor::block_on;
+
+    use crate::async_market::SyncMarket;
+    use crate::order_book::OrderBookMarket;
+
+    fn read(path: &Path) -> HashMap<String, i32> {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_settle() {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let dir = std::env::temp_dir().join(format!("widget-market-{}-{}", std::process::id(), nanos));
+
+        let account: HashMap<String, i32> = [("foo".to_string(), 10), ("bar".to_string(), 10)].iter().cloned().collect();
+        let market = SyncMarket::new(OrderBookMarket::new(account.clone()));
+        let seller = block_on(market.create_account()).unwrap();
+        let other = block_on(market.create_account()).unwrap();
+        let left = block_on(market.create_account()).unwrap();
+        block_on(market.remove_account(&left)).unwrap();
+        block_on(market.place_order(&seller, "foo", 4, "bar", 4)).unwrap();
+
+        // only open accounts are settled, with their resting orders returned
+        let settled = block_on(settle(&market, &dir)).unwrap();
+        let mut open = vec![seller.clone(), other.clone()];
+        open.sort();
+        assert_eq!(settled, open);
+        assert_eq!(read(&dir.join(format!("{}.json", seller))), account);
+        assert_eq!(read(&dir.join(format!("{}.json", other))), account);
+        assert!(!dir.join(format!("{}.json", left)).exists());
+        assert!(read(&dir.join(MARKET_FILE)).values().all(|&count| count == 0));
+        assert!(block_on(market.get_accounts()).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
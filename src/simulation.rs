@@ -0,0 +1,331 @@
+// runs trader agents against a market in-process
+//
+// a simulation joins every trader to the market and then plays some number of rounds.
+// each round the traders take turns in an order shuffled by the simulation's rng; on its
+// turn a trader observes the market, its own account and the market's quotes and decides
+// what trades to make. everything random comes from the one seeded rng, so the same
+// seed, market and traders always play out the same way. at the end every account is
+// removed from the market, returning anything still resting in the book, and reported
+// on with how many of its trades went through.
+//
+// the built-in traders are:
+//  - RandomTrader: trades random amounts of random widgets, and can place orders too
+//  - Rebalancer: trades the widget it has most of for the one it has least of
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::market::{Market, Quote, ValidationError};
+
+// what a trader sees on its turn
+#[derive(Clone, Copy, Debug)]
+pub struct Observation<'a> {
+    pub round: usize,
+    pub market: &'a HashMap<String, i32>,
+    pub account: &'a HashMap<String, i32>,
+    // empty for markets that don't quote prices
+    pub quotes: &'a [Quote],
+}
+
+impl Observation<'_> {
+    // the market's widgets, sorted so traders pick from them the same way every run
+    pub fn widgets(&self) -> Vec<&str> {
+        let mut widgets: Vec<&str> = self.market.keys().map(String::as_str).collect();
+        widgets.sort_unstable();
+        widgets
+    }
+
+    pub fn holding(&self, widget: &str) -> i32 {
+        self.account.get(widget).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    // buys amount of buy with sell
+    Trade { buy: String, sell: String, amount: i32 },
+    PlaceOrder { sell: String, sell_amount: i32, buy: String, buy_amount: i32 },
+}
+
+pub trait Trader {
+    // picks what to do this turn; actions are made in order, and one being rejected
+    // doesn't stop the rest
+    fn decide(&mut self, observation: &Observation, rng: &mut StdRng) -> Vec<Action>;
+    // told how each action went
+    fn outcome(&mut self, _action: &Action, _result: &Result<(), ValidationError>) {}
+}
+
+// how one trader did
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraderReport {
+    pub name: String,
+    pub id: String,
+    pub start: HashMap<String, i32>,
+    // what the account left the market with
+    pub account: HashMap<String, i32>,
+    pub made: usize,
+    pub rejected: usize,
+}
+
+impl fmt::Display for TraderReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}): {} actions made, {} rejected", self.name, self.id, self.made, self.rejected)?;
+        for widget in self.start.keys().chain(self.account.keys()).collect::<BTreeSet<_>>() {
+            let count = |counts: &HashMap<String, i32>| counts.get(widget).copied().unwrap_or(0);
+            write!(f, "\n  {}: {} -> {}", widget, count(&self.start), count(&self.account))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimulationReport {
+    pub seed: u64,
+    pub rounds: usize,
+    pub traders: Vec<TraderReport>,
+    // what the market held once every account had left
+    pub market: HashMap<String, i32>,
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} rounds with seed {}", self.rounds, self.seed)?;
+        self.traders.iter().try_for_each(|trader| write!(f, "\n{}", trader))?;
+        let mut market: Vec<_> = self.market.iter().collect();
+        market.sort();
+        write!(f, "\nmarket:")?;
+        market.iter().try_for_each(|(widget, count)| write!(f, "\n  {}: {}", widget, count))
+    }
+}
+
+struct Agent {
+    name: String,
+    trader: Box<dyn Trader>,
+    // joins with the market's usual account if there isn't one
+    account: Option<HashMap<String, i32>>,
+}
+
+pub struct Simulation<M: Market> {
+    market: M,
+    agents: Vec<Agent>,
+    seed: u64,
+    rounds: usize,
+}
+
+impl<M: Market> Simulation<M> {
+    pub fn new(market: M) -> Simulation<M> {
+        Simulation { market, agents: Vec::new(), seed: 0, rounds: 100 }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Simulation<M> {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_rounds(mut self, rounds: usize) -> Simulation<M> {
+        self.rounds = rounds;
+        self
+    }
+
+    pub fn with_trader<T: 'static + Trader>(mut self, name: &str, trader: T) -> Simulation<M> {
+        self.agents.push(Agent { name: name.to_string(), trader: Box::new(trader), account: None });
+        self
+    }
+
+    // a trader that joins with its own account
+    pub fn with_funded_trader<T: 'static + Trader>(mut self, name: &str, trader: T, account: HashMap<String, i32>) -> Simulation<M> {
+        self.agents.push(Agent { name: name.to_string(), trader: Box::new(trader), account: Some(account) });
+        self
+    }
+
+    // plays every round and settles the traders' accounts
+    pub fn run(mut self) -> Result<SimulationReport, ValidationError> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut reports = Vec::new();
+        for agent in &self.agents {
+            let id = match &agent.account {
+                Some(account) => self.market.add_account(account.clone())?,
+                None => self.market.create_account()?,
+            };
+            let start = self.market.get_account(&id)?.clone();
+            reports.push(TraderReport { name: agent.name.clone(), id, start, account: HashMap::new(), made: 0, rejected: 0 });
+        }
+
+        let mut turns: Vec<usize> = (0..self.agents.len()).collect();
+        for round in 0..self.rounds {
+            turns.shuffle(&mut rng);
+            for &turn in &turns {
+                let (agent, report) = (&mut self.agents[turn], &mut reports[turn]);
+                let actions = {
+                    let quotes = self.market.get_quotes().unwrap_or_default();
+                    let observation = Observation {
+                        round,
+                        market: self.market.get_market()?,
+                        account: self.market.get_account(&report.id)?,
+                        quotes: &quotes,
+                    };
+                    agent.trader.decide(&observation, &mut rng)
+                };
+                for action in &actions {
+                    let result = act(&mut self.market, &report.id, action);
+                    match result {
+                        Ok(()) => report.made += 1,
+                        Err(_) => report.rejected += 1,
+                    }
+                    agent.trader.outcome(action, &result);
+                }
+            }
+        }
+
+        for report in &mut reports {
+            report.account = self.market.remove_account(&report.id)?;
+        }
+        Ok(SimulationReport { seed: self.seed, rounds: self.rounds, traders: reports, market: self.market.get_market()?.clone() })
+    }
+}
+
+fn act<M: Market>(market: &mut M, id: &str, action: &Action) -> Result<(), ValidationError> {
+    match action {
+        Action::Trade { buy, sell, amount } => market.submit_trade(id, buy, sell, *amount),
+        Action::PlaceOrder { sell, sell_amount, buy, buy_amount } => market.place_order(id, sell, *sell_amount, buy, *buy_amount).map(drop),
+    }
+}
+
+// trades between two random widgets each turn; with orders, it places a limit order at a
+// random price instead about half the time
+#[derive(Clone, Copy, Debug)]
+pub struct RandomTrader {
+    max_amount: i32,
+    orders: bool,
+}
+
+impl RandomTrader {
+    pub fn new(max_amount: i32) -> RandomTrader {
+        RandomTrader { max_amount: max_amount.max(1), orders: false }
+    }
+
+    pub fn placing_orders(mut self) -> RandomTrader {
+        self.orders = true;
+        self
+    }
+}
+
+impl Trader for RandomTrader {
+    fn decide(&mut self, observation: &Observation, rng: &mut StdRng) -> Vec<Action> {
+        let widgets = observation.widgets();
+        if widgets.len() < 2 {
+            return Vec::new();
+        }
+        let mut pair = widgets.choose_multiple(rng, 2);
+        let (buy, sell) = match (pair.next(), pair.next()) {
+            (Some(buy), Some(sell)) => (buy.to_string(), sell.to_string()),
+            _ => return Vec::new(),
+        };
+        let amount = rng.gen_range(1..=self.max_amount);
+        if self.orders && rng.gen_bool(0.5) {
+            let buy_amount = rng.gen_range(1..=self.max_amount);
+            vec![Action::PlaceOrder { sell, sell_amount: amount, buy, buy_amount }]
+        } else {
+            vec![Action::Trade { buy, sell, amount }]
+        }
+    }
+}
+
+// evens out its holdings, a fraction of the gap between its biggest and smallest at a time
+#[derive(Clone, Copy, Debug)]
+pub struct Rebalancer {
+    // how many turns it takes to close the gap, give or take prices
+    patience: i32,
+}
+
+impl Rebalancer {
+    pub fn new(patience: i32) -> Rebalancer {
+        Rebalancer { patience: patience.max(1) }
+    }
+}
+
+impl Trader for Rebalancer {
+    fn decide(&mut self, observation: &Observation, _: &mut StdRng) -> Vec<Action> {
+        let widgets = observation.widgets();
+        let most = widgets.iter().max_by_key(|widget| observation.holding(widget));
+        let least = widgets.iter().min_by_key(|widget| observation.holding(widget));
+        match (most, least) {
+            (Some(most), Some(least)) => {
+                let amount = (observation.holding(most) - observation.holding(least)) / (2 * self.patience);
+                if amount > 0 {
+                    vec![Action::Trade { buy: least.to_string(), sell: most.to_string(), amount }]
+                } else {
+                    Vec::new()
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::amm::AmmMarket;
+    use crate::order_book::OrderBookMarket;
+
+    fn account(foo: i32, bar: i32) -> HashMap<String, i32> {
+        [("foo".to_string(), foo), ("bar".to_string(), bar)].iter().cloned().collect()
+    }
+
+    fn total(report: &SimulationReport, start: bool) -> HashMap<String, i32> {
+        let mut total = HashMap::new();
+        for trader in &report.traders {
+            for (widget, count) in if start { &trader.start } else { &trader.account } {
+                *total.entry(widget.clone()).or_insert(0) += count;
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn test_simulation() {
+        let amm = AmmMarket::new(account(100, 100)).with_pool(("foo", 1000), ("bar", 1000), 30);
+        let simulate = |seed: u64| {
+            Simulation::new(amm.clone())
+                .with_seed(seed)
+                .with_rounds(50)
+                .with_trader("random-1", RandomTrader::new(20))
+                .with_trader("random-2", RandomTrader::new(20))
+                .with_funded_trader("rebalancer", Rebalancer::new(2), account(300, 0))
+                .run()
+                .unwrap()
+        };
+
+        // the same seed plays out the same way, and a different one doesn't
+        let report = simulate(7);
+        assert_eq!(report, simulate(7));
+        assert_ne!(report, simulate(8));
+        assert!(report.traders[..2].iter().all(|trader| trader.made + trader.rejected == 50));
+
+        // the rebalancer evened out, and the widgets it sold ended up in the pool
+        let rebalancer = &report.traders[2];
+        assert_eq!(rebalancer.start, account(300, 0));
+        assert!((rebalancer.account["foo"] - rebalancer.account["bar"]).abs() < 10, "{}", report);
+        let (before, after) = (total(&report, true), total(&report, false));
+        assert_eq!(before["foo"] + 1000, after["foo"] + report.market["foo"]);
+        assert_eq!(before["bar"] + 1000, after["bar"] + report.market["bar"]);
+
+        // on an order book the traders fill each other's orders, and what's still resting
+        // is handed back when they leave
+        let book = Simulation::new(OrderBookMarket::new(account(100, 100)))
+            .with_seed(7)
+            .with_rounds(50)
+            .with_trader("random-1", RandomTrader::new(10).placing_orders())
+            .with_trader("random-2", RandomTrader::new(10).placing_orders())
+            .run()
+            .unwrap();
+        assert!(book.traders.iter().any(|trader| trader.made > 25), "{}", book);
+        assert_eq!(total(&book, true), total(&book, false));
+        assert!(book.market.values().all(|&count| count == 0));
+    }
+}
@@ -1,156 +1,1046 @@
 // a simple server that runs a single market server an queries the underlying market on the caller thread
+use std::cell::{Cell, RefCell};
 use std::net::SocketAddr;
 use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 
 use capnp_rpc::pry;
 use capnp::capability::Promise;
 use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
-use futures::{AsyncReadExt, FutureExt};
+use futures::channel::{mpsc, oneshot};
+use futures::{future, AsyncReadExt, Future, FutureExt, StreamExt};
 use log::{error, info};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::task::{spawn_local, LocalSet};
 use tokio_util::compat::TokioAsyncReadCompatExt;
 
-use crate::market::Market;
+use crate::async_market::{AsyncMarket, SyncMarket};
+use crate::market::{ErrorCode, Fill, Leg, Market, Order, Quote, Snapshot, TradeEvent, ValidationError};
+use crate::predicate::{Holder, Op, Predicate};
+use crate::recording::{Call, Outcome, Recorder};
+use crate::settlement;
+use crate::transfer::AccountBundle;
 use crate::widget_capnp;
 
-impl <M: Market> widget_capnp::market::Server for M {
+// id-based access lets anyone who knows an id act on the account; it is kept so
+// short-lived clients like the cli can keep working across connections
+fn legacy_ids_enabled() -> bool {
+    cfg!(feature = "legacy-ids")
+}
+
+fn legacy_ids_disabled() -> ValidationError {
+    ValidationError::AccountError(
+        ErrorCode::Other,
+        "id-based access is disabled; use the account returned by join".to_string(),
+    )
+}
+
+impl From<ErrorCode> for widget_capnp::market::error::Code {
+    fn from(code: ErrorCode) -> Self {
+        use widget_capnp::market::error::Code;
+        match code {
+            ErrorCode::Other => Code::Other,
+            ErrorCode::UnknownAccount => Code::UnknownAccount,
+            ErrorCode::DuplicateAccount => Code::DuplicateAccount,
+            ErrorCode::UnknownWidget => Code::UnknownWidget,
+            ErrorCode::IdenticalWidgets => Code::IdenticalWidgets,
+            ErrorCode::InsufficientWidgets => Code::InsufficientWidgets,
+            ErrorCode::InvalidAmount => Code::InvalidAmount,
+            ErrorCode::UnknownOrder => Code::UnknownOrder,
+            ErrorCode::Unsupported => Code::Unsupported,
+            ErrorCode::PredicateFailed => Code::PredicateFailed,
+            ErrorCode::UnknownMarket => Code::UnknownMarket,
+            ErrorCode::InvalidBundle => Code::InvalidBundle,
+            ErrorCode::TradeLimitExceeded => Code::TradeLimitExceeded,
+            ErrorCode::PositionLimitExceeded => Code::PositionLimitExceeded,
+        }
+    }
+}
+
+// writes a validation error into the error field of a response
+pub(crate) fn set_error(error: &ValidationError, builder: widget_capnp::market::error::Builder) {
+    let mut reason = match error {
+        ValidationError::AccountError(..) => builder.init_account(),
+        ValidationError::MarketError(..) => builder.init_market(),
+        ValidationError::TradeError(..) => builder.init_trade(),
+    };
+    reason.set_code(error.code().into());
+    reason.set_message(error.message());
+}
+
+fn set_counts(mut builder: capnp::struct_list::Builder<widget_capnp::market::widget_count::Owned>, counts: &HashMap<String, i32>) {
+    counts.iter().enumerate().for_each(|(i, (w, c))| {
+        builder.reborrow().get(i as u32).set_widget(w);
+        builder.reborrow().get(i as u32).set_count(*c);
+    });
+}
+
+fn set_quote(mut builder: widget_capnp::market::quote::Builder, quote: &Quote) {
+    builder.set_buy(&quote.buy);
+    builder.set_buy_amount(quote.buy_amount);
+    builder.set_sell(&quote.sell);
+    builder.set_sell_amount(quote.sell_amount);
+}
+
+fn set_quotes(mut builder: capnp::struct_list::Builder<widget_capnp::market::quote::Owned>, quotes: &[Quote]) {
+    quotes.iter().enumerate().for_each(|(i, quote)| set_quote(builder.reborrow().get(i as u32), quote));
+}
+
+fn set_order(mut builder: widget_capnp::market::order::Builder, order: &Order) {
+    builder.set_id(order.id);
+    builder.set_sell(&order.sell);
+    builder.set_sell_amount(order.sell_amount);
+    builder.set_buy(&order.buy);
+    builder.set_buy_amount(order.buy_amount);
+    builder.set_remaining(order.remaining);
+}
+
+fn set_fills(mut builder: capnp::struct_list::Builder<widget_capnp::market::fill::Owned>, fills: &[Fill]) {
+    fills.iter().enumerate().for_each(|(i, fill)| {
+        let mut builder = builder.reborrow().get(i as u32);
+        builder.set_sequence(fill.sequence);
+        builder.set_timestamp(fill.timestamp);
+        builder.set_buy(&fill.buy);
+        builder.set_buy_amount(fill.buy_amount);
+        builder.set_sell(&fill.sell);
+        builder.set_sell_amount(fill.sell_amount);
+    });
+}
+
+fn set_legs(mut builder: capnp::struct_list::Builder<widget_capnp::market::leg::Owned>, legs: &[Leg]) {
+    legs.iter().enumerate().for_each(|(i, leg)| {
+        let mut builder = builder.reborrow().get(i as u32);
+        builder.set_buy(&leg.buy);
+        builder.set_sell(&leg.sell);
+        builder.set_amount(leg.amount);
+    });
+}
+
+fn set_bundle(mut builder: widget_capnp::market::account_bundle::Builder, bundle: &AccountBundle) {
+    builder.set_origin(&bundle.origin);
+    builder.set_destination(&bundle.destination);
+    set_counts(builder.reborrow().init_account(bundle.account.len() as u32), &bundle.account);
+    builder.set_nonce(bundle.nonce);
+    builder.set_signature(&bundle.signature);
+}
+
+fn read_counts(counts: capnp::struct_list::Reader<widget_capnp::market::widget_count::Owned>) -> capnp::Result<HashMap<String, i32>> {
+    counts.iter().map(|count| Ok((count.get_widget()?.to_string(), count.get_count()))).collect()
+}
+
+fn read_bundle(bundle: widget_capnp::market::account_bundle::Reader) -> capnp::Result<AccountBundle> {
+    Ok(AccountBundle {
+        origin: bundle.get_origin()?.to_string(),
+        destination: bundle.get_destination()?.to_string(),
+        account: read_counts(bundle.get_account()?)?,
+        nonce: bundle.get_nonce(),
+        signature: bundle.get_signature()?.to_string(),
+    })
+}
+
+fn read_legs(legs: capnp::struct_list::Reader<widget_capnp::market::leg::Owned>) -> capnp::Result<Vec<Leg>> {
+    legs.iter()
+        .map(|leg| Ok(Leg { buy: leg.get_buy()?.to_string(), sell: leg.get_sell()?.to_string(), amount: leg.get_amount() }))
+        .collect()
+}
+
+fn read_predicate(predicate: widget_capnp::market::predicate::Reader) -> capnp::Result<Predicate> {
+    use widget_capnp::market::predicate;
+    let read_all = |predicates: capnp::struct_list::Reader<predicate::Owned>| -> capnp::Result<Vec<Predicate>> {
+        predicates.iter().map(read_predicate).collect()
+    };
+    Ok(match predicate.which()? {
+        predicate::Compare(comparison) => {
+            let comparison = comparison?;
+            let holder = match comparison.get_holder()? {
+                predicate::Holder::Market => Holder::Market,
+                predicate::Holder::Account => Holder::Account,
+            };
+            let op = match comparison.get_op()? {
+                predicate::Op::Less => Op::Less,
+                predicate::Op::LessOrEqual => Op::LessOrEqual,
+                predicate::Op::Equal => Op::Equal,
+                predicate::Op::GreaterOrEqual => Op::GreaterOrEqual,
+                predicate::Op::Greater => Op::Greater,
+            };
+            Predicate::Compare { holder, widget: comparison.get_widget()?.to_string(), op, value: comparison.get_value() }
+        }
+        predicate::All(predicates) => Predicate::All(read_all(predicates?)?),
+        predicate::Any(predicates) => Predicate::Any(read_all(predicates?)?),
+        predicate::Not(predicate) => Predicate::Not(Box::new(read_predicate(predicate?)?)),
+    })
+}
+
+// the operations shared by the id-based and capability-based interfaces
+async fn check<A: AsyncMarket>(market: &A, id: &str) -> Result<Snapshot, ValidationError> {
+    info!("check requested by account {}", id);
+    let snapshot = market.check(id).await;
+    if let Err(error) = &snapshot {
+        error!("unable to check account {}", id);
+        error!("{:?}", error);
+    }
+    snapshot
+}
+
+async fn quote<A: AsyncMarket>(market: &A, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+    info!("quote of {} {} in {} requested by account {}", amount, buy, sell, id);
+    let result = market.quote(id, buy, sell, amount).await;
+    if let Err(error) = &result {
+        error!("unable to quote trade");
+        error!("{:?}", error);
+    }
+    result
+}
+
+// traced trades also say how far they moved the market
+async fn trade<A: 'static + AsyncMarket + Clone>(
+    market: &A,
+    id: &str,
+    buy: &str,
+    sell: &str,
+    amount: i32,
+    condition: Option<&Predicate>,
+    traced: bool,
+) -> Result<Option<HashMap<String, i32>>, ValidationError> {
+    match condition {
+        Some(condition) => info!("trade of {} {} for {} requested by account {} if {:?}", amount, buy, sell, id, condition),
+        None => info!("trade of {} {} for {} requested by account {}", amount, buy, sell, id),
+    }
+    let result = match (condition, traced) {
+        (_, true) => market.trade_changes(id, buy, sell, amount, condition).await.map(Some),
+        (Some(condition), false) => market.submit_trade_if(id, buy, sell, amount, condition).await.map(|()| None),
+        (None, false) => market.submit_trade(id, buy, sell, amount).await.map(|()| None),
+    };
+    if let Err(error) = &result {
+        error!("unable to make trade");
+        error!("{:?}", error);
+    }
+    result
+}
+
+async fn submit_bundle<A: 'static + AsyncMarket + Clone>(market: &A, id: &str, legs: &[Leg], traced: bool) -> Result<Option<HashMap<String, i32>>, ValidationError> {
+    info!("bundle of {} trades requested by account {}", legs.len(), id);
+    let result = if traced {
+        market.bundle_changes(id, legs).await.map(Some)
+    } else {
+        market.submit_bundle(id, legs).await.map(|()| None)
+    };
+    if let Err(error) = &result {
+        error!("unable to make bundle; no trades were made");
+        error!("{:?}", error);
+    }
+    result
+}
+
+// leaving for another market hands back a bundle the destination can join with
+async fn leave<A: AsyncMarket>(market: &A, id: &str, destination: Option<&str>) -> Result<(HashMap<String, i32>, Option<AccountBundle>), ValidationError> {
+    let result = match destination {
+        Some(destination) => {
+            info!("leave for {} requested by account {}", destination, id);
+            market.export_account(id, destination).await.map(|bundle| (bundle.account.clone(), Some(bundle)))
+        }
+        None => {
+            info!("leave requested by account {}", id);
+            market.remove_account(id).await.map(|account| (account, None))
+        }
+    };
+    if let Err(error) = &result {
+        error!("unable to get remove account {}", id);
+        error!("{:?}", error);
+    }
+    result
+}
+
+async fn place_order<A: AsyncMarket>(market: &A, id: &str, sell: &str, sell_amount: i32, buy: &str, buy_amount: i32) -> Result<Order, ValidationError> {
+    info!("order of {} {} -> {} {} placed by account {}", sell_amount, sell, buy_amount, buy, id);
+    let result = market.place_order(id, sell, sell_amount, buy, buy_amount).await;
+    if let Err(error) = &result {
+        error!("unable to place order");
+        error!("{:?}", error);
+    }
+    result
+}
+
+async fn cancel_order<A: AsyncMarket>(market: &A, id: &str, order: u64) -> Result<Order, ValidationError> {
+    info!("cancel of order {} requested by account {}", order, id);
+    let result = market.cancel_order(id, order).await;
+    if let Err(error) = &result {
+        error!("unable to cancel order {}", order);
+        error!("{:?}", error);
+    }
+    result
+}
+
+async fn history<A: AsyncMarket>(market: &A, id: &str, since: u64) -> Result<Vec<Fill>, ValidationError> {
+    info!("history since {} requested by account {}", since, id);
+    let result = market.history(id, since).await;
+    if let Err(error) = &result {
+        error!("unable to get history of account {}", id);
+        error!("{:?}", error);
+    }
+    result
+}
+
+// carries trade events between servers for the same market on different threads. a
+// server thread that panicked while holding the lock leaves the senders usable
+pub(crate) type EventHub = Arc<Mutex<Vec<mpsc::UnboundedSender<TradeEvent>>>>;
+
+// listeners subscribed to a market; each one is told about every trade made in it
+#[derive(Default)]
+struct Subscribers {
+    next_id: u64,
+    listeners: HashMap<u64, widget_capnp::market_listener::Client>,
+    // if set, events go through the hub so every server's subscribers hear about them
+    hub: Option<EventHub>,
+}
+
+// sends an event to every subscriber without waiting on any of them. a listener
+// that can't be reached is dropped
+fn publish(subscribers: &Rc<RefCell<Subscribers>>, event: &TradeEvent) {
+    for (&id, listener) in subscribers.borrow().listeners.iter() {
+        let mut request = listener.on_trade_request();
+        set_legs(request.get().init_legs(event.legs.len() as u32), &event.legs);
+        set_counts(request.get().init_changes(event.changes.len() as u32), &event.changes);
+        let subscribers = subscribers.clone();
+        spawn_local(async move {
+            if let Err(error) = request.send().promise.await {
+                info!("dropping subscriber {}: {}", id, error);
+                subscribers.borrow_mut().listeners.remove(&id);
+            }
+        });
+    }
+}
+
+// makes trades on the market, publishing them to subscribers if they go through. the
+// trades are traced, saying how far they moved the market as they're made, only when
+// someone might be listening
+async fn publish_trades<F: Future<Output = Result<Option<HashMap<String, i32>>, ValidationError>>>(
+    subscribers: &Rc<RefCell<Subscribers>>,
+    legs: impl FnOnce() -> Vec<Leg>,
+    make_trades: impl FnOnce(bool) -> F,
+) -> Result<(), ValidationError> {
+    let hub = subscribers.borrow().hub.clone();
+    let listening = hub.is_some() || !subscribers.borrow().listeners.is_empty();
+    if let Some(changes) = make_trades(listening).await? {
+        let event = TradeEvent { legs: legs(), changes };
+        match hub {
+            Some(hub) => hub.lock().unwrap_or_else(PoisonError::into_inner).retain(|server| server.unbounded_send(event.clone()).is_ok()),
+            None => publish(subscribers, &event),
+        }
+    }
+    Ok(())
+}
+
+// a subscription capability; the listener stays subscribed until it is dropped
+struct SubscriptionServer {
+    subscribers: Rc<RefCell<Subscribers>>,
+    id: u64,
+}
+
+impl Drop for SubscriptionServer {
+    fn drop(&mut self) {
+        info!("subscriber {} unsubscribed", self.id);
+        self.subscribers.borrow_mut().listeners.remove(&self.id);
+    }
+}
+
+impl widget_capnp::subscription::Server for SubscriptionServer {}
+
+// the calls a server is in the middle of answering, so it can stop taking new ones and
+// wait for the rest to finish before shutting down
+#[derive(Default)]
+struct Calls {
+    in_flight: Cell<usize>,
+    closed: Cell<bool>,
+    // told once nothing is in flight
+    drained: RefCell<Vec<oneshot::Sender<()>>>,
+}
+
+// counts a call as in flight until it is answered or dropped with its connection
+struct InFlight(Rc<Calls>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let calls = &self.0;
+        calls.in_flight.set(calls.in_flight.get() - 1);
+        if calls.in_flight.get() == 0 {
+            calls.drained.borrow_mut().drain(..).for_each(|waiter| {
+                let _ = waiter.send(());
+            });
+        }
+    }
+}
+
+impl Calls {
+    fn answer(self: Rc<Self>, call: impl Future<Output = Result<(), capnp::Error>> + 'static) -> Promise<(), capnp::Error> {
+        if self.closed.get() {
+            return Promise::err(capnp::Error::disconnected("market is shutting down".to_string()));
+        }
+        self.in_flight.set(self.in_flight.get() + 1);
+        let in_flight = InFlight(self);
+        Promise::from_future(async move {
+            let _in_flight = in_flight;
+            call.await
+        })
+    }
+
+    // turns away any new calls and waits for the ones in flight
+    async fn drain(&self) {
+        self.closed.set(true);
+        if self.in_flight.get() > 0 {
+            info!("waiting on {} calls", self.in_flight.get());
+            let (waiter, drained) = oneshot::channel();
+            self.drained.borrow_mut().push(waiter);
+            let _ = drained.await;
+        }
+    }
+}
+
+// in recording mode, logs a call that reached the market along with its outcome
+fn record<T: Clone>(recorder: &Option<Rc<Recorder>>, call: impl FnOnce() -> Call, result: &Result<T, ValidationError>)
+where
+    Result<T, ValidationError>: Into<Outcome>,
+{
+    if let Some(recorder) = recorder {
+        if let Err(error) = recorder.record(call(), result.clone().into()) {
+            error!("unable to record call: {}", error);
+        }
+    }
+}
+
+// serves a market to any number of connections
+pub struct MarketServer<A: AsyncMarket> {
+    market: A,
+    recorder: Option<Rc<Recorder>>,
+    subscribers: Rc<RefCell<Subscribers>>,
+    calls: Rc<Calls>,
+    max_connections: Option<usize>,
+}
+
+impl <M: Market + Clone> MarketServer<SyncMarket<M>> {
+    pub fn new(market: M) -> MarketServer<SyncMarket<M>> {
+        MarketServer::from_async(SyncMarket::new(market))
+    }
+}
+
+impl <A: AsyncMarket> MarketServer<A> {
+    // serves a market that answers with futures
+    pub fn from_async(market: A) -> MarketServer<A> {
+        MarketServer { market, recorder: None, subscribers: Default::default(), calls: Default::default(), max_connections: None }
+    }
+
+    // records every call the market answers so the session can be replayed later
+    pub fn recording(mut self, recorder: Recorder) -> MarketServer<A> {
+        self.recorder = Some(Rc::new(recorder));
+        self
+    }
+
+    // turns away new connections while this many are open
+    pub fn with_max_connections(mut self, limit: usize) -> MarketServer<A> {
+        self.max_connections = Some(limit);
+        self
+    }
+
+    // shares trade events with every other server on the hub, and tells this server's
+    // subscribers about the trades made through all of them. this has to be called
+    // from inside the LocalSet the server runs on
+    pub(crate) fn sharing_events(self, hub: EventHub) -> MarketServer<A> {
+        let (sender, mut events) = mpsc::unbounded();
+        hub.lock().unwrap_or_else(PoisonError::into_inner).push(sender);
+        self.subscribers.borrow_mut().hub = Some(hub);
+        let subscribers = self.subscribers.clone();
+        spawn_local(async move {
+            while let Some(event) = events.next().await {
+                publish(&subscribers, &event);
+            }
+        });
+        self
+    }
+}
+
+// an account capability; holding it is the only authority needed to act on the account
+struct AccountServer<A: AsyncMarket> {
+    market: A,
+    recorder: Option<Rc<Recorder>>,
+    subscribers: Rc<RefCell<Subscribers>>,
+    calls: Rc<Calls>,
+    id: String,
+}
+
+impl <A: 'static + AsyncMarket + Clone> widget_capnp::market::Server for MarketServer<A> {
     fn join(&mut self, params: widget_capnp::market::JoinParams, mut results: widget_capnp::market::JoinResults) -> Promise<(), capnp::Error> {
         info!("join requested");
 
         let request = pry!(params.get());
-        if request.has_account() {
-            let account: HashMap<String, i32> = request
-                .get_account()
-                .unwrap()
-                .iter()
-                .map(|c| (c.get_widget().unwrap().to_string(), c.get_count()))
-                .collect();
-            match self.add_account(account) {
+        let call = if request.has_bundle() {
+            let bundle = pry!(read_bundle(pry!(request.get_bundle())));
+            info!("account arriving from {}", bundle.origin);
+            Call::Join { account: None, bundle: Some(bundle) }
+        } else if request.has_account() {
+            Call::Join { account: Some(pry!(read_counts(pry!(request.get_account())))), bundle: None }
+        } else {
+            Call::Join { account: None, bundle: None }
+        };
+        let (market, recorder, subscribers, calls) = (self.market.clone(), self.recorder.clone(), self.subscribers.clone(), self.calls.clone());
+        calls.clone().answer(async move {
+            let joined = match &call {
+                Call::Join { bundle: Some(bundle), .. } => market.import_account(bundle).await,
+                Call::Join { account: Some(account), .. } => market.add_account(account.clone()).await,
+                _ => market.create_account().await,
+            };
+            record(&recorder, || call, &joined);
+            match joined {
                 Ok(id) => {
                     info!("added account {}", id);
-                    results.get().set_id(&id);
-                    Promise::ok(())
+                    let mut results = results.get();
+                    if legacy_ids_enabled() {
+                        results.set_id(&id);
+                    }
+                    results.set_account(capnp_rpc::new_client(AccountServer { market, recorder, subscribers, calls, id }));
                 }
                 Err(error) => {
                     error!("unable to add account");
                     error!("{:?}", error);
-                    Promise::err(capnp::Error::failed(format!("{:?}", error)))
+                    set_error(&error, results.get().init_error());
                 }
             }
-        } else {
-            match self.create_account() {
-                Ok(id) => {
-                    info!("created account {}", id);
-                    results.get().set_id(&id);
-                    Promise::ok(())
+            Ok(())
+        })
+    }
+
+    fn check(&mut self, params: widget_capnp::market::CheckParams, mut results: widget_capnp::market::CheckResults) -> Promise<(), capnp::Error> {
+        if !legacy_ids_enabled() {
+            set_error(&legacy_ids_disabled(), results.get().init_error());
+            return Promise::ok(());
+        }
+        let id = pry!(pry!(params.get()).get_id()).to_string();
+
+        let (market, recorder, calls) = (self.market.clone(), self.recorder.clone(), self.calls.clone());
+        calls.answer(async move {
+            let snapshot = check(&market, &id).await;
+            record(&recorder, || Call::Check { id }, &snapshot);
+            let mut results = results.get();
+            match snapshot {
+                Ok(snapshot) => {
+                    set_counts(results.reborrow().init_market(snapshot.market.len() as u32), &snapshot.market);
+                    set_counts(results.reborrow().init_account(snapshot.account.len() as u32), &snapshot.account);
+                    set_quotes(results.reborrow().init_quotes(snapshot.quotes.len() as u32), &snapshot.quotes);
                 }
-                Err(error) => {
-                    error!("unable to create account");
-                    error!("{:?}", error);
-                    Promise::err(capnp::Error::failed(format!("{:?}", error)))
+                Err(error) => set_error(&error, results.init_error()),
+            }
+            Ok(())
+        })
+    }
+
+    fn trade(&mut self, params: widget_capnp::market::TradeParams, mut results: widget_capnp::market::TradeResults) -> Promise<(), capnp::Error> {
+        if !legacy_ids_enabled() {
+            set_error(&legacy_ids_disabled(), results.get().init_error());
+            return Promise::ok(());
+        }
+        // grab the params
+        let params = pry!(params.get());
+        let id = pry!(params.get_id()).to_string();
+        let buy = pry!(params.get_buy()).to_string();
+        let sell = pry!(params.get_sell()).to_string();
+        let amount = params.get_amount();
+        let condition = if params.has_condition() {
+            Some(pry!(read_predicate(pry!(params.get_condition()))))
+        } else {
+            None
+        };
+
+        let (market, recorder, subscribers, calls) = (self.market.clone(), self.recorder.clone(), self.subscribers.clone(), self.calls.clone());
+        calls.answer(async move {
+            let legs = || vec![Leg { buy: buy.clone(), sell: sell.clone(), amount }];
+            let result = publish_trades(&subscribers, legs, |traced| {
+                trade(&market, &id, &buy, &sell, amount, condition.as_ref(), traced)
+            }).await;
+            record(&recorder, || Call::Trade { id, buy, sell, amount, condition }, &result);
+            if let Err(error) = result {
+                set_error(&error, results.get().init_error());
+            }
+            Ok(())
+        })
+    }
+
+    fn leave(&mut self, params: widget_capnp::market::LeaveParams, mut results: widget_capnp::market::LeaveResults) -> Promise<(), capnp::Error> {
+        if !legacy_ids_enabled() {
+            set_error(&legacy_ids_disabled(), results.get().init_error());
+            return Promise::ok(());
+        }
+        let params = pry!(params.get());
+        let id = pry!(params.get_id()).to_string();
+        let destination = pry!(params.get_destination());
+        let destination = if destination.is_empty() { None } else { Some(destination.to_string()) };
+
+        let (market, recorder, calls) = (self.market.clone(), self.recorder.clone(), self.calls.clone());
+        calls.answer(async move {
+            let result = leave(&market, &id, destination.as_deref()).await;
+            record(&recorder, || Call::Leave { id, destination }, &result);
+            match result {
+                Ok((account, bundle)) => {
+                    set_counts(results.get().init_account(account.len() as u32), &account);
+                    if let Some(bundle) = bundle {
+                        set_bundle(results.get().init_bundle(), &bundle);
+                    }
                 }
+                Err(error) => set_error(&error, results.get().init_error()),
             }
+            Ok(())
+        })
+    }
+
+    fn place_order(&mut self, params: widget_capnp::market::PlaceOrderParams, mut results: widget_capnp::market::PlaceOrderResults) -> Promise<(), capnp::Error> {
+        if !legacy_ids_enabled() {
+            set_error(&legacy_ids_disabled(), results.get().init_error());
+            return Promise::ok(());
         }
+        let params = pry!(params.get());
+        let id = pry!(params.get_id()).to_string();
+        let sell = pry!(params.get_sell()).to_string();
+        let buy = pry!(params.get_buy()).to_string();
+        let (sell_amount, buy_amount) = (params.get_sell_amount(), params.get_buy_amount());
+
+        let (market, recorder, calls) = (self.market.clone(), self.recorder.clone(), self.calls.clone());
+        calls.answer(async move {
+            let result = place_order(&market, &id, &sell, sell_amount, &buy, buy_amount).await;
+            record(&recorder, || Call::PlaceOrder { id, sell, sell_amount, buy, buy_amount }, &result);
+            match result {
+                Ok(order) => set_order(results.get().init_order(), &order),
+                Err(error) => set_error(&error, results.get().init_error()),
+            }
+            Ok(())
+        })
     }
 
-    fn check(&mut self, params: widget_capnp::market::CheckParams, mut results: widget_capnp::market::CheckResults) -> Promise<(), capnp::Error> {
-        let id = pry!(params.get()).get_id().unwrap();
-        info!("check requested by account {}", id);
+    fn cancel_order(&mut self, params: widget_capnp::market::CancelOrderParams, mut results: widget_capnp::market::CancelOrderResults) -> Promise<(), capnp::Error> {
+        if !legacy_ids_enabled() {
+            set_error(&legacy_ids_disabled(), results.get().init_error());
+            return Promise::ok(());
+        }
+        let params = pry!(params.get());
+        let id = pry!(params.get_id()).to_string();
+        let order = params.get_order();
 
-        match self.get_market() {
-            Ok(market) => match self.get_account(id) {
-                Ok(account) => {
-                    let mut results = results.get();
-                    let mut builder = results.reborrow().init_market(market.len() as u32);
-                    market.iter().enumerate().for_each(|(i, (w, c))| {
-                        builder.reborrow().get(i as u32).set_widget(w);
-                        builder.reborrow().get(i as u32).set_count(*c as i32);
-                    });
-
-                    let mut builder = results.reborrow().init_account(market.len() as u32);
-                    account.iter().enumerate().for_each(|(i, (w, c))| {
-                        builder.reborrow().get(i as u32).set_widget(w);
-                        builder.reborrow().get(i as u32).set_count(*c as i32);
-                    });
-                    Promise::ok(())
+        let (market, recorder, calls) = (self.market.clone(), self.recorder.clone(), self.calls.clone());
+        calls.answer(async move {
+            let result = cancel_order(&market, &id, order).await;
+            record(&recorder, || Call::CancelOrder { id, order }, &result);
+            match result {
+                Ok(order) => set_order(results.get().init_order(), &order),
+                Err(error) => set_error(&error, results.get().init_error()),
+            }
+            Ok(())
+        })
+    }
+
+    fn quote(&mut self, params: widget_capnp::market::QuoteParams, mut results: widget_capnp::market::QuoteResults) -> Promise<(), capnp::Error> {
+        if !legacy_ids_enabled() {
+            set_error(&legacy_ids_disabled(), results.get().init_error());
+            return Promise::ok(());
+        }
+        let params = pry!(params.get());
+        let id = pry!(params.get_id()).to_string();
+        let buy = pry!(params.get_buy()).to_string();
+        let sell = pry!(params.get_sell()).to_string();
+        let amount = params.get_amount();
+
+        let (market, recorder, calls) = (self.market.clone(), self.recorder.clone(), self.calls.clone());
+        calls.answer(async move {
+            let result = quote(&market, &id, &buy, &sell, amount).await;
+            record(&recorder, || Call::Quote { id, buy, sell, amount }, &result);
+            match result {
+                Ok(quote) => set_quote(results.get().init_quote(), &quote),
+                Err(error) => set_error(&error, results.get().init_error()),
+            }
+            Ok(())
+        })
+    }
+
+    fn submit_bundle(&mut self, params: widget_capnp::market::SubmitBundleParams, mut results: widget_capnp::market::SubmitBundleResults) -> Promise<(), capnp::Error> {
+        if !legacy_ids_enabled() {
+            set_error(&legacy_ids_disabled(), results.get().init_error());
+            return Promise::ok(());
+        }
+        let params = pry!(params.get());
+        let id = pry!(params.get_id()).to_string();
+        let legs = pry!(read_legs(pry!(params.get_legs())));
+
+        let (market, recorder, subscribers, calls) = (self.market.clone(), self.recorder.clone(), self.subscribers.clone(), self.calls.clone());
+        calls.answer(async move {
+            let result = publish_trades(&subscribers, || legs.clone(), |traced| submit_bundle(&market, &id, &legs, traced)).await;
+            record(&recorder, || Call::Bundle { id, legs }, &result);
+            if let Err(error) = result {
+                set_error(&error, results.get().init_error());
+            }
+            Ok(())
+        })
+    }
+
+    fn list_orders(&mut self, _: widget_capnp::market::ListOrdersParams, mut results: widget_capnp::market::ListOrdersResults) -> Promise<(), capnp::Error> {
+        info!("order list requested");
+        let (market, recorder, calls) = (self.market.clone(), self.recorder.clone(), self.calls.clone());
+        calls.answer(async move {
+            let orders = market.get_orders().await;
+            record(&recorder, || Call::ListOrders, &orders);
+            match orders {
+                Ok(orders) => {
+                    let mut builder = results.get().init_orders(orders.len() as u32);
+                    orders.iter().enumerate().for_each(|(i, order)| set_order(builder.reborrow().get(i as u32), order));
                 }
                 Err(error) => {
-                    error!("unable to get account {}", id);
+                    error!("unable to list orders");
                     error!("{:?}", error);
-                    Promise::err(capnp::Error::failed(format!("{:?}", error)))
+                    set_error(&error, results.get().init_error());
                 }
-            },
-            Err(error) => {
-                error!("unable to get market");
-                error!("{:?}", error);
-                Promise::err(capnp::Error::failed(format!("{:?}", error)))
             }
+            Ok(())
+        })
+    }
+
+    fn history(&mut self, params: widget_capnp::market::HistoryParams, mut results: widget_capnp::market::HistoryResults) -> Promise<(), capnp::Error> {
+        if !legacy_ids_enabled() {
+            set_error(&legacy_ids_disabled(), results.get().init_error());
+            return Promise::ok(());
         }
+        let params = pry!(params.get());
+        let id = pry!(params.get_id()).to_string();
+        let since = params.get_since();
+
+        let (market, recorder, calls) = (self.market.clone(), self.recorder.clone(), self.calls.clone());
+        calls.answer(async move {
+            let result = history(&market, &id, since).await;
+            record(&recorder, || Call::History { id, since }, &result);
+            match result {
+                Ok(fills) => set_fills(results.get().init_fills(fills.len() as u32), &fills),
+                Err(error) => set_error(&error, results.get().init_error()),
+            }
+            Ok(())
+        })
     }
 
-    fn trade(&mut self, params: widget_capnp::market::TradeParams, _: widget_capnp::market::TradeResults) -> Promise<(), capnp::Error> {
-        // grab the params
+    fn subscribe(&mut self, params: widget_capnp::market::SubscribeParams, mut results: widget_capnp::market::SubscribeResults) -> Promise<(), capnp::Error> {
+        let listener = pry!(pry!(params.get()).get_listener());
+        let mut subscribers = self.subscribers.borrow_mut();
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.listeners.insert(id, listener);
+        info!("subscriber {} subscribed", id);
+        results.get().set_subscription(capnp_rpc::new_client(SubscriptionServer { subscribers: self.subscribers.clone(), id }));
+        Promise::ok(())
+    }
+}
+
+impl <A: 'static + AsyncMarket + Clone> widget_capnp::account::Server for AccountServer<A> {
+    fn check(&mut self, _: widget_capnp::account::CheckParams, mut results: widget_capnp::account::CheckResults) -> Promise<(), capnp::Error> {
+        let (market, recorder, calls, id) = (self.market.clone(), self.recorder.clone(), self.calls.clone(), self.id.clone());
+        calls.answer(async move {
+            let snapshot = check(&market, &id).await;
+            record(&recorder, || Call::Check { id }, &snapshot);
+            let mut results = results.get();
+            match snapshot {
+                Ok(snapshot) => {
+                    set_counts(results.reborrow().init_market(snapshot.market.len() as u32), &snapshot.market);
+                    set_counts(results.reborrow().init_account(snapshot.account.len() as u32), &snapshot.account);
+                    set_quotes(results.reborrow().init_quotes(snapshot.quotes.len() as u32), &snapshot.quotes);
+                }
+                Err(error) => set_error(&error, results.init_error()),
+            }
+            Ok(())
+        })
+    }
+
+    fn trade(&mut self, params: widget_capnp::account::TradeParams, mut results: widget_capnp::account::TradeResults) -> Promise<(), capnp::Error> {
         let params = pry!(params.get());
-        let id = params.get_id().unwrap();
-        let buy = params.get_buy().unwrap();
-        let sell = params.get_sell().unwrap();
-        info!("trade of {} -> {} requested by account {}", buy, sell, id);
+        let buy = pry!(params.get_buy()).to_string();
+        let sell = pry!(params.get_sell()).to_string();
+        let amount = params.get_amount();
+        let condition = if params.has_condition() {
+            Some(pry!(read_predicate(pry!(params.get_condition()))))
+        } else {
+            None
+        };
 
-        match self.submit_trade(id, buy, sell) {
-            Ok(()) => Promise::ok(()),
-            Err(error) => {
-                error!("unable to make trade");
-                error!("{:?}", error);
-                Promise::err(capnp::Error::failed(format!("{:?}", error)))
+        let (market, recorder, subscribers, calls, id) = (self.market.clone(), self.recorder.clone(), self.subscribers.clone(), self.calls.clone(), self.id.clone());
+        calls.answer(async move {
+            let legs = || vec![Leg { buy: buy.clone(), sell: sell.clone(), amount }];
+            let result = publish_trades(&subscribers, legs, |traced| {
+                trade(&market, &id, &buy, &sell, amount, condition.as_ref(), traced)
+            }).await;
+            record(&recorder, || Call::Trade { id, buy, sell, amount, condition }, &result);
+            if let Err(error) = result {
+                set_error(&error, results.get().init_error());
             }
-        }
+            Ok(())
+        })
     }
 
-    fn leave(&mut self, params: widget_capnp::market::LeaveParams, mut results: widget_capnp::market::LeaveResults) -> Promise<(), capnp::Error> {
-        let id = pry!(params.get()).get_id().unwrap();
-        info!("leave requested by account {}", id);
-        match self.remove_account(id) {
-            Ok(account) => {
-                let mut results = results.get();
-                let mut builder = results.reborrow().init_account(account.len() as u32);
-                account.iter().enumerate().for_each(|(i, (w, c))| {
-                    builder.reborrow().get(i as u32).set_widget(w);
-                    builder.reborrow().get(i as u32).set_count(*c as i32);
-                });
-                Promise::ok(())
+    fn leave(&mut self, params: widget_capnp::account::LeaveParams, mut results: widget_capnp::account::LeaveResults) -> Promise<(), capnp::Error> {
+        let destination = pry!(pry!(params.get()).get_destination());
+        let destination = if destination.is_empty() { None } else { Some(destination.to_string()) };
+
+        let (market, recorder, calls, id) = (self.market.clone(), self.recorder.clone(), self.calls.clone(), self.id.clone());
+        calls.answer(async move {
+            let result = leave(&market, &id, destination.as_deref()).await;
+            record(&recorder, || Call::Leave { id, destination }, &result);
+            match result {
+                Ok((account, bundle)) => {
+                    set_counts(results.get().init_account(account.len() as u32), &account);
+                    if let Some(bundle) = bundle {
+                        set_bundle(results.get().init_bundle(), &bundle);
+                    }
+                }
+                Err(error) => set_error(&error, results.get().init_error()),
             }
-            Err(error) => {
-                error!("unable to get remove account {}", id);
-                error!("{:?}", error);
-                Promise::err(capnp::Error::failed(format!("{:?}", error)))
+            Ok(())
+        })
+    }
+
+    fn place_order(&mut self, params: widget_capnp::account::PlaceOrderParams, mut results: widget_capnp::account::PlaceOrderResults) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let sell = pry!(params.get_sell()).to_string();
+        let buy = pry!(params.get_buy()).to_string();
+        let (sell_amount, buy_amount) = (params.get_sell_amount(), params.get_buy_amount());
+
+        let (market, recorder, calls, id) = (self.market.clone(), self.recorder.clone(), self.calls.clone(), self.id.clone());
+        calls.answer(async move {
+            let result = place_order(&market, &id, &sell, sell_amount, &buy, buy_amount).await;
+            record(&recorder, || Call::PlaceOrder { id, sell, sell_amount, buy, buy_amount }, &result);
+            match result {
+                Ok(order) => set_order(results.get().init_order(), &order),
+                Err(error) => set_error(&error, results.get().init_error()),
             }
-        }
+            Ok(())
+        })
     }
+
+    fn quote(&mut self, params: widget_capnp::account::QuoteParams, mut results: widget_capnp::account::QuoteResults) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let buy = pry!(params.get_buy()).to_string();
+        let sell = pry!(params.get_sell()).to_string();
+        let amount = params.get_amount();
+
+        let (market, recorder, calls, id) = (self.market.clone(), self.recorder.clone(), self.calls.clone(), self.id.clone());
+        calls.answer(async move {
+            let result = quote(&market, &id, &buy, &sell, amount).await;
+            record(&recorder, || Call::Quote { id, buy, sell, amount }, &result);
+            match result {
+                Ok(quote) => set_quote(results.get().init_quote(), &quote),
+                Err(error) => set_error(&error, results.get().init_error()),
+            }
+            Ok(())
+        })
+    }
+
+    fn cancel_order(&mut self, params: widget_capnp::account::CancelOrderParams, mut results: widget_capnp::account::CancelOrderResults) -> Promise<(), capnp::Error> {
+        let order = pry!(params.get()).get_order();
+
+        let (market, recorder, calls, id) = (self.market.clone(), self.recorder.clone(), self.calls.clone(), self.id.clone());
+        calls.answer(async move {
+            let result = cancel_order(&market, &id, order).await;
+            record(&recorder, || Call::CancelOrder { id, order }, &result);
+            match result {
+                Ok(order) => set_order(results.get().init_order(), &order),
+                Err(error) => set_error(&error, results.get().init_error()),
+            }
+            Ok(())
+        })
+    }
+
+    fn submit_bundle(&mut self, params: widget_capnp::account::SubmitBundleParams, mut results: widget_capnp::account::SubmitBundleResults) -> Promise<(), capnp::Error> {
+        let legs = pry!(read_legs(pry!(pry!(params.get()).get_legs())));
+
+        let (market, recorder, subscribers, calls, id) = (self.market.clone(), self.recorder.clone(), self.subscribers.clone(), self.calls.clone(), self.id.clone());
+        calls.answer(async move {
+            let result = publish_trades(&subscribers, || legs.clone(), |traced| submit_bundle(&market, &id, &legs, traced)).await;
+            record(&recorder, || Call::Bundle { id, legs }, &result);
+            if let Err(error) = result {
+                set_error(&error, results.get().init_error());
+            }
+            Ok(())
+        })
+    }
+
+    fn history(&mut self, params: widget_capnp::account::HistoryParams, mut results: widget_capnp::account::HistoryResults) -> Promise<(), capnp::Error> {
+        let since = pry!(params.get()).get_since();
+
+        let (market, recorder, calls, id) = (self.market.clone(), self.recorder.clone(), self.calls.clone(), self.id.clone());
+        calls.answer(async move {
+            let result = history(&market, &id, since).await;
+            record(&recorder, || Call::History { id, since }, &result);
+            match result {
+                Ok(fills) => set_fills(results.get().init_fills(fills.len() as u32), &fills),
+                Err(error) => set_error(&error, results.get().init_error()),
+            }
+            Ok(())
+        })
+    }
+}
+
+pub async fn run<M: 'static + Market + Clone>(addr: SocketAddr, market: M) -> Result<(), Box<dyn std::error::Error>> {
+    run_server(addr, MarketServer::new(market)).await
 }
 
-pub async fn run<M: 'static + Market>(addr: SocketAddr, market: M) -> Result<(), Box<dyn std::error::Error>> {
+// like run, but with a server that was already set up, e.g. to record or to serve an async market
+pub async fn run_server<A: 'static + AsyncMarket + Clone>(addr: SocketAddr, server: MarketServer<A>) -> Result<(), Box<dyn std::error::Error>> {
+    let connections = Connections::limited(server.max_connections);
+    let widget_client: widget_capnp::market::Client = capnp_rpc::new_client(server);
+    LocalSet::new().run_until(accept(addr, &widget_client.client, &connections, future::pending())).await
+}
+
+// like run, but stops once shutdown resolves. the server stops accepting connections,
+// finishes the calls it is answering and then settles every account left in the market
+// into the settlement directory (see settlement::settle)
+pub async fn run_until<M: 'static + Market + Clone>(
+    addr: SocketAddr,
+    market: M,
+    shutdown: impl Future<Output = ()>,
+    settlement: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    run_server_until(addr, MarketServer::new(market), shutdown, settlement).await
+}
+
+// like run_until, but with a server that was already set up
+pub async fn run_server_until<A: 'static + AsyncMarket + Clone>(
+    addr: SocketAddr,
+    server: MarketServer<A>,
+    shutdown: impl Future<Output = ()>,
+    settlement: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (market, calls) = (server.market.clone(), server.calls.clone());
+    let connections = Connections::limited(server.max_connections);
+    let widget_client: widget_capnp::market::Client = capnp_rpc::new_client(server);
     LocalSet::new()
         .run_until(async move {
-            let listener = TcpListener::bind(&addr).await?;
-            let widget_client: widget_capnp::market::Client = capnp_rpc::new_client(market);
-
-            info!("started server at {}", addr);
-            loop {
-                let (stream, _) = listener.accept().await?;
-                stream.set_nodelay(true)?;
-                let (reader, writer) = TokioAsyncReadCompatExt::compat(stream).split();
-                let network = twoparty::VatNetwork::new(
-                    reader,
-                    writer,
-                    rpc_twoparty_capnp::Side::Server,
-                    Default::default(),
-                );
-                let rpc_system =
-                    RpcSystem::new(Box::new(network), Some(widget_client.clone().client));
-
-                spawn_local(Box::pin(rpc_system.map(|_| ())));
-            }
+            accept(addr, &widget_client.client, &connections, shutdown).await?;
+            info!("shutting down");
+            calls.drain().await;
+            // give the connections a turn to send the last answers
+            tokio::task::yield_now().await;
+            settlement::settle(&market, settlement).await?;
+            Ok(())
         })
         .await
 }
+
+// resolves on the first ctrl-c or, on unix, sigterm
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(error) => {
+                error!("unable to listen for sigterm: {}", error);
+                return wait_for_ctrl_c().await;
+            }
+        };
+        tokio::select! {
+            _ = wait_for_ctrl_c() => {}
+            _ = terminate.recv() => info!("received sigterm"),
+        }
+    }
+    #[cfg(not(unix))]
+    wait_for_ctrl_c().await
+}
+
+async fn wait_for_ctrl_c() {
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => info!("received ctrl-c"),
+        // without the signal the server can't be asked to stop, so it keeps running
+        Err(error) => {
+            error!("unable to listen for ctrl-c: {}", error);
+            future::pending().await
+        }
+    }
+}
+
+// accepts connections forever, handing each one the bootstrap capability
+pub(crate) async fn serve(addr: SocketAddr, bootstrap: capnp::capability::Client) -> Result<(), Box<dyn std::error::Error>> {
+    LocalSet::new().run_until(accept(addr, &bootstrap, &Connections::default(), future::pending())).await
+}
+
+// accepts connections on the current LocalSet until shutdown resolves
+async fn accept(
+    addr: SocketAddr,
+    bootstrap: &capnp::capability::Client,
+    connections: &Connections,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(&addr).await?;
+
+    info!("started server at {}", addr);
+    futures::pin_mut!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                match connections.open() {
+                    Some(connection) => connect(stream, bootstrap, connection)?,
+                    None => info!("turning away {}; too many connections are open", peer),
+                }
+            }
+            _ = &mut shutdown => return Ok(()),
+        }
+    }
+}
+
+// counts a server's open connections so it can turn new ones away past a limit
+#[derive(Clone, Default)]
+pub(crate) struct Connections {
+    open: Arc<AtomicUsize>,
+    limit: Option<usize>,
+}
+
+// holds a connection's place until it closes
+pub(crate) struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Connections {
+    pub(crate) fn limited(limit: Option<usize>) -> Connections {
+        Connections { open: Default::default(), limit }
+    }
+
+    // takes a place for a new connection, if the limit allows another
+    pub(crate) fn open(&self) -> Option<OpenConnection> {
+        let open = self.open.fetch_add(1, Ordering::SeqCst);
+        let connection = OpenConnection(self.open.clone());
+        match self.limit {
+            Some(limit) if open >= limit => None,
+            _ => Some(connection),
+        }
+    }
+}
+
+// runs the rpc system for a new connection on the current LocalSet
+pub(crate) fn connect(stream: TcpStream, bootstrap: &capnp::capability::Client, connection: OpenConnection) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+    spawn_local(answer_stream(stream, bootstrap).map(move |_| drop(connection)));
+    Ok(())
+}
+
+// answers rpcs over any stream until it closes
+pub(crate) fn answer_stream<S>(stream: S, bootstrap: &capnp::capability::Client) -> impl Future<Output = ()>
+where
+    S: 'static + tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (reader, writer) = TokioAsyncReadCompatExt::compat(stream).split();
+    let network = twoparty::VatNetwork::new(
+        reader,
+        writer,
+        rpc_twoparty_capnp::Side::Server,
+        Default::default(),
+    );
+    let rpc_system =
+        RpcSystem::new(Box::new(network), Some(capnp::capability::Client::new(bootstrap.hook.add_ref())));
+
+    rpc_system.map(|_| ())
+}
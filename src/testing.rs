@@ -0,0 +1,948 @@
+// a conformance suite that any market can run against itself
+//
+// each check runs on its own copy of the market it's given, which should be fresh and
+// have no accounts yet, and is reported on its own so one failure doesn't hide the rest.
+// the checks only use the Market trait, so they trade between the market's own widgets
+// with amounts of 1; a check that needs a trade to go through is skipped if none will.
+//
+//  - unknown accounts: every account operation rejects an id the market never handed out
+//  - widget conservation: a trade moves widgets between the market and the account
+//    without making or losing any
+//  - added accounts: widgets the market doesn't trade are dropped from added accounts
+//  - removed accounts: removing an account returns its final balances and forgets it
+//  - negative balances: an account can't join owing widgets or trade widgets it doesn't have
+//
+// there is also a property test that makes random sequences of account and trade calls
+// and checks after every one that no widgets were made or lost and no count went
+// negative. a failing sequence is shrunk down to a short one that still fails
+//
+// for testing the whole rpc path, an InProcessServer connects clients to a market server
+// over in-memory streams set up exactly like tcp connections, so tests don't need ports
+// and can run in parallel. fuzz_server pushes the same path harder, decoding arbitrary
+// bytes into calls and raw capnp params and checking the server never panics and the
+// market it serves stays consistent
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+use capnp::message::{self, ReaderOptions, SegmentArray};
+use capnp::{any_pointer, Word};
+use futures::Future;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::task::{spawn_local, LocalSet};
+
+use crate::async_market::{AsyncMarket, SyncMarket};
+use crate::client::{self, WidgetMarketClient};
+use crate::market::{ErrorCode, Market};
+use crate::single_market::{self, MarketServer};
+use crate::widget_capnp;
+
+const UNKNOWN_ID: &str = "not-an-account";
+const UNKNOWN_WIDGET: &str = "not-a-widget";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Passed,
+    // the check couldn't be made against this market, and why
+    Skipped(String),
+    Failed(String),
+}
+
+// how one check went
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub name: &'static str,
+    pub verdict: Verdict,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.verdict {
+            Verdict::Passed => write!(f, "{}: passed", self.name),
+            Verdict::Skipped(reason) => write!(f, "{}: skipped ({})", self.name, reason),
+            Verdict::Failed(reason) => write!(f, "{}: failed ({})", self.name, reason),
+        }
+    }
+}
+
+// a check gives a verdict on the market, or says what went wrong
+type Check<M> = fn(&mut M) -> Result<Verdict, String>;
+
+// every check in the suite along with its name
+pub fn checks<M: Market>() -> Vec<(&'static str, Check<M>)> {
+    vec![
+        ("unknown accounts", unknown_accounts),
+        ("widget conservation", widget_conservation),
+        ("added accounts", added_accounts),
+        ("removed accounts", removed_accounts),
+        ("negative balances", negative_balances),
+    ]
+}
+
+// runs every check on its own copy of the market. a market that panics fails that
+// check without stopping the rest
+pub fn run_conformance<M: Market + Clone>(market: &M) -> Vec<Report> {
+    checks::<M>()
+        .into_iter()
+        .map(|(name, check)| {
+            let checked = panic::catch_unwind(AssertUnwindSafe(|| check(&mut market.clone())));
+            let verdict = checked.unwrap_or_else(|_| Err("market panicked".to_string())).unwrap_or_else(Verdict::Failed);
+            Report { name, verdict }
+        })
+        .collect()
+}
+
+// runs every check, panicking with all of the failures if there are any
+pub fn assert_conformance<M: Market + Clone>(market: &M) {
+    let failures: Vec<String> = run_conformance(market)
+        .iter()
+        .filter(|report| matches!(report.verdict, Verdict::Failed(_)))
+        .map(Report::to_string)
+        .collect();
+    assert!(failures.is_empty(), "market doesn't conform:\n{}", failures.join("\n"));
+}
+
+fn ensure(condition: bool, failure: impl FnOnce() -> String) -> Result<(), String> {
+    if condition {
+        Ok(())
+    } else {
+        Err(failure())
+    }
+}
+
+fn widgets<M: Market>(market: &M) -> Result<Vec<String>, String> {
+    let counts = market.get_market().map_err(|error| format!("get_market failed: {}", error))?;
+    Ok(counts.keys().cloned().collect::<BTreeSet<_>>().into_iter().collect())
+}
+
+fn account<M: Market>(market: &M, id: &str) -> Result<HashMap<String, i32>, String> {
+    market.get_account(id).cloned().map_err(|error| format!("get_account({}) failed: {}", id, error))
+}
+
+fn create_account<M: Market>(market: &mut M) -> Result<String, String> {
+    market.create_account().map_err(|error| format!("create_account failed: {}", error))
+}
+
+// every ordered pair of different widgets
+fn pairs(widgets: &[String]) -> Vec<(&str, &str)> {
+    let mut pairs = Vec::new();
+    for buy in widgets {
+        for sell in widgets.iter().filter(|&sell| sell != buy) {
+            pairs.push((buy.as_str(), sell.as_str()));
+        }
+    }
+    pairs
+}
+
+// makes the first trade of 1 widget that goes through, returning the pair
+fn any_trade<M: Market>(market: &mut M, id: &str) -> Result<Option<(String, String)>, String> {
+    for (buy, sell) in pairs(&widgets(market)?) {
+        if market.submit_trade(id, buy, sell, 1).is_ok() {
+            return Ok(Some((buy.to_string(), sell.to_string())));
+        }
+    }
+    Ok(None)
+}
+
+fn unknown_accounts<M: Market>(market: &mut M) -> Result<Verdict, String> {
+    let widgets = widgets(market)?;
+    let (buy, sell) = pairs(&widgets).first().copied().unwrap_or(("foo", "bar"));
+    let rejected = [
+        ("get_account", market.get_account(UNKNOWN_ID).map(|_| ())),
+        ("quote", market.quote(UNKNOWN_ID, buy, sell, 1).map(|_| ())),
+        ("submit_trade", market.submit_trade(UNKNOWN_ID, buy, sell, 1)),
+        ("remove_account", market.remove_account(UNKNOWN_ID).map(|_| ())),
+    ];
+    for (operation, result) in rejected.iter() {
+        match result {
+            Err(error) if error.code() == ErrorCode::UnknownAccount => {}
+            Err(error) => return Err(format!("{} rejected an unknown account with {:?}", operation, error.code())),
+            Ok(()) => return Err(format!("{} accepted an unknown account", operation)),
+        }
+    }
+    Ok(Verdict::Passed)
+}
+
+fn widget_conservation<M: Market>(market: &mut M) -> Result<Verdict, String> {
+    let id = create_account(market)?;
+    let totals = |market: &M| -> Result<HashMap<String, i32>, String> {
+        let mut totals = market.get_market().map_err(|error| format!("get_market failed: {}", error))?.clone();
+        for (widget, count) in account(market, &id)? {
+            *totals.entry(widget).or_insert(0) += count;
+        }
+        Ok(totals)
+    };
+    let before = totals(market)?;
+    let (buy, sell) = match any_trade(market, &id)? {
+        Some(pair) => pair,
+        None => return Ok(Verdict::Skipped("no trade between the market's widgets went through".to_string())),
+    };
+    let after = totals(market)?;
+    for widget in before.keys().chain(after.keys()).collect::<BTreeSet<_>>() {
+        let (was, is) = (before.get(widget).copied().unwrap_or(0), after.get(widget).copied().unwrap_or(0));
+        ensure(was == is, || format!("buying {} with {} changed the total {} from {} to {}", buy, sell, widget, was, is))?;
+    }
+    Ok(Verdict::Passed)
+}
+
+fn added_accounts<M: Market>(market: &mut M) -> Result<Verdict, String> {
+    let mut provided: HashMap<String, i32> = widgets(market)?.into_iter().map(|widget| (widget, 3)).collect();
+    provided.insert(UNKNOWN_WIDGET.to_string(), 3);
+    let id = market.add_account(provided.clone()).map_err(|error| format!("add_account failed: {}", error))?;
+    let added = account(market, &id)?;
+    ensure(!added.contains_key(UNKNOWN_WIDGET), || format!("added account kept {}", UNKNOWN_WIDGET))?;
+    for (widget, count) in provided.iter().filter(|(widget, _)| *widget != UNKNOWN_WIDGET) {
+        let kept = added.get(widget).copied().unwrap_or(0);
+        ensure(kept == *count, || format!("added account has {} {} instead of {}", kept, widget, count))?;
+    }
+    Ok(Verdict::Passed)
+}
+
+fn removed_accounts<M: Market>(market: &mut M) -> Result<Verdict, String> {
+    let id = create_account(market)?;
+    any_trade(market, &id)?;
+    let last = account(market, &id)?;
+    let removed = market.remove_account(&id).map_err(|error| format!("remove_account failed: {}", error))?;
+    ensure(removed == last, || format!("remove_account returned {:?} instead of {:?}", removed, last))?;
+    ensure(market.get_account(&id).is_err(), || "removed account can still be looked up".to_string())?;
+    Ok(Verdict::Passed)
+}
+
+fn negative_balances<M: Market>(market: &mut M) -> Result<Verdict, String> {
+    let widgets = widgets(market)?;
+    let empty: HashMap<String, i32> = widgets.iter().map(|widget| (widget.clone(), 0)).collect();
+    for widget in &widgets {
+        let owing = [(widget.clone(), -1)].iter().cloned().collect();
+        ensure(market.add_account(owing).is_err(), || format!("an account joined owing a {}", widget))?;
+    }
+    let id = market.add_account(empty).map_err(|error| format!("add_account failed: {}", error))?;
+    let before = (market.get_market().map_err(|error| format!("get_market failed: {}", error))?.clone(), account(market, &id)?);
+    for (buy, sell) in pairs(&widgets) {
+        ensure(market.submit_trade(&id, buy, sell, 1).is_err(), || format!("an empty account bought {} with {}", buy, sell))?;
+    }
+    let after = (market.get_market().map_err(|error| format!("get_market failed: {}", error))?.clone(), account(market, &id)?);
+    ensure(before == after, || "rejected trades changed the market".to_string())?;
+    Ok(Verdict::Passed)
+}
+
+// a call the property test makes. accounts are picked by their place among the accounts
+// open at the time, wrapping around, so a step still makes sense once others are removed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    CreateAccount,
+    AddAccount(Vec<(String, i32)>),
+    SubmitTrade { account: usize, buy: String, sell: String, amount: i32 },
+    RemoveAccount { account: usize },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::CreateAccount => write!(f, "create_account()"),
+            Step::AddAccount(account) => write!(f, "add_account({:?})", account),
+            Step::SubmitTrade { account, buy, sell, amount } => write!(f, "submit_trade(#{}, {}, {}, {})", account, buy, sell, amount),
+            Step::RemoveAccount { account } => write!(f, "remove_account(#{})", account),
+        }
+    }
+}
+
+// a sequence of steps that breaks an invariant
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Counterexample {
+    // seed of the case the sequence was first found in
+    pub seed: u64,
+    pub steps: Vec<Step>,
+    pub failure: String,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} (case seed {}) after:", self.failure, self.seed)?;
+        self.steps.iter().enumerate().try_for_each(|(i, step)| writeln!(f, "  {}. {}", i + 1, step))
+    }
+}
+
+// runs random sequences of calls against copies of a market
+#[derive(Clone, Copy, Debug)]
+pub struct PropertyTest {
+    seed: u64,
+    cases: usize,
+    steps: usize,
+}
+
+impl Default for PropertyTest {
+    fn default() -> PropertyTest {
+        PropertyTest { seed: 0, cases: 100, steps: 30 }
+    }
+}
+
+impl PropertyTest {
+    pub fn new() -> PropertyTest {
+        PropertyTest::default()
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> PropertyTest {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_cases(mut self, cases: usize) -> PropertyTest {
+        self.cases = cases;
+        self
+    }
+
+    // the most steps in one case
+    pub fn with_steps(mut self, steps: usize) -> PropertyTest {
+        self.steps = steps;
+        self
+    }
+
+    // runs every case, returning the first failure shrunk as far as it goes
+    pub fn check<M: Market + Clone>(&self, market: &M) -> Result<(), Counterexample> {
+        let widgets = widgets(market).map_err(|failure| Counterexample { seed: self.seed, steps: Vec::new(), failure })?;
+        for case in 0..self.cases as u64 {
+            let seed = self.seed.wrapping_add(case);
+            let steps = generate(&mut StdRng::seed_from_u64(seed), &widgets, self.steps);
+            if let Err(failure) = run_steps(market, &steps) {
+                let (steps, failure) = shrink(market, steps, failure);
+                return Err(Counterexample { seed, steps, failure });
+            }
+        }
+        Ok(())
+    }
+
+    // runs every case, panicking with the shrunk failure if there is one
+    pub fn assert_holds<M: Market + Clone>(&self, market: &M) {
+        if let Err(counterexample) = self.check(market) {
+            panic!("market broke an invariant: {}", counterexample);
+        }
+    }
+}
+
+fn generate(rng: &mut StdRng, widgets: &[String], steps: usize) -> Vec<Step> {
+    // mostly the market's own widgets, with the odd one it doesn't know
+    let widget = |rng: &mut StdRng| match rng.gen_range(0..=widgets.len()) {
+        i if i < widgets.len() => widgets[i].clone(),
+        _ => UNKNOWN_WIDGET.to_string(),
+    };
+    let amount = |rng: &mut StdRng| match rng.gen_range(0..10) {
+        0 => rng.gen_range(-5..=0),
+        1 => rng.gen_range(100..10000),
+        _ => rng.gen_range(1..=20),
+    };
+    (0..rng.gen_range(1..=steps.max(1)))
+        .map(|_| match rng.gen_range(0..10) {
+            0..=1 => Step::CreateAccount,
+            // accounts are only brought in with what they could really have
+            2 => Step::AddAccount((0..rng.gen_range(0..=widgets.len() + 1)).map(|_| (widget(rng), amount(rng).max(0))).collect()),
+            3 => Step::RemoveAccount { account: rng.gen_range(0..4) },
+            _ => Step::SubmitTrade { account: rng.gen_range(0..4), buy: widget(rng), sell: widget(rng), amount: amount(rng) },
+        })
+        .collect()
+}
+
+// every widget in the market and in open accounts
+fn holdings<M: Market>(market: &M, ids: &[String]) -> Result<HashMap<String, i32>, String> {
+    let mut totals = market.get_market().map_err(|error| format!("get_market failed: {}", error))?.clone();
+    for id in ids {
+        for (widget, count) in account(market, id)? {
+            ensure(count >= 0, || format!("account {} has {} {}", id, count, widget))?;
+            *totals.entry(widget).or_insert(0) += count;
+        }
+    }
+    Ok(totals)
+}
+
+// makes the calls on a copy of the market, checking the invariants after each one.
+// widgets only come and go with accounts, so the holdings are expected to change by
+// exactly what joined or left
+fn run_steps<M: Market + Clone>(market: &M, steps: &[Step]) -> Result<(), String> {
+    let mut market = market.clone();
+    let mut ids: Vec<String> = Vec::new();
+    let mut expected = holdings(&market, &ids)?;
+    for (i, step) in steps.iter().enumerate() {
+        let made = panic::catch_unwind(AssertUnwindSafe(|| make_step(&mut market, &mut ids, &mut expected, step)));
+        let fail = |failure: String| format!("step {} ({}): {}", i + 1, step, failure);
+        made.map_err(|_| fail("market panicked".to_string()))?.map_err(fail)?;
+
+        let held = holdings(&market, &ids).map_err(fail)?;
+        if let Some((widget, count)) = held.iter().find(|(_, &count)| count < 0) {
+            return Err(fail(format!("market has {} {}", count, widget)));
+        }
+        conserved(&expected, &held).map_err(fail)?;
+    }
+    Ok(())
+}
+
+fn conserved(expected: &HashMap<String, i32>, held: &HashMap<String, i32>) -> Result<(), String> {
+    for widget in expected.keys().chain(held.keys()).collect::<BTreeSet<_>>() {
+        let (should, does) = (expected.get(widget).copied().unwrap_or(0), held.get(widget).copied().unwrap_or(0));
+        ensure(should == does, || format!("there should be {} {} but there are {}", should, widget, does))?;
+    }
+    Ok(())
+}
+
+fn make_step<M: Market>(market: &mut M, ids: &mut Vec<String>, expected: &mut HashMap<String, i32>, step: &Step) -> Result<(), String> {
+    let mut change = |account: &HashMap<String, i32>, sign: i32| {
+        account.iter().for_each(|(widget, count)| *expected.entry(widget.clone()).or_insert(0) += sign * count)
+    };
+    let joined = match step {
+        Step::CreateAccount => market.create_account(),
+        Step::AddAccount(account) => market.add_account(account.iter().cloned().collect()),
+        Step::SubmitTrade { account, buy, sell, amount } => {
+            if !ids.is_empty() {
+                // rejected trades are fine; the invariants are checked either way
+                let _ = market.submit_trade(&ids[account % ids.len()], buy, sell, *amount);
+            }
+            return Ok(());
+        }
+        Step::RemoveAccount { account } => {
+            if !ids.is_empty() {
+                let id = ids.remove(account % ids.len());
+                let left = market.remove_account(&id).map_err(|error| format!("remove_account({}) failed: {}", id, error))?;
+                change(&left, -1);
+            }
+            return Ok(());
+        }
+    };
+    let id = joined.map_err(|error| format!("joining failed: {}", error))?;
+    change(&account(market, &id)?, 1);
+    ids.push(id);
+    Ok(())
+}
+
+// simpler versions of a step, simplest first
+fn simplify(step: &Step) -> Vec<Step> {
+    match step {
+        Step::CreateAccount => Vec::new(),
+        Step::AddAccount(account) => {
+            let mut simpler = vec![Step::CreateAccount];
+            simpler.extend((0..account.len()).map(|i| {
+                let mut account = account.clone();
+                account.remove(i);
+                Step::AddAccount(account)
+            }));
+            simpler
+        }
+        Step::SubmitTrade { account, buy, sell, amount } => {
+            let mut simpler = Vec::new();
+            if *account > 0 {
+                simpler.push(Step::SubmitTrade { account: 0, buy: buy.clone(), sell: sell.clone(), amount: *amount });
+            }
+            for smaller in [1, amount / 2].iter().filter(|&&smaller| smaller != *amount && smaller > 0) {
+                simpler.push(Step::SubmitTrade { account: *account, buy: buy.clone(), sell: sell.clone(), amount: *smaller });
+            }
+            simpler
+        }
+        Step::RemoveAccount { account } if *account > 0 => vec![Step::RemoveAccount { account: 0 }],
+        Step::RemoveAccount { .. } => Vec::new(),
+    }
+}
+
+// drops whatever steps it can, in ever smaller chunks, then simplifies the rest one at a
+// time until nothing more can be taken away without the sequence passing
+fn shrink<M: Market + Clone>(market: &M, mut steps: Vec<Step>, mut failure: String) -> (Vec<Step>, String) {
+    loop {
+        let mut shrunk = false;
+        let mut chunk = steps.len();
+        while chunk > 0 {
+            let mut start = 0;
+            while start < steps.len() {
+                let mut fewer = steps.clone();
+                fewer.drain(start..(start + chunk).min(steps.len()));
+                match run_steps(market, &fewer) {
+                    Err(still) => {
+                        steps = fewer;
+                        failure = still;
+                        shrunk = true;
+                    }
+                    Ok(()) => start += chunk,
+                }
+            }
+            chunk /= 2;
+        }
+        for i in 0..steps.len() {
+            for simpler in simplify(&steps[i]) {
+                let mut candidate = steps.clone();
+                candidate[i] = simpler;
+                if let Err(still) = run_steps(market, &candidate) {
+                    steps = candidate;
+                    failure = still;
+                    shrunk = true;
+                    break;
+                }
+            }
+        }
+        if !shrunk {
+            return (steps, failure);
+        }
+    }
+}
+
+// how much either end of an in-memory connection can write before the other reads
+const PIPE_SIZE: usize = 64 * 1024;
+
+// a market server that clients connect to without a port
+pub struct InProcessServer {
+    bootstrap: widget_capnp::market::Client,
+}
+
+impl InProcessServer {
+    pub fn new<A: 'static + AsyncMarket + Clone>(server: MarketServer<A>) -> InProcessServer {
+        InProcessServer { bootstrap: capnp_rpc::new_client(server) }
+    }
+
+    // connects a new client; both ends run on the LocalSet this is called from
+    pub fn connect(&self) -> WidgetMarketClient {
+        let (client, server) = tokio::io::duplex(PIPE_SIZE);
+        spawn_local(single_market::answer_stream(server, &self.bootstrap.client));
+        WidgetMarketClient::from_stream(client)
+    }
+}
+
+// runs a test with a client of the market on a runtime of its own
+pub fn run_in_process<M, F, T>(market: M, test: impl FnOnce(WidgetMarketClient) -> F) -> T
+where
+    M: 'static + Market + Clone,
+    F: Future<Output = T>,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("could not start a runtime");
+    LocalSet::new().block_on(&runtime, async move {
+        let server = InProcessServer::new(MarketServer::new(market));
+        test(server.connect()).await
+    })
+}
+
+// how much of the input a raw call's params can take up
+const MAX_PARAMS: usize = 255;
+
+// fuzz input, read a byte at a time; once it runs out every byte reads as zero
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let (taken, rest) = self.0.split_at(len.min(self.0.len()));
+        self.0 = rest;
+        taken
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.bytes(1).first().copied().unwrap_or(0)
+    }
+
+    // mostly small amounts that might go through, and now and then any i32 at all
+    fn amount(&mut self) -> i32 {
+        match self.byte() as i8 {
+            i8::MIN => {
+                let mut word = [0; 4];
+                let bytes = self.bytes(4);
+                word[..bytes.len()].copy_from_slice(bytes);
+                i32::from_le_bytes(word)
+            }
+            small => small as i32,
+        }
+    }
+
+    fn widget(&mut self, widgets: &[String]) -> String {
+        match self.byte() as usize % (widgets.len() + 1) {
+            i if i < widgets.len() => widgets[i].clone(),
+            _ => UNKNOWN_WIDGET.to_string(),
+        }
+    }
+}
+
+// the open accounts and what they and the market hold between them
+fn census<M: Market>(market: &M) -> Result<(Vec<String>, HashMap<String, i32>), String> {
+    let ids = market.get_accounts().map_err(|error| format!("get_accounts failed: {}", error))?;
+    let held = holdings(market, &ids)?;
+    match held.iter().find(|(_, &count)| count < 0) {
+        Some((widget, count)) => Err(format!("market has {} {}", count, widget)),
+        None => Ok((ids, held)),
+    }
+}
+
+// feeds a market server calls decoded from arbitrary bytes, failing if the server
+// panics or stops answering, a count goes negative, or widgets are made or lost other
+// than by accounts joining or leaving with them. the market has to list its accounts.
+// this is meant as the body of a fuzz target, e.g. for cargo fuzz:
+//
+//   fuzz_target!(|data: &[u8]| fuzz_server(&market, data).unwrap());
+//
+// three accounts are joined first. then each call starts with a header byte: the top
+// bit picks a raw call, whose params are the next (length byte) bytes read as a capnp
+// message, or a typed one, whose fields are read from the bytes that follow. bits 4-5
+// pick the market or one of the accounts to call, and the low bits the method
+pub fn fuzz_server<M: 'static + Market + Clone>(market: &M, data: &[u8]) -> Result<(), String> {
+    let market = Rc::new(RefCell::new(market.clone()));
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("could not start a runtime");
+    let fuzzed = panic::catch_unwind(AssertUnwindSafe(|| LocalSet::new().block_on(&runtime, fuzz_calls(market, data))));
+    fuzzed.map_err(|_| "fuzzing panicked".to_string())?
+}
+
+async fn fuzz_calls<M: 'static + Market + Clone>(market: Rc<RefCell<M>>, data: &[u8]) -> Result<(), String> {
+    let widgets = widgets(&*market.borrow())?;
+    let bootstrap: widget_capnp::market::Client = capnp_rpc::new_client(MarketServer::from_async(SyncMarket::shared(market.clone())));
+    let (client, server) = tokio::io::duplex(PIPE_SIZE);
+    let answering = spawn_local(single_market::answer_stream(server, &bootstrap.client));
+    let client: widget_capnp::market::Client = client::connect_stream(client);
+
+    // a disconnected call means the server's task is gone. otherwise the call says what
+    // an account left with, if one did
+    let answered = |call: usize, sent: capnp::Result<HashMap<String, i32>>| match sent {
+        Err(error) if error.kind == capnp::ErrorKind::Disconnected => Err(call),
+        sent => Ok(sent.unwrap_or_default()),
+    };
+    let died = |call: usize| async move {
+        match answering.await {
+            Err(error) if error.is_panic() => format!("call {}: server panicked", call),
+            _ => format!("call {}: server hung up", call),
+        }
+    };
+
+    let mut accounts = Vec::new();
+    for _ in 0..3 {
+        let response = client.join_request().send().promise.await.map_err(|error| format!("join failed: {}", error))?;
+        accounts.push(response.get().and_then(|joined| joined.get_account()).map_err(|error| format!("join failed: {}", error))?);
+    }
+
+    let mut input = Input(data);
+    let (mut ids, mut expected) = census(&*market.borrow())?;
+    let mut call = 0;
+    while !input.is_empty() {
+        call += 1;
+        let header = input.byte();
+        let (target, interface) = match (header >> 4) & 3 {
+            0 => (&client.client, widget_capnp::market::_private::TYPE_ID),
+            account => (&accounts[account as usize - 1].client, widget_capnp::account::_private::TYPE_ID),
+        };
+        let sent = if header & 0x80 != 0 {
+            let len = input.byte() as usize;
+            call_raw(target, interface, (header & 0x0f) as u16, input.bytes(len.min(MAX_PARAMS))).await
+        } else if (header >> 4) & 3 == 0 {
+            join(&client, &mut input, &widgets).await
+        } else {
+            call_account(&accounts[((header >> 4) & 3) as usize - 1], header & 0x0f, &mut input, &widgets).await
+        };
+        let left = match answered(call, sent) {
+            Ok(left) => left,
+            Err(call) => return Err(died(call).await),
+        };
+
+        // widgets only come and go with accounts, like in the property test
+        let fail = |failure: String| format!("call {}: {}", call, failure);
+        let (now_open, held) = census(&*market.borrow()).map_err(fail)?;
+        for id in now_open.iter().filter(|id| !ids.contains(id)) {
+            for (widget, count) in account(&*market.borrow(), id).map_err(fail)? {
+                *expected.entry(widget).or_insert(0) += count;
+            }
+        }
+        for (widget, count) in left {
+            *expected.entry(widget).or_insert(0) -= count;
+        }
+        conserved(&expected, &held).map_err(fail)?;
+        ids = now_open;
+    }
+
+    // whatever came before, the server still answers
+    let sent = client.list_orders_request().send().promise.await.map(|_| HashMap::new());
+    match answered(call + 1, sent) {
+        Ok(_) => Ok(()),
+        Err(call) => Err(died(call).await),
+    }
+}
+
+// what an account left with, as told by a leave call
+fn left_with(results: widget_capnp::account::leave_results::Reader) -> capnp::Result<HashMap<String, i32>> {
+    let mut left = HashMap::new();
+    for count in results.get_account()? {
+        *left.entry(count.get_widget()?.to_string()).or_insert(0) += count.get_count();
+    }
+    Ok(left)
+}
+
+// a call with whatever the bytes decode to as its params
+async fn call_raw(target: &capnp::capability::Client, interface: u64, method: u16, bytes: &[u8]) -> capnp::Result<HashMap<String, i32>> {
+    let mut words = Word::allocate_zeroed_vec(bytes.len() / 8 + 1);
+    Word::words_to_bytes_mut(&mut words)[..bytes.len()].copy_from_slice(bytes);
+    let segments = [Word::words_to_bytes(&words)];
+    let message = message::Reader::new(SegmentArray::new(&segments), ReaderOptions::new());
+    let mut request = target.new_call::<any_pointer::Owned, any_pointer::Owned>(interface, method, None);
+    // bytes that don't decode at all are sent as empty params
+    if let Ok(params) = message.get_root::<any_pointer::Reader>() {
+        let _ = request.get().set_as(params);
+    }
+    let response = request.send().promise.await?;
+    // both leave calls answer with the same results
+    let leaving = (interface, method) == (widget_capnp::market::_private::TYPE_ID, 3) || (interface, method) == (widget_capnp::account::_private::TYPE_ID, 2);
+    if leaving {
+        left_with(response.get()?.get_as()?)
+    } else {
+        Ok(HashMap::new())
+    }
+}
+
+// joins with up to three widget counts
+async fn join(market: &widget_capnp::market::Client, input: &mut Input<'_>, widgets: &[String]) -> capnp::Result<HashMap<String, i32>> {
+    let mut request = market.join_request();
+    let len = input.byte() % 4;
+    let mut counts = request.get().init_account(len as u32);
+    for i in 0..len {
+        let mut count = counts.reborrow().get(i as u32);
+        count.set_widget(&input.widget(widgets));
+        count.set_count(input.amount());
+    }
+    request.send().promise.await.map(|_| HashMap::new())
+}
+
+async fn call_account(account: &widget_capnp::account::Client, method: u8, input: &mut Input<'_>, widgets: &[String]) -> capnp::Result<HashMap<String, i32>> {
+    let sent = match method % 8 {
+        0 => account.check_request().send().promise.await.map(drop),
+        1 => {
+            let mut request = account.trade_request();
+            let mut params = request.get();
+            params.set_buy(&input.widget(widgets));
+            params.set_sell(&input.widget(widgets));
+            params.set_amount(input.amount());
+            request.send().promise.await.map(drop)
+        }
+        2 => {
+            let mut request = account.leave_request();
+            if input.byte() % 2 == 1 {
+                request.get().set_destination("elsewhere");
+            }
+            let response = request.send().promise.await?;
+            return left_with(response.get()?);
+        }
+        3 => {
+            let mut request = account.place_order_request();
+            let mut params = request.get();
+            params.set_sell(&input.widget(widgets));
+            params.set_sell_amount(input.amount());
+            params.set_buy(&input.widget(widgets));
+            params.set_buy_amount(input.amount());
+            request.send().promise.await.map(drop)
+        }
+        4 => {
+            let mut request = account.cancel_order_request();
+            request.get().set_order(input.byte() as u64);
+            request.send().promise.await.map(drop)
+        }
+        5 => {
+            let mut request = account.quote_request();
+            let mut params = request.get();
+            params.set_buy(&input.widget(widgets));
+            params.set_sell(&input.widget(widgets));
+            params.set_amount(input.amount());
+            request.send().promise.await.map(drop)
+        }
+        6 => {
+            let mut request = account.submit_bundle_request();
+            let len = input.byte() % 4;
+            let mut legs = request.get().init_legs(len as u32);
+            for i in 0..len {
+                let mut leg = legs.reborrow().get(i as u32);
+                leg.set_buy(&input.widget(widgets));
+                leg.set_sell(&input.widget(widgets));
+                leg.set_amount(input.amount());
+            }
+            request.send().promise.await.map(drop)
+        }
+        _ => {
+            let mut request = account.history_request();
+            request.get().set_since(input.byte() as u64);
+            request.send().promise.await.map(drop)
+        }
+    };
+    sent.map(|()| HashMap::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::StreamExt;
+
+    use crate::amm::AmmMarket;
+    use crate::client::ClientError;
+    use crate::market::{Leg, Quote, ValidationError};
+    use crate::order_book::OrderBookMarket;
+
+    fn starting_account() -> HashMap<String, i32> {
+        ["foo", "bar"].iter().map(|&widget| (widget.to_string(), 100)).collect()
+    }
+
+    // the one thing a broken market gets wrong
+    #[derive(Clone, Copy)]
+    enum Flaw {
+        // hands back nothing when an account leaves
+        Forgetful,
+        // panics when asked for quotes or a quote
+        Fragile,
+    }
+
+    // an amm with a flaw
+    #[derive(Clone)]
+    struct Broken(AmmMarket, Flaw);
+
+    impl Market for Broken {
+        fn get_market(&self) -> Result<&HashMap<String, i32>, ValidationError> {
+            self.0.get_market()
+        }
+
+        fn get_account(&self, id: &str) -> Result<&HashMap<String, i32>, ValidationError> {
+            self.0.get_account(id)
+        }
+
+        fn get_accounts(&self) -> Result<Vec<String>, ValidationError> {
+            self.0.get_accounts()
+        }
+
+        fn get_quotes(&self) -> Result<Vec<Quote>, ValidationError> {
+            match self.1 {
+                Flaw::Fragile => panic!("no quotes today"),
+                _ => self.0.get_quotes(),
+            }
+        }
+
+        fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+            match self.1 {
+                Flaw::Fragile => panic!("no quotes today"),
+                _ => self.0.quote(id, buy, sell, amount),
+            }
+        }
+
+        fn create_account(&mut self) -> Result<String, ValidationError> {
+            self.0.create_account()
+        }
+
+        fn add_account(&mut self, account: HashMap<String, i32>) -> Result<String, ValidationError> {
+            self.0.add_account(account)
+        }
+
+        fn remove_account(&mut self, id: &str) -> Result<HashMap<String, i32>, ValidationError> {
+            match self.1 {
+                Flaw::Forgetful => self.0.remove_account(id).map(|_| HashMap::new()),
+                _ => self.0.remove_account(id),
+            }
+        }
+
+        fn submit_trade(&mut self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ValidationError> {
+            self.0.submit_trade(id, buy, sell, amount)
+        }
+    }
+
+    #[test]
+    fn test_conformance() {
+        let amm = AmmMarket::new(starting_account()).with_pool(("foo", 1000), ("bar", 1000), 30);
+        assert_conformance(&amm);
+        assert!(run_conformance(&amm).iter().all(|report| report.verdict == Verdict::Passed));
+
+        // an empty book can't fill a trade, so conservation can't be checked
+        let reports = run_conformance(&OrderBookMarket::new(starting_account()));
+        let skipped: Vec<_> = reports.iter().filter(|report| matches!(report.verdict, Verdict::Skipped(_))).map(|report| report.name).collect();
+        assert_eq!(skipped, vec!["widget conservation"]);
+        assert_conformance(&OrderBookMarket::new(starting_account()));
+
+        // failures are reported check by check
+        let reports = run_conformance(&Broken(amm.clone(), Flaw::Forgetful));
+        let failed: Vec<_> = reports.iter().filter(|report| matches!(report.verdict, Verdict::Failed(_))).collect();
+        assert_eq!(failed.iter().map(|report| report.name).collect::<Vec<_>>(), vec!["removed accounts"]);
+        assert!(failed[0].to_string().starts_with("removed accounts: failed (remove_account returned {}"));
+
+        // and a check that panics fails on its own without taking the rest down with it
+        let reports = run_conformance(&Broken(amm, Flaw::Fragile));
+        let failed: Vec<_> = reports.iter().filter(|report| matches!(report.verdict, Verdict::Failed(_))).map(Report::to_string).collect();
+        assert_eq!(failed, vec!["unknown accounts: failed (market panicked)".to_string()]);
+        assert_eq!(reports.len(), checks::<Broken>().len());
+    }
+
+    #[test]
+    fn test_property() {
+        let amm = AmmMarket::new(starting_account()).with_pool(("foo", 1000), ("bar", 1000), 30);
+        PropertyTest::new().assert_holds(&amm);
+        PropertyTest::new().assert_holds(&OrderBookMarket::new(starting_account()));
+
+        // an account leaving with nothing loses its widgets, which shrinks down to joining and leaving
+        let counterexample = PropertyTest::new().check(&Broken(amm.clone(), Flaw::Forgetful)).expect_err("widgets went missing");
+        assert_eq!(counterexample.steps, vec![Step::CreateAccount, Step::RemoveAccount { account: 0 }]);
+        assert!(counterexample.failure.starts_with("step 2 (remove_account(#0)): there should be"));
+
+        // the same seed finds the same sequence
+        let again = PropertyTest::new().check(&Broken(amm, Flaw::Forgetful)).unwrap_err();
+        assert_eq!(again, counterexample);
+    }
+
+    #[test]
+    fn test_in_process() {
+        let amm = AmmMarket::new(starting_account()).with_pool(("foo", 1000), ("bar", 1000), 0);
+        run_in_process(amm.clone(), |client| async move {
+            // servers without legacy ids don't hand them out, and joining by id says so
+            if !cfg!(feature = "legacy-ids") {
+                assert!(matches!(client.join().await, Err(ClientError::Decode(_))));
+                return;
+            }
+            let id = client.join().await.unwrap();
+            client.trade(&id, "foo", "bar", 10).await.unwrap();
+            assert_eq!(client.check(&id).await.unwrap().account["foo"], 110);
+
+            // rejections come back with the market's error
+            match client.trade(&id, "foo", "baz", 1).await {
+                Err(ClientError::Market(error)) => assert_eq!(error.code(), ErrorCode::UnknownWidget),
+                other => panic!("expected an unknown widget, got {:?}", other),
+            }
+            match client.list_orders().await {
+                Err(ClientError::Market(error)) => assert_eq!(error.code(), ErrorCode::Unsupported),
+                other => panic!("expected orders to be unsupported, got {:?}", other),
+            }
+            assert_eq!(client.leave(&id).await.unwrap()["foo"], 110);
+        });
+
+        // several clients can share a server, and hear about each other's trades
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        LocalSet::new().block_on(&runtime, async move {
+            let server = InProcessServer::new(MarketServer::new(amm));
+            let (watcher, trader) = (server.connect(), server.connect());
+            let mut trades = watcher.subscribe().await.unwrap();
+            let account = trader.join_handle().await.unwrap();
+            account.trade("bar", "foo", 4).await.unwrap();
+            let event = trades.next().await.unwrap();
+            assert_eq!(event.legs, vec![Leg { buy: "bar".to_string(), sell: "foo".to_string(), amount: 4 }]);
+            assert_eq!(event.changes["bar"], -4);
+        });
+    }
+
+    #[test]
+    fn test_fuzz_server() {
+        let amm = AmmMarket::new(starting_account()).with_pool(("foo", 1000), ("bar", 1000), 30);
+        let book = OrderBookMarket::new(starting_account());
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let data: Vec<u8> = (0..rng.gen_range(0..400)).map(|_| rng.gen()).collect();
+            fuzz_server(&amm, &data).unwrap();
+            fuzz_server(&book, &data).unwrap();
+        }
+
+        // joining with -10 foo is turned away rather than leaving the account in debt
+        fuzz_server(&amm, &[0x00, 1, 0, -10i8 as u8]).unwrap();
+        // trading foo for bar, then the same from raw params that don't decode to anything sensible
+        fuzz_server(&amm, &[0x11, 0, 1, 5, 0x91, 8, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]).unwrap();
+
+        // an account leaving with nothing is caught even though the accounts changed
+        let forgetful = Broken(amm.clone(), Flaw::Forgetful);
+        let failure = fuzz_server(&forgetful, &[0x12, 0]).unwrap_err();
+        assert!(failure.starts_with("call 1: there should be"), "{}", failure);
+        // leaving from raw params too
+        fuzz_server(&amm, &[0x92, 0]).unwrap();
+        fuzz_server(&forgetful, &[0x92, 0]).expect_err("the account left with nothing");
+
+        // an account checking its quotes brings the server down
+        assert_eq!(fuzz_server(&Broken(amm, Flaw::Fragile), &[0x10]), Err("call 1: server panicked".to_string()));
+    }
+}
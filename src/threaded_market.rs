@@ -0,0 +1,286 @@
+// a server that handles connections on several threads around a market owned by one actor
+//
+// capnp connections can't move between threads, so each worker thread runs its own
+// single-threaded runtime and the connections it is handed stay there. the market itself
+// lives on an actor thread; workers send it every call as a job and wait for the answer
+// without blocking, so calls are made one at a time in the order the actor receives them
+// while network i/o is spread over the workers.
+//
+// trade events are shared between the workers so subscribers hear about every trade.
+// recording isn't offered here since calls answered on different threads can't be put
+// back in the order the market made them
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use futures::channel::{mpsc as async_mpsc, oneshot};
+use futures::future;
+use futures::StreamExt;
+use log::{error, info};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::LocalSet;
+
+use crate::async_market::{AsyncMarket, MarketFuture};
+use crate::market::{self, ErrorCode, Fill, Leg, Market, Order, Quote, Snapshot, ValidationError};
+use crate::predicate::Predicate;
+use crate::single_market::{self, Connections, EventHub, MarketServer, OpenConnection};
+use crate::transfer::AccountBundle;
+use crate::widget_capnp;
+
+type Job<M> = Box<dyn FnOnce(&mut M) + Send>;
+
+fn stopped() -> ValidationError {
+    ValidationError::MarketError(ErrorCode::Other, "market is no longer running".to_string())
+}
+
+// a handle to a market owned by an actor thread. the actor stops once every handle is dropped
+pub struct ActorMarket<M: Market> {
+    jobs: Sender<Job<M>>,
+}
+
+impl<M: Market> Clone for ActorMarket<M> {
+    fn clone(&self) -> ActorMarket<M> {
+        ActorMarket { jobs: self.jobs.clone() }
+    }
+}
+
+impl<M: 'static + Market + Send> ActorMarket<M> {
+    // moves the market onto its own thread
+    pub fn spawn(mut market: M) -> ActorMarket<M> {
+        let (jobs, received) = mpsc::channel::<Job<M>>();
+        thread::Builder::new()
+            .name("market-actor".to_string())
+            .spawn(move || received.into_iter().for_each(|job| job(&mut market)))
+            .expect("could not start the market actor");
+        ActorMarket { jobs }
+    }
+
+    // makes the call on the actor thread
+    fn call<T: 'static + Send>(&self, operation: impl FnOnce(&mut M) -> Result<T, ValidationError> + Send + 'static) -> MarketFuture<T> {
+        let (reply, replied) = oneshot::channel();
+        let job: Job<M> = Box::new(move |market| {
+            // nobody is waiting if the connection went away
+            let _ = reply.send(operation(market));
+        });
+        if self.jobs.send(job).is_err() {
+            return Box::pin(future::ready(Err(stopped())));
+        }
+        Box::pin(async move { replied.await.unwrap_or_else(|_| Err(stopped())) })
+    }
+}
+
+impl<M: 'static + Market + Clone + Send> AsyncMarket for ActorMarket<M> {
+    fn get_market(&self) -> MarketFuture<HashMap<String, i32>> {
+        self.call(|market| market.get_market().cloned())
+    }
+
+    fn get_account(&self, id: &str) -> MarketFuture<HashMap<String, i32>> {
+        let id = id.to_string();
+        self.call(move |market| market.get_account(&id).cloned())
+    }
+
+    fn get_accounts(&self) -> MarketFuture<Vec<String>> {
+        self.call(|market| market.get_accounts())
+    }
+
+    fn get_quotes(&self) -> MarketFuture<Vec<Quote>> {
+        self.call(|market| market.get_quotes())
+    }
+
+    fn check(&self, id: &str) -> MarketFuture<Snapshot> {
+        let id = id.to_string();
+        self.call(move |market| {
+            let view = market.get_market()?.clone();
+            Ok(Snapshot { account: market.get_account(&id)?.clone(), market: view, quotes: market.get_quotes()? })
+        })
+    }
+
+    fn create_account(&self) -> MarketFuture<String> {
+        self.call(|market| market.create_account())
+    }
+
+    fn add_account(&self, account: HashMap<String, i32>) -> MarketFuture<String> {
+        self.call(move |market| market.add_account(account))
+    }
+
+    fn remove_account(&self, id: &str) -> MarketFuture<HashMap<String, i32>> {
+        let id = id.to_string();
+        self.call(move |market| market.remove_account(&id))
+    }
+
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> MarketFuture<Quote> {
+        let (id, buy, sell) = (id.to_string(), buy.to_string(), sell.to_string());
+        self.call(move |market| market.quote(&id, &buy, &sell, amount))
+    }
+
+    fn submit_trade(&self, id: &str, buy: &str, sell: &str, amount: i32) -> MarketFuture<()> {
+        let (id, buy, sell) = (id.to_string(), buy.to_string(), sell.to_string());
+        self.call(move |market| market.submit_trade(&id, &buy, &sell, amount))
+    }
+
+    fn submit_trade_if(&self, id: &str, buy: &str, sell: &str, amount: i32, condition: &Predicate) -> MarketFuture<()>
+    where
+        Self: Sized + Clone + 'static,
+    {
+        let (id, buy, sell, condition) = (id.to_string(), buy.to_string(), sell.to_string(), condition.clone());
+        self.call(move |market| market.submit_trade_if(&id, &buy, &sell, amount, &condition))
+    }
+
+    fn submit_bundle(&self, id: &str, legs: &[Leg]) -> MarketFuture<()> {
+        let (id, legs) = (id.to_string(), legs.to_vec());
+        self.call(move |market| market.submit_bundle(&id, &legs))
+    }
+
+    // the changes are worked out in the same job as the trade, so no other call can slip in
+    fn trade_changes(&self, id: &str, buy: &str, sell: &str, amount: i32, condition: Option<&Predicate>) -> MarketFuture<HashMap<String, i32>>
+    where
+        Self: Sized + Clone + 'static,
+    {
+        let (id, buy, sell, condition) = (id.to_string(), buy.to_string(), sell.to_string(), condition.cloned());
+        self.call(move |market| {
+            market::traced(market, |market| match &condition {
+                Some(condition) => market.submit_trade_if(&id, &buy, &sell, amount, condition),
+                None => market.submit_trade(&id, &buy, &sell, amount),
+            })
+        })
+    }
+
+    fn bundle_changes(&self, id: &str, legs: &[Leg]) -> MarketFuture<HashMap<String, i32>>
+    where
+        Self: Sized + Clone + 'static,
+    {
+        let (id, legs) = (id.to_string(), legs.to_vec());
+        self.call(move |market| market::traced(market, |market| market.submit_bundle(&id, &legs)))
+    }
+
+    fn place_order(&self, id: &str, sell: &str, sell_amount: i32, buy: &str, buy_amount: i32) -> MarketFuture<Order> {
+        let (id, sell, buy) = (id.to_string(), sell.to_string(), buy.to_string());
+        self.call(move |market| market.place_order(&id, &sell, sell_amount, &buy, buy_amount))
+    }
+
+    fn cancel_order(&self, id: &str, order: u64) -> MarketFuture<Order> {
+        let id = id.to_string();
+        self.call(move |market| market.cancel_order(&id, order))
+    }
+
+    fn get_orders(&self) -> MarketFuture<Vec<Order>> {
+        self.call(|market| market.get_orders())
+    }
+
+    fn export_account(&self, id: &str, destination: &str) -> MarketFuture<AccountBundle> {
+        let (id, destination) = (id.to_string(), destination.to_string());
+        self.call(move |market| market.export_account(&id, &destination))
+    }
+
+    fn import_account(&self, bundle: &AccountBundle) -> MarketFuture<String> {
+        let bundle = bundle.clone();
+        self.call(move |market| market.import_account(&bundle))
+    }
+
+    fn history(&self, id: &str, since: u64) -> MarketFuture<Vec<Fill>> {
+        let id = id.to_string();
+        self.call(move |market| market.history(&id, since))
+    }
+}
+
+// serves the connections handed to one worker thread until the acceptor stops
+fn work<M: 'static + Market + Clone + Send>(market: ActorMarket<M>, hub: EventHub, mut streams: async_mpsc::UnboundedReceiver<(std::net::TcpStream, OpenConnection)>) {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("could not start a worker runtime");
+    LocalSet::new().block_on(&runtime, async move {
+        let server = MarketServer::from_async(market).sharing_events(hub);
+        let client: widget_capnp::market::Client = capnp_rpc::new_client(server);
+        while let Some((stream, connection)) = streams.next().await {
+            let connected = TcpStream::from_std(stream).and_then(|stream| single_market::connect(stream, &client.client, connection));
+            if let Err(error) = connected {
+                error!("unable to set up connection: {}", error);
+            }
+        }
+    });
+}
+
+// like single_market::run, but with connections spread over some number of worker threads.
+// new connections are turned away while max_connections are open across all of them
+pub async fn run<M: 'static + Market + Clone + Send>(
+    addr: SocketAddr,
+    market: M,
+    workers: usize,
+    max_connections: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let market = ActorMarket::spawn(market);
+    let hub: EventHub = Arc::new(Mutex::new(Vec::new()));
+    let workers: Vec<_> = (0..workers.max(1))
+        .map(|i| {
+            let (streams, received) = async_mpsc::unbounded();
+            let (market, hub) = (market.clone(), hub.clone());
+            thread::Builder::new().name(format!("market-worker-{}", i)).spawn(move || work(market, hub, received))?;
+            Ok(streams)
+        })
+        .collect::<std::io::Result<_>>()?;
+
+    let listener = TcpListener::bind(&addr).await?;
+    info!("started server at {} with {} workers", addr, workers.len());
+    // connections are dealt out to the workers in turn
+    let connections = Connections::limited(max_connections);
+    let mut workers = workers.iter().cycle();
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        match connections.open() {
+            Some(connection) => workers.next().unwrap().unbounded_send((stream.into_std()?, connection))?,
+            None => info!("turning away {}; too many connections are open", peer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+
+    use crate::amm::AmmMarket;
+
+    #[test]
+    fn test_actor_market() {
+        let account: HashMap<String, i32> = [("foo".to_string(), 100), ("bar".to_string(), 100)].iter().cloned().collect();
+        let market = ActorMarket::spawn(AmmMarket::new(account).with_pool(("foo", 1000), ("bar", 1000), 0));
+        let ids: Vec<String> = (0..4).map(|_| block_on(market.create_account()).unwrap()).collect();
+
+        // trades from many threads are made one at a time, so no widgets go missing and
+        // what each trade says it moved is only its own doing
+        let traders: Vec<_> = ids
+            .iter()
+            .cloned()
+            .map(|id| {
+                let market = market.clone();
+                thread::spawn(move || {
+                    let mut moved = 0;
+                    for i in 0..50 {
+                        let (buy, sell) = if i % 2 == 0 { ("foo", "bar") } else { ("bar", "foo") };
+                        if i % 5 == 0 {
+                            moved += block_on(market.trade_changes(&id, buy, sell, 3, None)).unwrap()["foo"];
+                        } else {
+                            block_on(market.submit_trade(&id, buy, sell, 3)).unwrap();
+                            moved += if buy == "foo" { -3 } else { 3 };
+                        }
+                    }
+                    moved
+                })
+            })
+            .collect();
+        let moved: i32 = traders.into_iter().map(|trader| trader.join().unwrap()).sum();
+        let view = block_on(market.get_market()).unwrap();
+        let total = |widget: &str| {
+            view[widget] + ids.iter().map(|id| block_on(market.get_account(id)).unwrap()[widget]).sum::<i32>()
+        };
+        assert_eq!((total("foo"), total("bar")), (1400, 1400));
+        assert_eq!(view["foo"], 1000 + moved);
+
+        // and a market that has stopped says so
+        let (jobs, received) = mpsc::channel::<Job<AmmMarket>>();
+        drop(received);
+        let stopped = ActorMarket { jobs };
+        assert_eq!(block_on(stopped.get_market()).unwrap_err().code(), ErrorCode::Other);
+    }
+}
@@ -0,0 +1,244 @@
+// signed account bundles that let an account move from one market to another
+//
+// markets that accept each other's accounts share a secret per origin market. when
+// an account leaves for another market, the market it left signs its widgets along
+// with both market names and a random nonce. the receiving market only accepts the
+// bundle if it trusts the origin, it is the named destination, the signature matches
+// and it has not seen the nonce before.
+//
+// signatures are an hmac-sha256 of the bundle under the origin's secret, written out
+// as hex, and are checked in constant time
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::market::{ErrorCode, Execution, Fill, Market, Order, Quote, ValidationError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccountBundle {
+    // the market the account left
+    pub origin: String,
+    // the only market that may accept the bundle
+    pub destination: String,
+    pub account: HashMap<String, i32>,
+    pub nonce: u64,
+    pub signature: String,
+}
+
+// fields are length-prefixed and widgets sorted so every bundle has one encoding
+fn encode(origin: &str, destination: &str, account: &HashMap<String, i32>, nonce: u64) -> Vec<u8> {
+    let mut message = Vec::new();
+    let write_text = |message: &mut Vec<u8>, text: &str| {
+        message.extend_from_slice(&(text.len() as u64).to_le_bytes());
+        message.extend_from_slice(text.as_bytes());
+    };
+    write_text(&mut message, origin);
+    write_text(&mut message, destination);
+    message.extend_from_slice(&nonce.to_le_bytes());
+    for (widget, count) in account.iter().collect::<BTreeMap<_, _>>() {
+        write_text(&mut message, widget);
+        message.extend_from_slice(&count.to_le_bytes());
+    }
+    message
+}
+
+fn mac(secret: &str, message: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any length");
+    mac.update(message);
+    mac
+}
+
+fn sign(secret: &str, message: &[u8]) -> String {
+    mac(secret, message).finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// checks the signature in constant time; one that isn't even hex just doesn't match
+fn verify(secret: &str, message: &[u8], signature: &str) -> bool {
+    let bytes: Option<Vec<u8>> = (0..signature.len())
+        .step_by(2)
+        .map(|i| signature.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect();
+    bytes.is_some_and(|bytes| mac(secret, message).verify_slice(&bytes).is_ok())
+}
+
+fn invalid_bundle(message: String) -> ValidationError {
+    ValidationError::AccountError(ErrorCode::InvalidBundle, message)
+}
+
+// wraps a market so its accounts can leave for and arrive from trusted markets
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transferable<M: Market> {
+    market: M,
+    name: String,
+    secret: String,
+    // secrets of the markets we accept accounts from
+    trusted: HashMap<String, String>,
+    // (origin, nonce) of every bundle already accepted
+    seen: HashSet<(String, u64)>,
+}
+
+impl<M: Market> Transferable<M> {
+    // names the market and sets the secret it signs with; a market always trusts itself
+    pub fn new(market: M, name: &str, secret: &str) -> Transferable<M> {
+        let trusted = [(name.to_string(), secret.to_string())].iter().cloned().collect();
+        Transferable {
+            market,
+            name: name.to_string(),
+            secret: secret.to_string(),
+            trusted,
+            seen: HashSet::new(),
+        }
+    }
+
+    // accepts accounts signed by another market with its secret
+    pub fn trust(mut self, origin: &str, secret: &str) -> Transferable<M> {
+        self.trusted.insert(origin.to_string(), secret.to_string());
+        self
+    }
+}
+
+impl<M: Market> Market for Transferable<M> {
+    fn get_market(&self) -> Result<&HashMap<String, i32>, ValidationError> {
+        self.market.get_market()
+    }
+
+    fn get_account(&self, id: &str) -> Result<&HashMap<String, i32>, ValidationError> {
+        self.market.get_account(id)
+    }
+
+    fn get_accounts(&self) -> Result<Vec<String>, ValidationError> {
+        self.market.get_accounts()
+    }
+
+    fn get_quotes(&self) -> Result<Vec<Quote>, ValidationError> {
+        self.market.get_quotes()
+    }
+
+    fn create_account(&mut self) -> Result<String, ValidationError> {
+        self.market.create_account()
+    }
+
+    fn add_account(&mut self, account: HashMap<String, i32>) -> Result<String, ValidationError> {
+        self.market.add_account(account)
+    }
+
+    fn remove_account(&mut self, id: &str) -> Result<HashMap<String, i32>, ValidationError> {
+        self.market.remove_account(id)
+    }
+
+    fn quote(&self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<Quote, ValidationError> {
+        self.market.quote(id, buy, sell, amount)
+    }
+
+    fn submit_trade(&mut self, id: &str, buy: &str, sell: &str, amount: i32) -> Result<(), ValidationError> {
+        self.market.submit_trade(id, buy, sell, amount)
+    }
+
+    fn place_order(&mut self, id: &str, sell: &str, sell_amount: i32, buy: &str, buy_amount: i32) -> Result<Order, ValidationError> {
+        self.market.place_order(id, sell, sell_amount, buy, buy_amount)
+    }
+
+    fn cancel_order(&mut self, id: &str, order: u64) -> Result<Order, ValidationError> {
+        self.market.cancel_order(id, order)
+    }
+
+    fn get_orders(&self) -> Result<Vec<Order>, ValidationError> {
+        self.market.get_orders()
+    }
+
+    fn last_executions(&self) -> Vec<Execution> {
+        self.market.last_executions()
+    }
+
+    fn export_account(&mut self, id: &str, destination: &str) -> Result<AccountBundle, ValidationError> {
+        let account = self.market.remove_account(id)?;
+        let nonce = rand::random();
+        let signature = sign(&self.secret, &encode(&self.name, destination, &account, nonce));
+        Ok(AccountBundle { origin: self.name.clone(), destination: destination.to_string(), account, nonce, signature })
+    }
+
+    fn import_account(&mut self, bundle: &AccountBundle) -> Result<String, ValidationError> {
+        let secret = self
+            .trusted
+            .get(&bundle.origin)
+            .ok_or_else(|| invalid_bundle(format!("{} is not a trusted market", bundle.origin)))?;
+        if bundle.destination != self.name {
+            return Err(invalid_bundle(format!("bundle is for {}, not {}", bundle.destination, self.name)));
+        }
+        let message = encode(&bundle.origin, &bundle.destination, &bundle.account, bundle.nonce);
+        if !verify(secret, &message, &bundle.signature) {
+            return Err(invalid_bundle("bundle signature does not match".to_string()));
+        }
+        if self.seen.contains(&(bundle.origin.clone(), bundle.nonce)) {
+            return Err(ValidationError::AccountError(ErrorCode::DuplicateAccount, "bundle was already used".to_string()));
+        }
+        let id = self.market.add_account(bundle.account.clone())?;
+        self.seen.insert((bundle.origin.clone(), bundle.nonce));
+        Ok(id)
+    }
+
+    fn history(&self, id: &str, since: u64) -> Result<Vec<Fill>, ValidationError> {
+        self.market.history(id, since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::amm::AmmMarket;
+    use crate::order_book::OrderBookMarket;
+
+    fn starting_account() -> HashMap<String, i32> {
+        ["foo", "bar"].iter().map(|&widget| (widget.to_string(), 10)).collect()
+    }
+
+    #[test]
+    fn test_transfer() {
+        // rfc 4231 test case 2
+        let signature = sign("Jefe", b"what do ya want for nothing?");
+        assert_eq!(signature, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert!(verify("Jefe", b"what do ya want for nothing?", &signature));
+        assert!(!verify("Jefe", b"what do ya want for nothing?", &signature[..62]));
+        assert!(!verify("Jefe", b"what do ya want for nothing?", "not hex"));
+
+        let mut book = Transferable::new(OrderBookMarket::new(starting_account()), "book", "book secret")
+            .trust("amm", "amm secret");
+        let mut amm = Transferable::new(AmmMarket::new(starting_account()).with_pool(("foo", 100), ("bar", 100), 0), "amm", "amm secret")
+            .trust("book", "book secret");
+        let mut stranger = Transferable::new(AmmMarket::new(starting_account()).with_pool(("foo", 100), ("bar", 100), 0), "amm", "other secret");
+
+        // leaving signs the account for the destination
+        let id = book.create_account().unwrap();
+        let bundle = book.export_account(&id, "amm").unwrap();
+        assert_eq!(bundle.account, starting_account());
+        book.get_account(&id).expect_err("account should have left");
+
+        // only the named, trusting destination accepts it, and only once
+        book.import_account(&bundle).expect_err("bundle is for amm");
+        stranger.import_account(&bundle).expect_err("stranger doesn't trust book");
+        let mut forged = bundle.clone();
+        forged.account.insert("foo".to_string(), 1000);
+        amm.import_account(&forged).expect_err("the account was changed");
+        let arrived = amm.import_account(&bundle).unwrap();
+        assert_eq!(amm.get_account(&arrived).unwrap(), &starting_account());
+        let replayed = amm.import_account(&bundle).expect_err("bundle was already used");
+        assert_eq!(replayed.code(), ErrorCode::DuplicateAccount);
+
+        // and back again
+        amm.submit_trade(&arrived, "foo", "bar", 1).unwrap();
+        let bundle = amm.export_account(&arrived, "book").unwrap();
+        let home = book.import_account(&bundle).unwrap();
+        assert_eq!(book.get_account(&home).unwrap()["foo"], 11);
+
+        // markets that don't sign bundles reject transfers
+        let mut plain = OrderBookMarket::new(starting_account());
+        let id = plain.create_account().unwrap();
+        assert_eq!(plain.export_account(&id, "amm").unwrap_err().code(), ErrorCode::Unsupported);
+        plain.get_account(&id).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+// checks a market can run on a trade before making it
+//
+// a market works out what a trade would move and asks its validators about it before
+// touching any widgets; the first rule that objects rejects the trade. rules can be
+// chained together in a ValidatorChain, which is itself a validator, and rules that
+// keep track of trades are told about each one that goes through.
+//
+// the built-in rules are:
+//  - WidgetsExist: both widgets are in the market
+//  - DistinctWidgets: the widgets being traded are different
+//  - PositiveAmounts: both sides of the trade are positive
+//  - SufficientBalance: the market has what is bought and the account has what is paid
+//  - TradeLimit: an account makes at most some number of trades
+//  - MaxPosition: an account never holds more than some amount of any one widget
+//
+// validators aren't serialized, so limits don't survive persistence: a market restored
+// from a snapshot or journal gets whatever chain it rebuilds for itself, and TradeLimit
+// starts counting from zero. a market that needs its limits to hold across restarts has
+// to keep them in its own state.
+//
+// validators only see the market's and account's counts, so they suit markets that price
+// trades off those counts, like foo_market. the amm and order book check trades
+// themselves: the amm prices off each pool's reserves rather than the market's totals,
+// and the book's counts only hold what is resting in it, so neither can say up front
+// whether the market has what a trade buys
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use crate::market::{ErrorCode, ValidationError};
+
+// a trade that is about to be made; the account gets buy_amount of buy for sell_amount of sell
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProposedTrade<'a> {
+    pub id: &'a str,
+    pub buy: &'a str,
+    pub buy_amount: i32,
+    pub sell: &'a str,
+    pub sell_amount: i32,
+}
+
+fn count(counts: &HashMap<String, i32>, widget: &str) -> i32 {
+    counts.get(widget).copied().unwrap_or(0)
+}
+
+pub trait Validator: ValidatorClone + Debug + Send {
+    // checks the trade against the market and the trading account as they are right before it
+    fn validate(&self, trade: &ProposedTrade, market: &HashMap<String, i32>, account: &HashMap<String, i32>) -> Result<(), ValidationError>;
+    // told about every trade that was made
+    fn record(&mut self, _trade: &ProposedTrade) {}
+}
+
+// lets chains of boxed validators be copied along with their market
+pub trait ValidatorClone {
+    fn clone_box(&self) -> Box<dyn Validator>;
+}
+
+impl<V: 'static + Validator + Clone> ValidatorClone for V {
+    fn clone_box(&self) -> Box<dyn Validator> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Validator> {
+    fn clone(&self) -> Box<dyn Validator> {
+        self.clone_box()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WidgetsExist;
+
+impl Validator for WidgetsExist {
+    fn validate(&self, trade: &ProposedTrade, market: &HashMap<String, i32>, _: &HashMap<String, i32>) -> Result<(), ValidationError> {
+        match [trade.buy, trade.sell].iter().find(|widget| !market.contains_key(**widget)) {
+            Some(widget) => Err(ValidationError::TradeError(ErrorCode::UnknownWidget, format!("{} is not in market", widget))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DistinctWidgets;
+
+impl Validator for DistinctWidgets {
+    fn validate(&self, trade: &ProposedTrade, _: &HashMap<String, i32>, _: &HashMap<String, i32>) -> Result<(), ValidationError> {
+        if trade.buy == trade.sell {
+            Err(ValidationError::TradeError(ErrorCode::IdenticalWidgets, format!("both widgets are {}", trade.buy)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PositiveAmounts;
+
+impl Validator for PositiveAmounts {
+    fn validate(&self, trade: &ProposedTrade, _: &HashMap<String, i32>, _: &HashMap<String, i32>) -> Result<(), ValidationError> {
+        if trade.buy_amount <= 0 || trade.sell_amount <= 0 {
+            Err(ValidationError::TradeError(ErrorCode::InvalidAmount, "trade amount must be positive".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SufficientBalance;
+
+impl Validator for SufficientBalance {
+    fn validate(&self, trade: &ProposedTrade, market: &HashMap<String, i32>, account: &HashMap<String, i32>) -> Result<(), ValidationError> {
+        if count(market, trade.buy) < trade.buy_amount {
+            Err(ValidationError::TradeError(ErrorCode::InsufficientWidgets, format!("not enough {} in market", trade.buy)))
+        } else if count(account, trade.sell) < trade.sell_amount {
+            Err(ValidationError::TradeError(ErrorCode::InsufficientWidgets, format!("not enough {} in account {}", trade.sell, trade.id)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// counts are kept in memory only, so they start from zero whenever the market is restored
+#[derive(Clone, Debug)]
+pub struct TradeLimit {
+    limit: u32,
+    trades: HashMap<String, u32>,
+}
+
+impl TradeLimit {
+    pub fn new(limit: u32) -> TradeLimit {
+        TradeLimit { limit, trades: HashMap::new() }
+    }
+}
+
+impl Validator for TradeLimit {
+    fn validate(&self, trade: &ProposedTrade, _: &HashMap<String, i32>, _: &HashMap<String, i32>) -> Result<(), ValidationError> {
+        if self.trades.get(trade.id).copied().unwrap_or(0) >= self.limit {
+            Err(ValidationError::AccountError(
+                ErrorCode::TradeLimitExceeded,
+                format!("account {} has already made {} trades", trade.id, self.limit),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn record(&mut self, trade: &ProposedTrade) {
+        *self.trades.entry(trade.id.to_string()).or_default() += 1;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MaxPosition {
+    limit: i32,
+}
+
+impl MaxPosition {
+    pub fn new(limit: i32) -> MaxPosition {
+        MaxPosition { limit }
+    }
+}
+
+impl Validator for MaxPosition {
+    fn validate(&self, trade: &ProposedTrade, _: &HashMap<String, i32>, account: &HashMap<String, i32>) -> Result<(), ValidationError> {
+        // a position too big to count is past any limit
+        match count(account, trade.buy).checked_add(trade.buy_amount) {
+            Some(position) if position <= self.limit => Ok(()),
+            _ => Err(ValidationError::AccountError(
+                ErrorCode::PositionLimitExceeded,
+                format!("account {} would hold more than {} {}", trade.id, self.limit, trade.buy),
+            )),
+        }
+    }
+}
+
+// rules checked in the order they were added
+#[derive(Clone, Debug, Default)]
+pub struct ValidatorChain {
+    rules: Vec<Box<dyn Validator>>,
+}
+
+impl ValidatorChain {
+    pub fn new() -> ValidatorChain {
+        ValidatorChain::default()
+    }
+
+    // the checks every market needs: known, distinct widgets, positive amounts and enough of both
+    pub fn standard() -> ValidatorChain {
+        ValidatorChain::new()
+            .with_rule(DistinctWidgets)
+            .with_rule(WidgetsExist)
+            .with_rule(PositiveAmounts)
+            .with_rule(SufficientBalance)
+    }
+
+    pub fn with_rule<V: 'static + Validator>(mut self, rule: V) -> ValidatorChain {
+        self.rules.push(Box::new(rule));
+        self
+    }
+}
+
+impl Validator for ValidatorChain {
+    fn validate(&self, trade: &ProposedTrade, market: &HashMap<String, i32>, account: &HashMap<String, i32>) -> Result<(), ValidationError> {
+        self.rules.iter().try_for_each(|rule| rule.validate(trade, market, account))
+    }
+
+    fn record(&mut self, trade: &ProposedTrade) {
+        self.rules.iter_mut().for_each(|rule| rule.record(trade));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(foo: i32, bar: i32) -> HashMap<String, i32> {
+        [("foo".to_string(), foo), ("bar".to_string(), bar)].iter().cloned().collect()
+    }
+
+    fn trade<'a>(buy: &'a str, buy_amount: i32, sell: &'a str, sell_amount: i32) -> ProposedTrade<'a> {
+        ProposedTrade { id: "account", buy, buy_amount, sell, sell_amount }
+    }
+
+    #[test]
+    fn test_validator_chain() {
+        let (market, account) = (counts(10, 10), counts(5, 5));
+        let code = |chain: &ValidatorChain, trade: ProposedTrade| chain.validate(&trade, &market, &account).map_err(|error| error.code());
+
+        let standard = ValidatorChain::standard();
+        assert_eq!(code(&standard, trade("foo", 3, "bar", 3)), Ok(()));
+        assert_eq!(code(&standard, trade("foo", 3, "foo", 3)), Err(ErrorCode::IdenticalWidgets));
+        assert_eq!(code(&standard, trade("baz", 3, "bar", 3)), Err(ErrorCode::UnknownWidget));
+        assert_eq!(code(&standard, trade("foo", 3, "bar", 0)), Err(ErrorCode::InvalidAmount));
+        assert_eq!(code(&standard, trade("foo", 11, "bar", 3)), Err(ErrorCode::InsufficientWidgets));
+        assert_eq!(code(&standard, trade("foo", 3, "bar", 6)), Err(ErrorCode::InsufficientWidgets));
+        // rules are checked in order
+        assert_eq!(code(&standard, trade("baz", 3, "baz", 0)), Err(ErrorCode::IdenticalWidgets));
+
+        let capped = ValidatorChain::standard().with_rule(MaxPosition::new(8));
+        assert_eq!(code(&capped, trade("foo", 3, "bar", 3)), Ok(()));
+        assert_eq!(code(&capped, trade("foo", 4, "bar", 3)), Err(ErrorCode::PositionLimitExceeded));
+        let uncapped = MaxPosition::new(i32::MAX).validate(&trade("foo", 1, "bar", 1), &market, &counts(i32::MAX, 5));
+        assert_eq!(uncapped.map_err(|error| error.code()), Err(ErrorCode::PositionLimitExceeded));
+
+        // limits count the trades recorded for each account, and copies count separately
+        let mut limited = ValidatorChain::standard().with_rule(TradeLimit::new(2));
+        limited.record(&trade("foo", 1, "bar", 1));
+        let before = limited.clone();
+        limited.record(&trade("bar", 1, "foo", 1));
+        assert_eq!(code(&limited, trade("foo", 1, "bar", 1)), Err(ErrorCode::TradeLimitExceeded));
+        assert_eq!(code(&before, trade("foo", 1, "bar", 1)), Ok(()));
+        let other = ProposedTrade { id: "other", ..trade("foo", 1, "bar", 1) };
+        assert_eq!(code(&limited, other), Ok(()));
+    }
+}